@@ -3,7 +3,264 @@ pub mod patchers;
 
 #[cfg(test)]
 mod tests {
-    use crate::patchers::{HDiff, KrDiff};
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant, SystemTime};
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicBool;
+    use crate::patchers::{ChecksumMismatchError, ChecksumSection, CompressionMode, FileFilter, HDiff, HDiffWriter, InsufficientSpaceError, KrDiff, KrDiffBatch, KrDiffInfo, KrDiffWriter, KrFileEntry, OutputAllocation, OutputTimestamps, PatchOptions, SameFileStrategy, SymlinkPolicy, UnsafePathError};
+    use crate::utils::checksum::crc32_of;
+    use crate::utils::journal::{Journal, OutputFs};
+    use crate::utils::paths::normalize_patch_path;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hdiffpatch-rs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Entries ending in '/' are directories, everything else is a file with the given content.
+    fn write_tree(root: &Path, entries: &[(&str, &[u8])]) {
+        for (path, data) in entries {
+            let full = root.join(path);
+            if path.ends_with('/') { fs::create_dir_all(&full).unwrap(); continue; }
+            if let Some(parent) = full.parent() { fs::create_dir_all(parent).unwrap(); }
+            fs::write(&full, data).unwrap();
+        }
+    }
+
+    fn pack_uint(out: &mut Vec<u8>, v: u64) {
+        pack_uint_tagged(out, v, 0, 0);
+    }
+
+    fn pack_uint_tagged(out: &mut Vec<u8>, mut v: u64, tag_bit: u8, tag: u8) {
+        let first_bits = 7 - tag_bit;
+        let mut groups = Vec::new();
+        while v >> first_bits != 0 { groups.push((v & 0x7F) as u8); v >>= 7; }
+        let mut first = v as u8;
+        if tag_bit > 0 { first |= tag << (8 - tag_bit); }
+        if !groups.is_empty() { first |= 1 << first_bits; }
+        out.push(first);
+        for (i, g) in groups.iter().rev().enumerate() { out.push(if i + 1 < groups.len() { g | 0x80 } else { *g }); }
+    }
+
+    fn pack_deltas(out: &mut Vec<u8>, indices: &[usize]) {
+        let mut back = -1i64;
+        for &i in indices { pack_uint(out, (i as i64 - back - 1) as u64); back = i as i64; }
+    }
+
+    // Greedy exact-match cover search, good enough for tiny fixtures.
+    fn find_covers(old: &[u8], new: &[u8]) -> Vec<(usize, usize, usize)> {
+        const MIN_MATCH: usize = 8;
+        let mut index: HashMap<&[u8], usize> = HashMap::new();
+        for j in 0..old.len().saturating_sub(MIN_MATCH - 1) { index.entry(&old[j..j + MIN_MATCH]).or_insert(j); }
+        let mut covers = Vec::new();
+        let mut i = 0;
+        while i + MIN_MATCH <= new.len() {
+            let Some(&j) = index.get(&new[i..i + MIN_MATCH]) else { i += 1; continue; };
+            let mut len = MIN_MATCH;
+            while i + len < new.len() && j + len < old.len() && new[i + len] == old[j + len] { len += 1; }
+            covers.push((j, i, len));
+            i += len;
+        }
+        covers
+    }
+
+    // Everything after the "HDIFF13&" signature, uncompressed.
+    fn encode_hdiff13_body(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let covers = find_covers(old, new);
+        let (mut cover_buf, mut ctrl_buf, mut diff_buf) = (Vec::new(), Vec::new(), Vec::new());
+        let (mut last_old_end, mut last_new_end) = (0i64, 0usize);
+        for &(o, n, l) in &covers {
+            let inc = o as i64 - last_old_end;
+            pack_uint_tagged(&mut cover_buf, inc.unsigned_abs(), 1, (inc < 0) as u8);
+            pack_uint(&mut cover_buf, (n - last_new_end) as u64);
+            pack_uint(&mut cover_buf, l as u64);
+            diff_buf.extend_from_slice(&new[last_new_end..n]);
+            last_old_end = (o + l) as i64;
+            last_new_end = n + l;
+        }
+        diff_buf.extend_from_slice(&new[last_new_end..]);
+        if !new.is_empty() { pack_uint_tagged(&mut ctrl_buf, new.len() as u64 - 1, 2, 0); }
+
+        let mut out = Vec::new();
+        for v in [new.len(), old.len(), covers.len(), cover_buf.len(), 0, ctrl_buf.len(), 0, 0, 0, diff_buf.len(), 0] { pack_uint(&mut out, v as u64); }
+        out.extend_from_slice(&cover_buf);
+        out.extend_from_slice(&ctrl_buf);
+        out.extend_from_slice(&diff_buf);
+        out
+    }
+
+    fn build_single_patch(old: &[u8], new: &[u8]) -> Vec<u8> {
+        let mut out = b"HDIFF13&\0".to_vec();
+        out.extend_from_slice(&encode_hdiff13_body(old, new));
+        out
+    }
+
+    // HDIFFSF20 with one step holding every cover plus the closing zero-length one; covers copy old bytes verbatim,
+    // so the RLE part is a single zero run over all of them.
+    fn build_sf_patch(old: &[u8], new: &[u8]) -> Vec<u8> {
+        build_sf_patch_in_steps(old, new, usize::MAX)
+    }
+
+    // Same, with at most `per_step` covers in a step. Each step is followed by the literals its covers skip over.
+    fn build_sf_patch_in_steps(old: &[u8], new: &[u8], per_step: usize) -> Vec<u8> {
+        let mut covers = find_covers(old, new);
+        let last_old_end = covers.last().map_or(0, |&(o, _, l)| o + l);
+        covers.push((last_old_end, new.len(), 0));
+
+        let (mut diff, mut step_size) = (Vec::new(), 0);
+        let (mut last_old_end, mut last_new_end) = (0usize, 0usize);
+        for step in covers.chunks(per_step) {
+            let (mut cover_buf, mut literals) = (Vec::new(), Vec::new());
+            for &(o, n, l) in step {
+                let inc = o as i64 - last_old_end as i64;
+                pack_uint_tagged(&mut cover_buf, inc.unsigned_abs(), 1, (inc < 0) as u8);
+                pack_uint(&mut cover_buf, (n - last_new_end) as u64);
+                pack_uint(&mut cover_buf, l as u64);
+                literals.extend_from_slice(&new[last_new_end..n]);
+                (last_old_end, last_new_end) = (o + l, n + l);
+            }
+            let mut rle_buf = Vec::new();
+            let covered: usize = step.iter().map(|c| c.2).sum();
+            if covered > 0 { pack_uint(&mut rle_buf, covered as u64); }
+
+            let step_start = diff.len();
+            pack_uint(&mut diff, cover_buf.len() as u64);
+            pack_uint(&mut diff, rle_buf.len() as u64);
+            step_size = step_size.max(diff.len() - step_start + cover_buf.len() + rle_buf.len());
+            diff.extend_from_slice(&cover_buf);
+            diff.extend_from_slice(&rle_buf);
+            diff.extend_from_slice(&literals);
+        }
+
+        let mut out = b"HDIFFSF20&\0".to_vec();
+        for v in [new.len(), old.len(), covers.len(), step_size.max(4096), diff.len(), 0] { pack_uint(&mut out, v as u64); }
+        out.extend_from_slice(&diff);
+        out
+    }
+
+    // Uncompressed HDIFF19 patch between two trees laid out like `write_tree` entries; the root "" is implied.
+    fn build_dir_patch(old: &[(&str, &[u8])], new: &[(&str, &[u8])]) -> Vec<u8> {
+        build_dir_patch_with_roots(old, true, new, true, build_single_patch, &[])
+    }
+
+    // A side that is not a directory is a single file, given as one `("", data)` entry with no root entry in front.
+    // `inner` builds the diff of the combined old and new data, signature included. `executables` index into `new`.
+    fn build_dir_patch_with_roots(old: &[(&str, &[u8])], old_is_dir: bool, new: &[(&str, &[u8])], new_is_dir: bool, inner: fn(&[u8], &[u8]) -> Vec<u8>, executables: &[usize]) -> Vec<u8> {
+        let (old_off, new_off) = (old_is_dir as usize, new_is_dir as usize);
+        let old_paths: Vec<&str> = old_is_dir.then_some("").into_iter().chain(old.iter().map(|e| e.0)).collect();
+        let new_paths: Vec<&str> = new_is_dir.then_some("").into_iter().chain(new.iter().map(|e| e.0)).collect();
+        let is_file = |p: &str, d: &[u8]| !p.ends_with('/') && !d.is_empty();
+
+        let old_refs: Vec<usize> = old.iter().enumerate().filter(|(_, e)| is_file(e.0, e.1)).map(|(i, _)| i + old_off).collect();
+        let old_data: Vec<u8> = old_refs.iter().flat_map(|&i| old[i - old_off].1.iter().copied()).collect();
+        let (mut new_refs, mut same_pairs) = (Vec::new(), Vec::new());
+        for (i, e) in new.iter().enumerate() {
+            if !is_file(e.0, e.1) { continue; }
+            match old.iter().position(|o| is_file(o.0, o.1) && o.1 == e.1) {
+                Some(j) => same_pairs.push((i + new_off, j + old_off)),
+                None => new_refs.push(i + new_off),
+            }
+        }
+        let new_data: Vec<u8> = new_refs.iter().flat_map(|&i| new[i - new_off].1.iter().copied()).collect();
+
+        let mut head = Vec::new();
+        for p in old_paths.iter().chain(new_paths.iter()) { head.extend_from_slice(p.as_bytes()); head.push(0); }
+        pack_deltas(&mut head, &old_refs);
+        pack_deltas(&mut head, &new_refs);
+        for &i in &new_refs { pack_uint(&mut head, new[i - new_off].1.len() as u64); }
+        let (mut back_new, mut back_old) = (-1i64, -1i64);
+        for &(n, o) in &same_pairs {
+            pack_uint(&mut head, (n as i64 - back_new - 1) as u64);
+            let inc = o as i64 - (back_old + 1);
+            pack_uint_tagged(&mut head, inc.unsigned_abs(), 1, (inc < 0) as u8);
+            back_new = n as i64;
+            back_old = o as i64;
+        }
+        let executables: Vec<usize> = executables.iter().map(|&i| i + new_off).collect();
+        pack_deltas(&mut head, &executables);
+
+        let sum_size = |paths: &[&str]| paths.iter().map(|p| p.len() + 1).sum::<usize>();
+        let same_size: usize = same_pairs.iter().map(|&(n, _)| new[n - new_off].1.len()).sum();
+        let mut out = b"HDIFF19&&\0".to_vec();
+        out.extend_from_slice(&[old_is_dir as u8, new_is_dir as u8]);
+        for v in [old_paths.len(), sum_size(&old_paths), new_paths.len(), sum_size(&new_paths), old_refs.len(), old_data.len(), new_refs.len(), new_data.len(), same_pairs.len(), same_size, executables.len(), 0, 0, 0, head.len(), 0, 0] { pack_uint(&mut out, v as u64); }
+        out.extend_from_slice(&head);
+        out.extend_from_slice(&inner(&old_data, &new_data));
+        out
+    }
+
+    // Uncompressed KrDiff patch: an HDIFF19 head listing every file with its size, then an HDIFF13 body whose covers copy old bytes verbatim.
+    fn build_kr_patch(old: &[(&str, &[u8])], new: &[(&str, &[u8])]) -> Vec<u8> {
+        build_kr_patch_with_checksums(old, new, false)
+    }
+
+    // With `crc32`, the head also carries the four checksums: new files, old files, same files (none), then the rest of the patch.
+    fn build_kr_patch_with_checksums(old: &[(&str, &[u8])], new: &[(&str, &[u8])], crc32: bool) -> Vec<u8> {
+        let old_files: Vec<usize> = (0..old.len()).filter(|&i| !old[i].0.ends_with('/')).collect();
+        let new_files: Vec<usize> = (0..new.len()).filter(|&i| !new[i].0.ends_with('/')).collect();
+        let old_data: Vec<u8> = old_files.iter().flat_map(|&i| old[i].1.iter().copied()).collect();
+        let new_data: Vec<u8> = new_files.iter().flat_map(|&i| new[i].1.iter().copied()).collect();
+
+        let mut head = Vec::new();
+        for e in old.iter().chain(new.iter()) { head.extend_from_slice(e.0.as_bytes()); head.push(0); }
+        pack_deltas(&mut head, &old_files);
+        pack_deltas(&mut head, &new_files);
+        for &i in &old_files { pack_uint(&mut head, old[i].1.len() as u64); }
+        for &i in &new_files { pack_uint(&mut head, new[i].1.len() as u64); }
+        for _ in &new_files { pack_uint(&mut head, 0); }
+
+        let sum_size = |entries: &[(&str, &[u8])]| entries.iter().map(|e| e.0.len() + 1).sum::<usize>();
+        let mut out = if crc32 { b"HDIFF19&&crc32\0".to_vec() } else { b"HDIFF19&&\0".to_vec() };
+        out.extend_from_slice(&[1, 1]);
+        for v in [old.len(), sum_size(old), new.len(), sum_size(new), old_files.len(), old_data.len(), new_files.len(), new_data.len(), 0, 0, 0, 0, 0, 0, head.len(), 0, if crc32 { 4 } else { 0 }] { pack_uint(&mut out, v as u64); }
+        let checksum_at = out.len();
+        out.extend_from_slice(&head);
+
+        let covers = find_covers(&old_data, &new_data);
+        let (mut cover_buf, mut diff_buf) = (Vec::new(), Vec::new());
+        let (mut read_pos, mut write_pos) = (0i64, 0usize);
+        for &(o, n, l) in &covers {
+            let inc = o as i64 - read_pos;
+            pack_uint_tagged(&mut cover_buf, inc.unsigned_abs(), 1, (inc < 0) as u8);
+            pack_uint(&mut cover_buf, (n - write_pos) as u64);
+            pack_uint(&mut cover_buf, l as u64);
+            diff_buf.extend_from_slice(&new_data[write_pos..n]);
+            read_pos = (o + l) as i64;
+            write_pos = n + l;
+        }
+        diff_buf.extend_from_slice(&new_data[write_pos..]);
+        out.extend_from_slice(b"HDIFF13&\0");
+        for v in [new_data.len(), old_data.len(), covers.len(), cover_buf.len(), 0, 0, 0, 0, 0, diff_buf.len(), 0] { pack_uint(&mut out, v as u64); }
+        out.extend_from_slice(&cover_buf);
+        out.extend_from_slice(&diff_buf);
+        if crc32 {
+            let rest = crc32_of([Ok(&out[checksum_at..])]).unwrap();
+            let checksums: Vec<u8> = [crc32_of([Ok(&new_data[..])]).unwrap(), crc32_of([Ok(&old_data[..])]).unwrap(), 0, rest].iter().flat_map(|c| c.to_le_bytes()).collect();
+            out.splice(checksum_at..checksum_at, checksums);
+        }
+        out
+    }
+
+    // Xorshift noise, so fixtures built from different seeds never share runs the cover search could latch onto.
+    // An upstream HDiffPatch tool, named by the upper-cased env var or found on the PATH. Tests against upstream are
+    // skipped without one.
+    fn upstream_tool(name: &str) -> Option<PathBuf> {
+        if let Some(path) = std::env::var_os(name.to_uppercase()) { return Some(PathBuf::from(path)); }
+        let paths = std::env::var_os("PATH")?;
+        std::env::split_paths(&paths).map(|dir| dir.join(name)).find(|p| p.is_file())
+    }
+
+    fn sample_bytes(seed: u8, len: usize) -> Vec<u8> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64 ^ seed as u64;
+        (0..len).map(|_| { state ^= state << 13; state ^= state >> 7; state ^= state << 17; (state >> 24) as u8 }).collect()
+    }
 
     #[test]
     fn apply_krdiff_patch() {
@@ -26,4 +283,1250 @@ mod tests {
         let status = hd.apply();
         if status { println!("hdiff applied successfully"); } else { println!("hdiff apply failed"); }
     }
+
+    #[test]
+    fn apply_hdiff_single_file() {
+        let dir = scratch_dir("single");
+        let old = sample_bytes(1, 4096);
+        let mut new = old.clone();
+        new[100..140].copy_from_slice(&[0xAB; 40]);
+        new.extend_from_slice(b"tail data appended to the new file");
+        fs::write(dir.join("old.bin"), &old).unwrap();
+        fs::write(dir.join("patch.hdiff"), build_single_patch(&old, &new)).unwrap();
+
+        let mut hd = HDiff::new(dir.join("old.bin").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("new.bin").to_string_lossy().into());
+        assert!(hd.apply());
+        assert_eq!(fs::read(dir.join("new.bin")).unwrap(), new);
+    }
+
+    #[test]
+    fn hdiff_writer_round_trips_single_files() {
+        let dir = scratch_dir("hdiff-writer");
+        let old = sample_bytes(40, 200_000);
+        // Every 97th byte bumped, a block replaced, a block dropped and a tail appended.
+        let mut edited: Vec<u8> = old.iter().enumerate().map(|(i, &b)| if i % 97 == 0 { b.wrapping_add(3) } else { b }).collect();
+        edited[50_000..50_300].copy_from_slice(&[0x5A; 300]);
+        edited.drain(120_000..130_000);
+        edited.extend_from_slice(&sample_bytes(41, 1000));
+        let moved = [&old[150_000..], &old[..150_000]].concat();
+        let text: Vec<u8> = (0..4000).flat_map(|i| format!("line {} of a text file\n", i % 700).into_bytes()).collect();
+        let mut text_new = text.clone();
+        text_new.splice(20_000..20_000, b"an inserted line\n".iter().copied());
+        let cases: [(&str, &[u8], &[u8]); 7] = [
+            ("empty-old", b"", &old[..1000]),
+            ("empty-new", &old, b""),
+            ("identical", &old, &old),
+            ("edited", &old, &edited),
+            ("moved", &old, &moved),
+            ("tiny", b"abc", b"abcd"),
+            ("text", &text, &text_new),
+        ];
+
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+        for (name, old, new) in cases {
+            for compression in [CompressionMode::Nocomp, CompressionMode::Zstd] {
+                let patch = HDiffWriter::new().with_compression(compression).encode(old, new).unwrap();
+                if name == "edited" || name == "moved" { assert!(patch.len() < new.len() / 10, "{} {:?}: {} bytes", name, compression, patch.len()); }
+                fs::write(dir.join("old.bin"), old).unwrap();
+                fs::write(dir.join("patch.hdiff"), &patch).unwrap();
+                HDiff::new(path("old.bin"), path("patch.hdiff"), path("new.bin")).try_apply().unwrap_or_else(|e| panic!("{} {:?}: {}", name, compression, e));
+                assert!(fs::read(dir.join("new.bin")).unwrap() == new, "{} {:?}", name, compression);
+            }
+        }
+
+        fs::write(dir.join("edited.bin"), &edited).unwrap();
+        fs::write(dir.join("old.bin"), &old).unwrap();
+        HDiffWriter::new().with_compression(CompressionMode::Zstd).with_compression_level(19).write(dir.join("old.bin"), dir.join("edited.bin"), dir.join("file.hdiff")).unwrap();
+        HDiff::new(path("old.bin"), path("file.hdiff"), path("new.bin")).try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("new.bin")).unwrap(), edited);
+
+        let err = HDiffWriter::new().with_compression(CompressionMode::Bz2).encode(&old, &edited).unwrap_err();
+        assert!(err.to_string().contains("not supported"), "{}", err);
+    }
+
+    #[test]
+    fn hdiff_writer_round_trips_directories() {
+        let dir = scratch_dir("hdiff-writer-dir");
+        let shared = sample_bytes(42, 50_000);
+        let moved = sample_bytes(43, 20_000);
+        let edit_old = sample_bytes(44, 100_000);
+        let mut edit_new = edit_old.clone();
+        edit_new[30_000..30_100].copy_from_slice(&[7; 100]);
+        edit_new.extend_from_slice(b"appended");
+        let old: Vec<(&str, &[u8])> = vec![("same.bin", &shared), ("moved/a.bin", &moved), ("edit.bin", &edit_old), ("gone.txt", b"removed"), ("empty.txt", b""), ("olddir/", b"")];
+        let new: Vec<(&str, &[u8])> = vec![("same.bin", &shared), ("dup.bin", &shared), ("renamed/a.bin", &moved), ("edit.bin", &edit_new), ("empty.txt", b""), ("bin/run.sh", b"#!/bin/sh\necho hi\n"), ("newdir/", b"")];
+        write_tree(&dir.join("old"), &old);
+        write_tree(&dir.join("new"), &new);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir.join("new/bin/run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        for compression in [CompressionMode::Nocomp, CompressionMode::Zstd] {
+            for crc32 in [false, true] {
+                let case = format!("{:?} crc32={}", compression, crc32);
+                let writer = HDiffWriter::new().with_compression(compression).with_crc32(crc32);
+                writer.write_dir(dir.join("old"), dir.join("new"), dir.join("dir.hdiff")).unwrap();
+                assert!(fs::metadata(dir.join("dir.hdiff")).unwrap().len() < 10_000, "{}", case);
+
+                let out = dir.join("out");
+                let _ = fs::remove_dir_all(&out);
+                let options = PatchOptions { same_file_strategy: SameFileStrategy::Hardlink, ..Default::default() };
+                let path = |p: &Path| p.to_string_lossy().into_owned();
+                HDiff::new(path(&dir.join("old")), path(&dir.join("dir.hdiff")), path(&out)).with_options(options).try_apply().unwrap_or_else(|e| panic!("{}: {}", case, e));
+                for (p, data) in &new {
+                    if p.ends_with('/') { assert!(out.join(p).is_dir(), "{} {}", case, p); } else { assert_eq!(fs::read(out.join(p)).unwrap(), *data, "{} {}", case, p); }
+                }
+                assert!(!out.join("gone.txt").exists() && !out.join("olddir").exists() && !out.join("moved").exists(), "{}", case);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+                    assert!(fs::metadata(out.join("bin/run.sh")).unwrap().permissions().mode() & 0o111 != 0, "{}", case);
+                    // Identical files come from same-file pairs, which this strategy hard links.
+                    assert_eq!(fs::metadata(out.join("renamed/a.bin")).unwrap().ino(), fs::metadata(dir.join("old/moved/a.bin")).unwrap().ino(), "{}", case);
+                    assert_eq!(fs::metadata(out.join("dup.bin")).unwrap().ino(), fs::metadata(dir.join("old/same.bin")).unwrap().ino(), "{}", case);
+                }
+            }
+        }
+
+        let err = HDiffWriter::new().encode_dir(dir.join("old/edit.bin"), dir.join("new")).unwrap_err();
+        assert!(err.to_string().contains("not a directory"), "{}", err);
+    }
+
+    #[test]
+    fn hdiff_writer_dir_patches_apply_with_hpatchz() {
+        let Some(hpatchz) = upstream_tool("hpatchz") else { eprintln!("hpatchz not found, skipping"); return; };
+        let dir = scratch_dir("hdiff-writer-hpatchz");
+        let shared = sample_bytes(45, 30_000);
+        let edit_old = sample_bytes(46, 60_000);
+        let mut edit_new = edit_old.clone();
+        edit_new[20_000..20_100].copy_from_slice(&[8; 100]);
+        write_tree(&dir.join("old"), &[("same.bin", &shared), ("edit.bin", &edit_old), ("gone.txt", b"removed")]);
+        let new: Vec<(&str, &[u8])> = vec![("same.bin", &shared), ("dup/same.bin", &shared), ("edit.bin", &edit_new), ("run.sh", b"#!/bin/sh\n"), ("empty/", b"")];
+        write_tree(&dir.join("new"), &new);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir.join("new/run.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        for crc32 in [false, true] {
+            let out = dir.join(format!("out-{}", crc32));
+            HDiffWriter::new().with_crc32(crc32).write_dir(dir.join("old"), dir.join("new"), dir.join("dir.hdiff")).unwrap();
+            let status = Command::new(&hpatchz).arg(if crc32 { "-C-all" } else { "-C-no" }).arg("-f").arg(dir.join("old")).arg(dir.join("dir.hdiff")).arg(&out).status().unwrap();
+            assert!(status.success(), "crc32={}: hpatchz exited with {}", crc32, status);
+            for (p, data) in &new {
+                if p.ends_with('/') { assert!(out.join(p).is_dir(), "crc32={} {}", crc32, p); } else { assert_eq!(fs::read(out.join(p)).unwrap(), *data, "crc32={} {}", crc32, p); }
+            }
+            assert!(!out.join("gone.txt").exists(), "crc32={}", crc32);
+        }
+    }
+
+    #[test]
+    fn single_file_output_is_replaced_atomically() {
+        #[cfg(unix)]
+        use std::os::unix::fs::PermissionsExt;
+        let dir = scratch_dir("single-atomic");
+        let old = sample_bytes(29, 100_000);
+        let mut new = old.clone();
+        new[5000..5100].copy_from_slice(&[3; 100]);
+        new.truncate(90_000);
+        fs::write(dir.join("patch.hdiff"), build_single_patch(&old, &new)).unwrap();
+        fs::write(dir.join("game.bin"), &old).unwrap();
+        #[cfg(unix)]
+        fs::set_permissions(dir.join("game.bin"), fs::Permissions::from_mode(0o640)).unwrap();
+
+        // Destination and source are the same file: the source is read in full before being replaced.
+        let game: String = dir.join("game.bin").to_string_lossy().into();
+        let mut hd = HDiff::new(game.clone(), dir.join("patch.hdiff").to_string_lossy().into(), game.clone());
+        hd.try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("game.bin")).unwrap(), new);
+        assert!(!dir.join(".game.bin.hdiffpatch-tmp").exists());
+        #[cfg(unix)]
+        assert_eq!(fs::metadata(dir.join("game.bin")).unwrap().permissions().mode() & 0o777, 0o640);
+
+        // A failing patch leaves the destination as it was and cleans up after itself.
+        fs::write(dir.join("other.bin"), &old).unwrap();
+        let mut truncated = build_single_patch(&old, &new);
+        truncated.truncate(truncated.len() - 4);
+        fs::write(dir.join("bad.hdiff"), truncated).unwrap();
+        let mut hd = HDiff::new(dir.join("other.bin").to_string_lossy().into(), dir.join("bad.hdiff").to_string_lossy().into(), dir.join("other.bin").to_string_lossy().into());
+        assert!(hd.try_apply().is_err());
+        assert_eq!(fs::read(dir.join("other.bin")).unwrap(), old);
+        assert!(!dir.join(".other.bin.hdiffpatch-tmp").exists());
+
+        // A symlinked destination gets the file it points to replaced, as writing through it did before.
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(dir.join("other.bin"), dir.join("link.bin")).unwrap();
+            let mut hd = HDiff::new(dir.join("other.bin").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("link.bin").to_string_lossy().into());
+            hd.try_apply().unwrap();
+            assert!(fs::symlink_metadata(dir.join("link.bin")).unwrap().file_type().is_symlink());
+            assert_eq!(fs::read(dir.join("other.bin")).unwrap(), new);
+
+            // A hard link to the source is refused: renaming over it would only patch one of the two names.
+            fs::write(dir.join("other.bin"), &old).unwrap();
+            fs::hard_link(dir.join("other.bin"), dir.join("hard.bin")).unwrap();
+            let mut hd = HDiff::new(dir.join("other.bin").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("hard.bin").to_string_lossy().into());
+            let err = hd.try_apply().unwrap_err();
+            assert!(err.to_string().contains("hard link"), "{}", err);
+            assert_eq!(fs::read(dir.join("hard.bin")).unwrap(), old);
+            assert!(!dir.join(".hard.bin.hdiffpatch-tmp").exists());
+        }
+    }
+
+    #[test]
+    fn apply_hdiff_dir_in_place() {
+        let dir = scratch_dir("in-place");
+        let game = dir.join("game");
+        let a_old = sample_bytes(2, 8192);
+        let mut a_new = a_old.clone();
+        a_new[4000..4100].copy_from_slice(&[7; 100]);
+        let shared = sample_bytes(3, 2048);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("b.bin", &shared), ("obsolete.txt", b"old only"), ("sub/", b""), ("sub/gone.bin", b"bye")];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("c.bin", &shared), ("fresh/", b""), ("fresh/new.bin", b"brand new file contents")];
+        write_tree(&game, &old);
+        fs::write(dir.join("patch.hdiff"), build_dir_patch(&old, &new)).unwrap();
+
+        let game_str: String = game.to_string_lossy().into();
+        let mut hd = HDiff::new(game_str.clone(), dir.join("patch.hdiff").to_string_lossy().into(), game_str);
+        assert!(hd.apply());
+        assert_eq!(fs::read(game.join("a.bin")).unwrap(), a_new);
+        assert_eq!(fs::read(game.join("c.bin")).unwrap(), shared);
+        assert_eq!(fs::read(game.join("fresh/new.bin")).unwrap(), b"brand new file contents");
+        assert!(!game.join("b.bin").exists());
+        assert!(!game.join("obsolete.txt").exists());
+        assert!(!game.join("sub").exists());
+        assert!(!game.join(".hdiffpatch-staging").exists());
+    }
+
+    #[test]
+    fn transactional_dir_patch_rolls_back_on_cancel() {
+        let dir = scratch_dir("transactional");
+        let a_old = sample_bytes(4, 8192);
+        let mut a_new = a_old.clone();
+        a_new[10..20].copy_from_slice(&[1; 10]);
+        let shared = sample_bytes(5, 1024);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("b.bin", &shared)];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("c.bin", &shared), ("fresh/", b""), ("fresh/new.bin", b"new")];
+        write_tree(&dir.join("src"), &old);
+        write_tree(&dir.join("dst"), &[("a.bin", b"stale destination copy"), ("keep.txt", b"untouched")]);
+        fs::write(dir.join("patch.hdiff"), build_dir_patch(&old, &new)).unwrap();
+
+        let options = PatchOptions { transactional: true, cancel_flag: Some(Arc::new(AtomicBool::new(true))), ..Default::default() };
+        let mut hd = HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("dst").to_string_lossy().into()).with_options(options);
+        assert!(!hd.apply());
+        assert_eq!(fs::read(dir.join("dst/a.bin")).unwrap(), b"stale destination copy");
+        assert_eq!(fs::read(dir.join("dst/keep.txt")).unwrap(), b"untouched");
+        assert!(!dir.join("dst/c.bin").exists());
+        assert!(!dir.join("dst/fresh").exists());
+        assert!(!dir.join("dst/.hdiffpatch-journal").exists());
+
+        let options = PatchOptions { transactional: true, ..Default::default() };
+        let mut hd = HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("dst").to_string_lossy().into()).with_options(options);
+        assert!(hd.apply());
+        assert_eq!(fs::read(dir.join("dst/a.bin")).unwrap(), a_new);
+        assert_eq!(fs::read(dir.join("dst/c.bin")).unwrap(), shared);
+        assert!(!dir.join("dst/.hdiffpatch-journal").exists());
+    }
+
+    #[test]
+    fn journal_left_by_crash_is_recovered() {
+        let dir = scratch_dir("journal-recover");
+        write_tree(&dir, &[("a.bin", b"original"), ("sub/b.bin", b"kept")]);
+
+        let output_fs = OutputFs::transactional(&dir, SymlinkPolicy::Refuse).unwrap();
+        output_fs.create_file(&dir.join("a.bin")).unwrap();
+        fs::write(dir.join("a.bin"), b"half written").unwrap();
+        output_fs.create_file(&dir.join("new/c.bin")).unwrap();
+        output_fs.remove_file(&dir.join("sub/b.bin")).unwrap();
+        drop(output_fs); // Simulates the process dying before commit or rollback.
+
+        assert!(Journal::recover(&dir).unwrap());
+        assert_eq!(fs::read(dir.join("a.bin")).unwrap(), b"original");
+        assert_eq!(fs::read(dir.join("sub/b.bin")).unwrap(), b"kept");
+        assert!(!dir.join("new").exists());
+        assert!(!dir.join(".hdiffpatch-journal").exists());
+        assert!(!Journal::recover(&dir).unwrap());
+    }
+
+    const RESUME_CHILD_ENV: &str = "HDIFFPATCH_RESUME_CHILD";
+
+    fn resume_options(dir: &Path) -> PatchOptions {
+        PatchOptions { checkpoint_path: Some(dir.join("patch.ckpt")), checkpoint_interval: 64 << 10, ..Default::default() }
+    }
+
+    fn resume_fixture_patch(dir: &Path) -> HDiff {
+        HDiff::new(dir.join("old.bin").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("new.bin").to_string_lossy().into()).with_options(resume_options(dir))
+    }
+
+    fn resume_fixture_kr_patch(dir: &Path) -> KrDiff {
+        KrDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.krpdiff").to_string_lossy().into(), dir.join("out").to_string_lossy().into()).with_options(resume_options(dir))
+    }
+
+    // Runs in a child process spawned by `kill_after_checkpoint`, slowed down so it can be killed mid-patch.
+    // A directory holding `patch.krpdiff` is patched with KrDiff, anything else with HDiff.
+    #[test]
+    #[ignore]
+    fn resume_child_process() {
+        let Ok(dir) = std::env::var(RESUME_CHILD_ENV) else { return; };
+        let dir = Path::new(&dir);
+        let slow = |_| std::thread::sleep(Duration::from_millis(50));
+        if dir.join("patch.krpdiff").exists() { resume_fixture_kr_patch(dir).with_progress(slow).apply(); } else { resume_fixture_patch(dir).with_progress(slow).apply(); }
+    }
+
+    // Patches `dir` in a child process and kills it once the first checkpoint is on disk.
+    fn kill_after_checkpoint(dir: &Path) {
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args(["tests::resume_child_process", "--exact", "--ignored", "--test-threads=1"])
+            .env(RESUME_CHILD_ENV, dir)
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        while !dir.join("patch.ckpt").exists() {
+            assert!(started.elapsed() < Duration::from_secs(60), "child never wrote a checkpoint");
+            assert!(child.try_wait().unwrap().is_none(), "child finished before it could be killed");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    // Bytes a patcher reports writing, to tell a resumed run from one that started over.
+    fn count_progress() -> (Rc<Cell<i64>>, impl FnMut(i64) + 'static) {
+        let written = Rc::new(Cell::new(0));
+        let counter = written.clone();
+        (written, move |n| counter.set(counter.get() + n))
+    }
+
+    #[test]
+    fn resumes_after_process_is_killed() {
+        let dir = scratch_dir("resume");
+        let old = sample_bytes(6, 2 << 20);
+        let mut new = old.clone();
+        for chunk in new.chunks_mut(4096) { chunk[0] ^= 0x5A; }
+        fs::write(dir.join("old.bin"), &old).unwrap();
+        fs::write(dir.join("patch.hdiff"), build_single_patch(&old, &new)).unwrap();
+
+        kill_after_checkpoint(&dir);
+        // The partial output sits in the temp file; nothing is at the destination until the patch completes.
+        assert!(!dir.join("new.bin").exists());
+        assert_ne!(fs::read(dir.join(".new.bin.hdiffpatch-tmp")).unwrap(), new);
+
+        let (written, progress) = count_progress();
+        let mut hd = resume_fixture_patch(&dir).with_progress(progress);
+        assert!(hd.resume());
+        assert_eq!(fs::read(dir.join("new.bin")).unwrap(), new);
+        assert!(written.get() < new.len() as i64, "resume rewrote all {} bytes", written.get());
+        assert!(!dir.join("patch.ckpt").exists());
+        assert!(!dir.join(".new.bin.hdiffpatch-tmp").exists());
+    }
+
+    #[test]
+    fn resumes_sf_patch_after_process_is_killed() {
+        let dir = scratch_dir("resume-sf");
+        let old = sample_bytes(16, 2 << 20);
+        let mut new = old.clone();
+        for chunk in new.chunks_mut(4096) { chunk[0] ^= 0x5A; }
+        fs::write(dir.join("old.bin"), &old).unwrap();
+        // Checkpoints fall between steps, so there have to be plenty of them.
+        fs::write(dir.join("patch.hdiff"), build_sf_patch_in_steps(&old, &new, 8)).unwrap();
+
+        kill_after_checkpoint(&dir);
+        assert!(!dir.join("new.bin").exists());
+
+        let (written, progress) = count_progress();
+        let mut hd = resume_fixture_patch(&dir).with_progress(progress);
+        assert!(hd.resume());
+        assert!(fs::read(dir.join("new.bin")).unwrap() == new);
+        assert!(written.get() < new.len() as i64, "resume rewrote all {} bytes", written.get());
+        assert!(!dir.join("patch.ckpt").exists());
+    }
+
+    #[test]
+    fn resumes_kr_patch_after_process_is_killed() {
+        let dir = scratch_dir("resume-kr");
+        let (a_old, b_old) = (sample_bytes(17, 1 << 20), sample_bytes(18, 1 << 20));
+        let (mut a_new, mut b_new) = (a_old.clone(), b_old.clone());
+        for chunk in a_new.chunks_mut(4096).chain(b_new.chunks_mut(4096)) { chunk[0] ^= 0x5A; }
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("sub/b.bin", &b_old)];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("sub/b.bin", &b_new)];
+        write_tree(&dir.join("src"), &old);
+        fs::write(dir.join("patch.krpdiff"), build_kr_patch(&old, &new)).unwrap();
+
+        kill_after_checkpoint(&dir);
+        assert!(fs::read(dir.join("out/sub/b.bin")).map_or(true, |b| b != b_new));
+
+        let (written, progress) = count_progress();
+        let mut kr = resume_fixture_kr_patch(&dir).with_progress(progress);
+        assert!(kr.resume());
+        assert!(fs::read(dir.join("out/a.bin")).unwrap() == a_new);
+        assert!(fs::read(dir.join("out/sub/b.bin")).unwrap() == b_new);
+        assert!(written.get() < (a_new.len() + b_new.len()) as i64, "resume rewrote all {} bytes", written.get());
+        assert!(!dir.join("patch.ckpt").exists());
+    }
+
+    #[test]
+    fn normalize_patch_path_fuzz() {
+        const PIECES: [&str; 12] = ["a", "b.bin", "..", ".", "", "/", "\\", "C:", "x..y", "..\\", "%2e%2e", "\0"];
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        for _ in 0..20_000 {
+            let mut raw = String::new();
+            seed ^= seed << 13; seed ^= seed >> 7; seed ^= seed << 17;
+            let mut bits = seed;
+            for _ in 0..(bits % 7) + 1 {
+                bits >>= 4;
+                raw.push_str(PIECES[(bits % PIECES.len() as u64) as usize]);
+                if bits & 1 == 1 { raw.push('/'); }
+            }
+
+            let Ok(norm) = normalize_patch_path(&raw) else { continue; };
+            let root = Path::new("/patch/root");
+            let joined = root.join(&norm);
+            assert!(joined.starts_with(root), "{:?} -> {:?} escaped the root", raw, norm);
+            assert!(!Path::new(&norm).has_root(), "{:?} -> {:?} is absolute", raw, norm);
+            assert!(norm.split(['/', '\\']).all(|c| c != ".."), "{:?} -> {:?} kept a parent component", raw, norm);
+            assert!(norm.split('/').all(|c| c != "."), "{:?} -> {:?} kept a dot component", raw, norm);
+            assert!(!norm.contains('\0') && !norm.contains("//"), "{:?} -> {:?}", raw, norm);
+            assert_eq!(normalize_patch_path(&norm).unwrap(), norm, "normal form of {:?} is not stable", raw);
+        }
+
+        for bad in ["../x", "a/../../x", "/etc/passwd", "\\server\\share", "C:\\Windows", "c:x", "a\\..\\..\\x", "a\0b"] {
+            let err = normalize_patch_path(bad).unwrap_err();
+            assert!(err.get_ref().is_some_and(|e| e.is::<UnsafePathError>()), "{:?} was not rejected as unsafe", bad);
+        }
+        assert_eq!(normalize_patch_path("./a//b/./c/").unwrap(), "a/b/c/");
+        assert_eq!(normalize_patch_path("").unwrap(), "");
+    }
+
+    #[test]
+    fn dir_patch_with_escaping_path_is_rejected() {
+        let dir = scratch_dir("traversal");
+        let payload = sample_bytes(7, 64);
+        for evil in ["../escaped.bin", "sub/../../escaped.bin"] {
+            let old: Vec<(&str, &[u8])> = vec![("a.bin", b"old contents")];
+            let new: Vec<(&str, &[u8])> = vec![("a.bin", b"old contents"), (evil, &payload)];
+            write_tree(&dir.join("src"), &old);
+            fs::write(dir.join("patch.hdiff"), build_dir_patch(&old, &new)).unwrap();
+
+            let mut hd = HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("dst").to_string_lossy().into());
+            let err = hd.try_apply().unwrap_err();
+            let unsafe_path = err.downcast_ref::<UnsafePathError>().expect("expected an UnsafePathError");
+            assert_eq!(unsafe_path.path, evil);
+            assert!(!dir.join("escaped.bin").exists());
+        }
+    }
+
+    #[test]
+    fn windows_paths_resolve_case_and_backslashes() {
+        let dir = scratch_dir("windows-paths");
+        let tex_old = sample_bytes(8, 4096);
+        let mut tex_new = tex_old.clone();
+        tex_new[2000..2010].copy_from_slice(&[9; 10]);
+        let shared = sample_bytes(9, 512);
+        write_tree(&dir.join("src"), &[("Data/Textures/Stone.bin", &tex_old), ("Data/Shared.bin", &shared)]);
+
+        let old: Vec<(&str, &[u8])> = vec![("data\\textures\\stone.BIN", &tex_old), ("DATA\\shared.bin", &shared)];
+        let new: Vec<(&str, &[u8])> = vec![("Data\\Textures\\Stone.bin", &tex_new), ("Data\\Copy\\Shared.bin", &shared)];
+        fs::write(dir.join("patch.hdiff"), build_dir_patch(&old, &new)).unwrap();
+
+        let patch = |dst: &str, windows_paths: bool| {
+            let options = PatchOptions { windows_paths, ..Default::default() };
+            HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join(dst).to_string_lossy().into()).with_options(options)
+        };
+        assert!(!patch("strict", false).apply());
+        assert!(patch("dst", true).apply());
+        assert_eq!(fs::read(dir.join("dst/Data/Textures/Stone.bin")).unwrap(), tex_new);
+        assert_eq!(fs::read(dir.join("dst/Data/Copy/Shared.bin")).unwrap(), shared);
+
+        write_tree(&dir.join("src"), &[("data/shared.bin", &shared)]);
+        let err = patch("ambiguous", true).try_apply().unwrap_err();
+        assert!(err.to_string().contains("ambiguous"), "{}", err);
+    }
+
+    #[test]
+    fn same_file_strategies() {
+        let dir = scratch_dir("same-file");
+        let shared = sample_bytes(10, 3000);
+        let moved = sample_bytes(11, 700);
+        let old: Vec<(&str, &[u8])> = vec![("shared.bin", &shared), ("moved.bin", &moved), ("changed.bin", b"changed file, old version")];
+        let new: Vec<(&str, &[u8])> = vec![("shared.bin", &shared), ("copy/shared.bin", &shared), ("renamed.bin", &moved), ("changed.bin", b"changed file, new version")];
+        fs::write(dir.join("patch.hdiff"), build_dir_patch(&old, &new)).unwrap();
+
+        for strategy in [SameFileStrategy::Copy, SameFileStrategy::Reflink, SameFileStrategy::Hardlink, SameFileStrategy::Rename] {
+            let game = dir.join(format!("{:?}", strategy));
+            write_tree(&game, &old);
+            let game_str: String = game.to_string_lossy().into();
+            let options = PatchOptions { same_file_strategy: strategy, ..Default::default() };
+            let mut hd = HDiff::new(game_str.clone(), dir.join("patch.hdiff").to_string_lossy().into(), game_str).with_options(options);
+            hd.try_apply().unwrap();
+
+            assert_eq!(fs::read(game.join("shared.bin")).unwrap(), shared, "{:?}", strategy);
+            assert_eq!(fs::read(game.join("copy/shared.bin")).unwrap(), shared, "{:?}", strategy);
+            assert_eq!(fs::read(game.join("renamed.bin")).unwrap(), moved, "{:?}", strategy);
+            assert!(!game.join("moved.bin").exists(), "{:?}", strategy);
+            #[cfg(unix)]
+            if strategy == SameFileStrategy::Hardlink {
+                use std::os::unix::fs::MetadataExt;
+                assert_eq!(fs::metadata(game.join("shared.bin")).unwrap().ino(), fs::metadata(game.join("copy/shared.bin")).unwrap().ino());
+            }
+        }
+    }
+
+    #[test]
+    fn failed_same_file_copy_is_an_error() {
+        let dir = scratch_dir("same-file-error");
+        let shared = sample_bytes(12, 1000);
+        let old: Vec<(&str, &[u8])> = vec![("shared.bin", &shared), ("changed.bin", b"changed file, old version")];
+        let new: Vec<(&str, &[u8])> = vec![("copy.bin", &shared), ("changed.bin", b"changed file, new version")];
+        fs::write(dir.join("patch.hdiff"), build_dir_patch(&old, &new)).unwrap();
+        write_tree(&dir.join("src"), &old);
+        // A directory squatting on the output path makes the copy fail.
+        write_tree(&dir.join("dst"), &[("copy.bin/", b"")]);
+
+        let mut hd = HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("dst").to_string_lossy().into());
+        let err = hd.try_apply().unwrap_err();
+        assert!(err.to_string().contains("copy.bin"), "{}", err);
+    }
+
+    #[test]
+    fn file_filter_globs() {
+        let voice = FileFilter::glob("voice/**");
+        assert!(voice.matches("voice/en/line.wem") && voice.matches("voice/jp/") && !voice.matches("voice.pak") && !voice.matches("data/voice/a"));
+        let paks = FileFilter::glob("**/*.pak");
+        assert!(paks.matches("a.pak") && paks.matches("x/y/b.pak") && !paks.matches("x/b.pak.bak"));
+        let one = FileFilter::glob("data/?.bin");
+        assert!(one.matches("data/a.bin") && !one.matches("data/ab.bin") && !one.matches("data//.bin"));
+    }
+
+    #[test]
+    fn selective_patching_writes_only_matching_files() {
+        let dir = scratch_dir("selective");
+        let a_old = sample_bytes(13, 5000);
+        let v_old = sample_bytes(14, 3000);
+        let shared = sample_bytes(15, 800);
+        let (mut a_new, mut v_new) = (a_old.clone(), v_old.clone());
+        a_new[100..120].copy_from_slice(&[3; 20]);
+        v_new[2000..2050].copy_from_slice(&[4; 50]);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("voice/v.bin", &v_old), ("shared.bin", &shared)];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("voice/v.bin", &v_new), ("voice/shared.bin", &shared), ("other/shared.bin", &shared)];
+        fs::write(dir.join("patch.hdiff"), build_dir_patch(&old, &new)).unwrap();
+        fs::write(dir.join("patch.krpdiff"), build_kr_patch(&old, &new)).unwrap();
+        // a.bin is only needed to rebuild a.bin, which the filter drops.
+        write_tree(&dir.join("src"), &old[1..]);
+
+        for (patch, dst) in [("patch.hdiff", "hdiff"), ("patch.krpdiff", "krdiff")] {
+            let options = PatchOptions { file_filter: Some(FileFilter::glob("voice/**")), ..Default::default() };
+            let (src, diff, out): (String, String, String) = (dir.join("src").to_string_lossy().into(), dir.join(patch).to_string_lossy().into(), dir.join(dst).to_string_lossy().into());
+            if dst == "hdiff" { HDiff::new(src, diff, out).with_options(options).try_apply().unwrap(); } else { KrDiff::new(src, diff, out).with_options(options).try_apply().unwrap(); }
+
+            let out = dir.join(dst);
+            assert_eq!(fs::read(out.join("voice/v.bin")).unwrap(), v_new, "{}", patch);
+            assert_eq!(fs::read(out.join("voice/shared.bin")).unwrap(), shared, "{}", patch);
+            assert!(!out.join("a.bin").exists() && !out.join("other").exists(), "{}", patch);
+        }
+
+        // Without the filter the missing old file is needed again.
+        let mut hd = HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("full").to_string_lossy().into());
+        assert!(hd.try_apply().is_err());
+        let options = PatchOptions { file_filter: Some(FileFilter::new(|p| p == "a.bin")), ..Default::default() };
+        let mut kr = KrDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.krpdiff").to_string_lossy().into(), dir.join("needs-a").to_string_lossy().into()).with_options(options);
+        assert!(kr.try_apply().unwrap_err().to_string().contains("a.bin"));
+
+        // Missing old files with a present one between them cannot be laid out without their sizes; refused up front.
+        write_tree(&dir.join("src-gaps"), &old[1..2]);
+        let options = PatchOptions { file_filter: Some(FileFilter::glob("voice/v.bin")), ..Default::default() };
+        let err = HDiff::new(dir.join("src-gaps").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("gaps").to_string_lossy().into()).with_options(options.clone()).try_apply().unwrap_err();
+        assert!(err.to_string().contains("voice/v.bin sits between a.bin and shared.bin"), "{}", err);
+        assert!(!dir.join("gaps/voice").exists());
+        // KrDiff stores every old file size, so the same gaps are fine there.
+        KrDiff::new(dir.join("src-gaps").to_string_lossy().into(), dir.join("patch.krpdiff").to_string_lossy().into(), dir.join("kr-gaps").to_string_lossy().into()).with_options(options).try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("kr-gaps/voice/v.bin")).unwrap(), v_new);
+    }
+
+    // Built with our own HDIFF19 writer, which lays the single-file side out the way hdiffz does:
+    // one "" path entry, no root directory entry, and its flag byte cleared.
+    #[test]
+    fn file_to_dir_and_dir_to_file_patches() {
+        let dir = scratch_dir("file-dir");
+        let old_file = sample_bytes(16, 4000);
+        let mut changed = old_file.clone();
+        changed[1000..1040].copy_from_slice(&[5; 40]);
+
+        let new: Vec<(&str, &[u8])> = vec![("copy.bin", &old_file), ("sub/", b""), ("sub/changed.bin", &changed)];
+        fs::write(dir.join("to-dir.hdiff"), build_dir_patch_with_roots(&[("", &old_file)], false, &new, true, build_single_patch, &[])).unwrap();
+        fs::write(dir.join("old.bin"), &old_file).unwrap();
+        let mut hd = HDiff::new(dir.join("old.bin").to_string_lossy().into(), dir.join("to-dir.hdiff").to_string_lossy().into(), dir.join("out-dir").to_string_lossy().into());
+        hd.try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("out-dir/copy.bin")).unwrap(), old_file);
+        assert_eq!(fs::read(dir.join("out-dir/sub/changed.bin")).unwrap(), changed);
+
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &old_file), ("b.bin", b"unrelated")];
+        fs::write(dir.join("to-file.hdiff"), build_dir_patch_with_roots(&old, true, &[("", &changed)], false, build_single_patch, &[])).unwrap();
+        write_tree(&dir.join("old-dir"), &old);
+        let mut hd = HDiff::new(dir.join("old-dir").to_string_lossy().into(), dir.join("to-file.hdiff").to_string_lossy().into(), dir.join("out.bin").to_string_lossy().into());
+        hd.try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("out.bin")).unwrap(), changed);
+        assert!(!dir.join(".hdiffpatch-journal").exists());
+    }
+
+    // Patches from upstream hdiffz in each format it writes, so the on-disk layout is not only checked against our own
+    // fixture builders. There is no public tool producing KrDiff patches.
+    #[test]
+    fn upstream_hdiffz_patches_apply() {
+        let Some(hdiffz) = upstream_tool("hdiffz") else { eprintln!("hdiffz not found, skipping"); return; };
+        let dir = scratch_dir("upstream-formats");
+        let a_old = sample_bytes(47, 50_000);
+        let mut a_new = a_old.clone();
+        a_new[10_000..10_200].copy_from_slice(&[9; 200]);
+        a_new.extend_from_slice(b"appended");
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("same.bin", b"unchanged"), ("gone.txt", b"removed")];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("same.bin", b"unchanged"), ("sub/", b""), ("sub/b.bin", b"brand new")];
+        write_tree(&dir.join("old"), &old);
+        write_tree(&dir.join("new"), &new);
+        fs::write(dir.join("a.old"), &a_old).unwrap();
+        fs::write(dir.join("a.new"), &a_new).unwrap();
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+
+        // HDIFF13 plain and zstd, HDIFFSF20 from -SD.
+        for (name, args) in [("hdiff13", &[][..]), ("hdiff13-zstd", &["-c-zstd"][..]), ("sf20", &["-SD"][..])] {
+            let status = Command::new(&hdiffz).args(args).arg(dir.join("a.old")).arg(dir.join("a.new")).arg(dir.join(name)).status().unwrap();
+            assert!(status.success(), "{}: hdiffz exited with {}", name, status);
+            let out = format!("{}.out", name);
+            HDiff::new(path("a.old"), path(name), path(&out)).try_apply().unwrap_or_else(|e| panic!("{}: {}", name, e));
+            assert_eq!(fs::read(dir.join(&out)).unwrap(), a_new, "{}", name);
+        }
+
+        // HDIFF19 with and without checksums, and an HDIFFSF20 directory patch.
+        for (name, args) in [("hdiff19", &[][..]), ("hdiff19-crc32", &["-C-crc32", "-c-zstd"][..]), ("sf20-dir", &["-SD"][..])] {
+            let status = Command::new(&hdiffz).args(args).arg(dir.join("old")).arg(dir.join("new")).arg(dir.join(name)).status().unwrap();
+            assert!(status.success(), "{}: hdiffz exited with {}", name, status);
+            let out = format!("{}.out", name);
+            HDiff::new(path("old"), path(name), path(&out)).try_apply().unwrap_or_else(|e| panic!("{}: {}", name, e));
+            for (p, data) in &new {
+                if p.ends_with('/') { assert!(dir.join(&out).join(p).is_dir(), "{} {}", name, p); } else { assert_eq!(fs::read(dir.join(&out).join(p)).unwrap(), *data, "{} {}", name, p); }
+            }
+            assert!(!dir.join(&out).join("gone.txt").exists(), "{}", name);
+        }
+    }
+
+    // The same two modes as produced by upstream hdiffz, which is what the head layout has to agree with.
+    #[test]
+    fn upstream_file_to_dir_and_dir_to_file_patches() {
+        let Some(hdiffz) = upstream_tool("hdiffz") else { eprintln!("hdiffz not found, skipping"); return; };
+        let dir = scratch_dir("upstream-file-dir");
+        let old_file = sample_bytes(19, 4000);
+        let mut changed = old_file.clone();
+        changed[1000..1040].copy_from_slice(&[5; 40]);
+        fs::write(dir.join("old.bin"), &old_file).unwrap();
+        fs::write(dir.join("new.bin"), &changed).unwrap();
+        write_tree(&dir.join("old-dir"), &[("a.bin", &old_file), ("b.bin", b"unrelated")]);
+        write_tree(&dir.join("new-dir"), &[("copy.bin", &old_file), ("sub/", b""), ("sub/changed.bin", &changed)]);
+        let diff = |old: &str, new: &str, patch: &str| {
+            let status = Command::new(&hdiffz).arg(dir.join(old)).arg(dir.join(new)).arg(dir.join(patch)).status().unwrap();
+            assert!(status.success(), "hdiffz {} {} exited with {}", old, new, status);
+        };
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+
+        diff("old.bin", "new-dir", "to-dir.hdiff");
+        HDiff::new(path("old.bin"), path("to-dir.hdiff"), path("out-dir")).try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("out-dir/copy.bin")).unwrap(), old_file);
+        assert_eq!(fs::read(dir.join("out-dir/sub/changed.bin")).unwrap(), changed);
+
+        diff("old-dir", "new.bin", "to-file.hdiff");
+        HDiff::new(path("old-dir"), path("to-file.hdiff"), path("out.bin")).try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("out.bin")).unwrap(), changed);
+    }
+
+    #[test]
+    fn hdiffsf20_single_file_and_dir_patches() {
+        let dir = scratch_dir("sf20");
+        let a_old = sample_bytes(17, 6000);
+        let b_old = sample_bytes(18, 2500);
+        let (mut a_new, mut c_new) = (a_old.clone(), b_old[..1200].to_vec());
+        a_new[3000..3100].copy_from_slice(&[6; 100]);
+        c_new.extend_from_slice(b"fresh tail");
+
+        fs::write(dir.join("a.old"), &a_old).unwrap();
+        fs::write(dir.join("single.hdiff"), build_sf_patch(&a_old, &a_new)).unwrap();
+        let mut hd = HDiff::new(dir.join("a.old").to_string_lossy().into(), dir.join("single.hdiff").to_string_lossy().into(), dir.join("a.new").to_string_lossy().into());
+        hd.try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("a.new")).unwrap(), a_new);
+
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("b.bin", &b_old)];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("data/", b""), ("data/c.bin", &c_new), ("data/b.bin", &b_old), ("empty.txt", b"")];
+        fs::write(dir.join("dir.hdiff"), build_dir_patch_with_roots(&old, true, &new, true, build_sf_patch, &[])).unwrap();
+        write_tree(&dir.join("src"), &old);
+        let written = Rc::new(RefCell::new(0i64));
+        let counter = written.clone();
+        let mut hd = HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("dir.hdiff").to_string_lossy().into(), dir.join("out").to_string_lossy().into()).with_progress(move |n| *counter.borrow_mut() += n);
+        hd.try_apply().unwrap();
+        for (path, data) in &new {
+            if !path.ends_with('/') { assert_eq!(&fs::read(dir.join("out").join(path)).unwrap(), data, "{}", path); }
+        }
+        assert_eq!(*written.borrow(), (a_new.len() + c_new.len()) as i64);
+
+        // Cancelling a transactional SF20 dir patch rolls the output back like the HDIFF13 path does.
+        let options = PatchOptions { transactional: true, cancel_flag: Some(Arc::new(AtomicBool::new(true))), ..Default::default() };
+        let mut hd = HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("dir.hdiff").to_string_lossy().into(), dir.join("cancelled").to_string_lossy().into()).with_options(options);
+        assert!(hd.try_apply().is_err());
+        assert!(!dir.join("cancelled/a.bin").exists() && !dir.join("cancelled/data").exists());
+    }
+
+    #[test]
+    fn patches_with_empty_streams_and_zero_length_files() {
+        let dir = scratch_dir("empty-streams");
+        let data = sample_bytes(19, 3000);
+        let mut changed = data.clone();
+        changed[10..30].copy_from_slice(&[7; 20]);
+        type Tree<'a> = Vec<(&'a str, &'a [u8])>;
+        let cases: [(&str, Tree, Tree); 4] = [
+            // No old reference data: every new file is brand new.
+            ("all-new", vec![], vec![("d/", b""), ("d/a.bin", &data)]),
+            // No new reference data in the HDIFF19 patch: only a directory, an identical copy and an empty file.
+            ("copies-only", vec![("a.bin", &data)], vec![("d/", b""), ("d/copy.bin", &data), ("empty.txt", b"")]),
+            // Nothing to write at all.
+            ("empty-only", vec![("a.bin", &data)], vec![("d/", b""), ("empty.txt", b"")]),
+            // Zero-length files between real ones on both sides.
+            ("zero-length", vec![("a.bin", &data), ("z.txt", b""), ("b.bin", &data[..100])], vec![("z0.txt", b""), ("a.bin", &changed), ("z1.txt", b""), ("b.bin", &data[..100])]),
+        ];
+
+        for (name, old, new) in &cases {
+            let src = dir.join(name).join("src");
+            fs::create_dir_all(&src).unwrap();
+            write_tree(&src, old);
+            for (kind, patch) in [("hdiff", build_dir_patch(old, new)), ("krdiff", build_kr_patch(old, new))] {
+                let patch_path = dir.join(name).join(format!("patch.{}", kind));
+                fs::write(&patch_path, patch).unwrap();
+                let out = dir.join(name).join(kind);
+                let (src, diff, dst): (String, String, String) = (src.to_string_lossy().into(), patch_path.to_string_lossy().into(), out.to_string_lossy().into());
+                let result = if kind == "hdiff" { HDiff::new(src, diff, dst).try_apply() } else { KrDiff::new(src, diff, dst).try_apply() };
+                result.unwrap_or_else(|e| panic!("{} {}: {}", name, kind, e));
+                for (path, data) in new {
+                    let full = out.join(path);
+                    if path.ends_with('/') { assert!(full.is_dir(), "{} {} {}", name, kind, path); } else { assert_eq!(&fs::read(&full).unwrap(), data, "{} {} {}", name, kind, path); }
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_policies() {
+        use std::os::unix::fs::symlink;
+        let dir = scratch_dir("symlinks");
+        let a_old = sample_bytes(20, 3000);
+        let same = sample_bytes(21, 1000);
+        let x_new = sample_bytes(22, 500);
+        let mut a_new = a_old.clone();
+        a_new[50..90].copy_from_slice(&[8; 40]);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("same.bin", &same)];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("data/", b""), ("data/x.bin", &x_new), ("same.bin", &same)];
+        fs::write(dir.join("patch.hdiff"), build_dir_patch(&old, &new)).unwrap();
+        write_tree(&dir.join("real"), &old);
+
+        // Plain old files; the output tree has links pointing out of it where a file and a directory go.
+        write_tree(&dir.join("src"), &old);
+        let run = |src: &str, name: &str, policy: SymlinkPolicy| {
+            let (out, outside) = (dir.join(name), dir.join(format!("{}-outside", name)));
+            fs::create_dir_all(outside.join("dir")).unwrap();
+            fs::write(outside.join("file"), b"keep me").unwrap();
+            fs::create_dir_all(&out).unwrap();
+            symlink(outside.join("file"), out.join("a.bin")).unwrap();
+            symlink(outside.join("dir"), out.join("data")).unwrap();
+            let options = PatchOptions { symlink_policy: policy, ..Default::default() };
+            let result = HDiff::new(dir.join(src).to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), out.to_string_lossy().into()).with_options(options).try_apply();
+            assert_eq!(fs::read(outside.join("file")).unwrap(), b"keep me", "{}", name);
+            (result, out, outside)
+        };
+
+        let (result, _, outside) = run("src", "refuse", SymlinkPolicy::Refuse);
+        assert!(result.unwrap_err().to_string().contains("symlink"));
+        assert!(fs::read_dir(outside.join("dir")).unwrap().next().is_none());
+
+        let (result, out, outside) = run("src", "follow", SymlinkPolicy::Follow);
+        result.unwrap();
+        assert!(!fs::symlink_metadata(out.join("a.bin")).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read(out.join("a.bin")).unwrap(), a_new);
+        assert_eq!(fs::read(outside.join("dir/x.bin")).unwrap(), x_new);
+
+        let (result, out, outside) = run("src", "opaque", SymlinkPolicy::Opaque);
+        result.unwrap();
+        assert!(fs::symlink_metadata(out.join("data")).unwrap().is_dir());
+        assert_eq!(fs::read(out.join("data/x.bin")).unwrap(), x_new);
+        assert!(fs::read_dir(outside.join("dir")).unwrap().next().is_none());
+
+        // Old reference data behind a link: only `Follow` reads through it.
+        fs::create_dir_all(dir.join("src-linked-ref")).unwrap();
+        symlink(dir.join("real/a.bin"), dir.join("src-linked-ref/a.bin")).unwrap();
+        fs::write(dir.join("src-linked-ref/same.bin"), &same).unwrap();
+        assert!(run("src-linked-ref", "ref-refuse", SymlinkPolicy::Refuse).0.is_err());
+        assert!(run("src-linked-ref", "ref-opaque", SymlinkPolicy::Opaque).0.is_err());
+        run("src-linked-ref", "ref-follow", SymlinkPolicy::Follow).0.unwrap();
+        // Existing callers keep following links unless they opt into `Refuse`.
+        assert_eq!(PatchOptions::default().symlink_policy, SymlinkPolicy::Follow);
+    }
+
+    #[test]
+    fn output_timestamps_and_permissions() {
+        let dir = scratch_dir("metadata");
+        let a_old = sample_bytes(23, 2000);
+        let same = sample_bytes(24, 700);
+        let tool = sample_bytes(25, 300);
+        let mut a_new = a_old.clone();
+        a_new[0..16].copy_from_slice(&[9; 16]);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("same.bin", &same)];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("copy/", b""), ("copy/same.bin", &same), ("tool", &tool)];
+        fs::write(dir.join("patch.hdiff"), build_dir_patch_with_roots(&old, true, &new, true, build_single_patch, &[3])).unwrap();
+        fs::write(dir.join("patch.krpdiff"), build_kr_patch(&old, &[("a.bin", &a_new)])).unwrap();
+        write_tree(&dir.join("src"), &old);
+
+        let old_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        for name in ["a.bin", "same.bin"] { fs::File::options().write(true).open(dir.join("src").join(name)).unwrap().set_modified(old_time).unwrap(); }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(dir.join("src/a.bin"), fs::Permissions::from_mode(0o640)).unwrap();
+        }
+        let mtime = |path: PathBuf| fs::metadata(path).unwrap().modified().unwrap();
+
+        let options = PatchOptions { output_timestamps: OutputTimestamps::FromOldFile, preserve_permissions: true, ..Default::default() };
+        HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("from-old").to_string_lossy().into()).with_options(options.clone()).try_apply().unwrap();
+        KrDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.krpdiff").to_string_lossy().into(), dir.join("kr-from-old").to_string_lossy().into()).with_options(options).try_apply().unwrap();
+        let out = dir.join("from-old");
+        assert_eq!(mtime(out.join("a.bin")), old_time);
+        assert_eq!(mtime(out.join("copy/same.bin")), old_time);
+        assert_eq!(mtime(dir.join("kr-from-old/a.bin")), old_time);
+        assert_ne!(mtime(out.join("tool")), old_time);
+
+        let fixed = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let options = PatchOptions { output_timestamps: OutputTimestamps::Fixed(fixed), ..Default::default() };
+        HDiff::new(dir.join("src").to_string_lossy().into(), dir.join("patch.hdiff").to_string_lossy().into(), dir.join("fixed").to_string_lossy().into()).with_options(options).try_apply().unwrap();
+        for name in ["a.bin", "copy/same.bin", "tool"] { assert_eq!(mtime(dir.join("fixed").join(name)), fixed, "{}", name); }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(out.join("a.bin")), 0o640);
+            assert_eq!(mode(dir.join("kr-from-old/a.bin")), 0o640);
+            // Marked executable by the patch, whatever the options say.
+            let tool_mode = mode(dir.join("fixed/tool"));
+            assert_eq!(tool_mode & 0o111, (tool_mode & 0o444) >> 2);
+            assert_eq!(mode(dir.join("fixed/a.bin")) & 0o111, 0);
+        }
+    }
+
+    #[test]
+    fn disk_space_is_checked_before_writing() {
+        let dir = scratch_dir("disk-space");
+        let old = sample_bytes(26, 100);
+        fs::write(dir.join("old.bin"), &old).unwrap();
+        // An HDIFF13 header claiming a 1 PiB output; nothing past the header is read before the check.
+        let mut patch = b"HDIFF13&\0".to_vec();
+        for v in [1u64 << 50, old.len() as u64, 0, 0, 0, 0, 0, 0, 0, 0, 0] { pack_uint(&mut patch, v); }
+        fs::write(dir.join("huge.hdiff"), patch).unwrap();
+
+        let mut hd = HDiff::new(dir.join("old.bin").to_string_lossy().into(), dir.join("huge.hdiff").to_string_lossy().into(), dir.join("new.bin").to_string_lossy().into());
+        let err = hd.try_apply().unwrap_err();
+        let space = err.downcast_ref::<InsufficientSpaceError>().unwrap_or_else(|| panic!("unexpected error: {}", err));
+        assert_eq!(space.needed, 1 << 50);
+        assert!(space.available < space.needed);
+        assert!(!dir.join("new.bin").exists());
+
+        // Opting out goes straight to patching, which then trips over the bogus patch instead.
+        let options = PatchOptions { skip_disk_space_check: true, ..Default::default() };
+        let mut hd = HDiff::new(dir.join("old.bin").to_string_lossy().into(), dir.join("huge.hdiff").to_string_lossy().into(), dir.join("new.bin").to_string_lossy().into()).with_options(options);
+        assert!(hd.try_apply().unwrap_err().downcast_ref::<InsufficientSpaceError>().is_none());
+    }
+
+    #[test]
+    fn preallocated_outputs() {
+        let dir = scratch_dir("preallocate");
+        let a_old = sample_bytes(27, 200_000);
+        let mut a_new = a_old.clone();
+        a_new[1000..1100].copy_from_slice(&[1; 100]);
+        a_new.extend_from_slice(&sample_bytes(28, 50_000));
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old)];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new)];
+        fs::write(dir.join("single.hdiff"), build_single_patch(&a_old, &a_new)).unwrap();
+        fs::write(dir.join("dir.hdiff"), build_dir_patch(&old, &new)).unwrap();
+        fs::write(dir.join("dir.krpdiff"), build_kr_patch(&old, &new)).unwrap();
+        write_tree(&dir.join("src"), &old);
+
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+        let options = PatchOptions { preallocate: true, ..Default::default() };
+        let mut single = HDiff::new(path("src/a.bin"), path("single.hdiff"), path("single.bin")).with_options(options.clone());
+        let mut hd = HDiff::new(path("src"), path("dir.hdiff"), path("hdiff")).with_options(options.clone());
+        let mut kr = KrDiff::new(path("src"), path("dir.krpdiff"), path("krdiff")).with_options(options);
+        single.try_apply().unwrap();
+        hd.try_apply().unwrap();
+        kr.try_apply().unwrap();
+
+        // Filesystems without fallocate report the sparse fallback instead; either way the output is complete.
+        for (allocation, out) in [(single.output_allocation(), dir.join("single.bin")), (hd.output_allocation(), dir.join("hdiff/a.bin")), (kr.output_allocation(), dir.join("krdiff/a.bin"))] {
+            assert!(allocation.is_some(), "{}", out.display());
+            assert_eq!(fs::read(&out).unwrap(), a_new, "{}", out.display());
+            #[cfg(unix)]
+            if allocation == Some(OutputAllocation::Preallocated) {
+                use std::os::unix::fs::MetadataExt;
+                assert!(fs::metadata(&out).unwrap().blocks() * 512 >= a_new.len() as u64, "{}", out.display());
+            }
+        }
+
+        let mut plain = HDiff::new(path("src"), path("dir.hdiff"), path("plain"));
+        plain.try_apply().unwrap();
+        assert_eq!(plain.output_allocation(), Some(OutputAllocation::Sparse));
+    }
+
+    #[test]
+    fn krdiff_batch_applies_staged_groups() {
+        let dir = scratch_dir("kr-batch");
+        let a_old = sample_bytes(30, 40_000);
+        let b_old = sample_bytes(31, 30_000);
+        let mut a_first = a_old.clone();
+        a_first[100..200].copy_from_slice(&[1; 100]);
+        let mut a_last = a_old.clone();
+        a_last[300..400].copy_from_slice(&[2; 100]);
+        let mut b_new = b_old.clone();
+        b_new.extend_from_slice(b"appended by group 2");
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("b.bin", &b_old)];
+        write_tree(&dir.join("game"), &old);
+
+        // Groups 1 and 10 both write a.bin, so 10 has to run after 1 even with spare workers; group 2 is independent.
+        let staging = dir.join("staging");
+        fs::create_dir_all(&staging).unwrap();
+        fs::write(staging.join("2.6.2_2.7.0_group_10_1758874286190.krpdiff"), build_kr_patch(&old[..1], &[("a.bin", &a_last)])).unwrap();
+        fs::write(staging.join("2.6.2_2.7.0_group_1_1758874286190.krpdiff"), build_kr_patch(&old[..1], &[("a.bin", &a_first)])).unwrap();
+        fs::write(staging.join("2.6.2_2.7.0_group_2_1758874286190.krpdiff"), build_kr_patch(&old[1..], &[("b.bin", &b_new)])).unwrap();
+        fs::write(staging.join("notes.txt"), b"not a patch").unwrap();
+        // Would sort as 2.6.2 if the suffix were dropped.
+        fs::write(staging.join("2.6.2-beta_2.7.0_group_3_1758874286190.krpdiff"), b"").unwrap();
+
+        let path = |p: &Path| p.to_string_lossy().into_owned();
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        let mut batch = KrDiffBatch::new(path(&staging), path(&dir.join("game")), path(&dir.join("out"))).with_parallelism(4).with_progress(move |written, total| sink.lock().unwrap().push((written, total)));
+        let groups: Vec<u32> = batch.discover().unwrap().iter().map(|p| p.group).collect();
+        assert_eq!(groups, [1, 2, 10]);
+        let results = batch.apply().unwrap();
+        assert!(results.iter().all(|r| r.result.is_ok()), "{:?}", results);
+        assert_eq!(fs::read(dir.join("out/a.bin")).unwrap(), a_last);
+        assert_eq!(fs::read(dir.join("out/b.bin")).unwrap(), b_new);
+        let total = (a_first.len() + a_last.len() + b_new.len()) as u64;
+        let reported = reported.lock().unwrap();
+        assert!(reported.windows(2).all(|w| w[0].0 <= w[1].0));
+        assert_eq!(reported.last(), Some(&(total, total)));
+
+        // A failed group takes the later groups writing the same files with it; the others still run.
+        let mut broken = build_kr_patch(&old[..1], &[("a.bin", &a_first)]);
+        broken.truncate(broken.len() - 10);
+        fs::write(staging.join("2.6.2_2.7.0_group_1_1758874286190.krpdiff"), broken).unwrap();
+        let results = KrDiffBatch::new(path(&staging), path(&dir.join("game")), path(&dir.join("out2"))).apply().unwrap();
+        let outcome: Vec<(u32, bool)> = results.iter().map(|r| (r.patch.group, r.result.is_ok())).collect();
+        assert_eq!(outcome, [(1, false), (2, true), (10, false)]);
+        assert!(results[2].result.as_ref().unwrap_err().to_string().contains("group 1"));
+        assert_eq!(fs::read(dir.join("out2/b.bin")).unwrap(), b_new);
+
+        // Groups from different updates are not mixed.
+        fs::write(staging.join("2.7.0_2.8.0_group_1_1758874286190.krpdiff"), b"").unwrap();
+        let err = KrDiffBatch::new(path(&staging), path(&dir.join("game")), path(&dir.join("out3"))).apply().unwrap_err();
+        assert!(err.to_string().contains("2.8.0"), "{}", err);
+    }
+
+    // Just the HDIFF19 head of a KrDiff patch writing one new file of `size` bytes; nothing past it is there.
+    fn kr_head_only(path: &str, size: u64) -> Vec<u8> {
+        let mut head = [path.as_bytes(), b"\0"].concat();
+        pack_deltas(&mut head, &[0]);
+        pack_uint(&mut head, size);
+        pack_uint(&mut head, 0);
+        let mut out = b"HDIFF19&&\0".to_vec();
+        out.extend_from_slice(&[1, 1]);
+        for v in [0, 0, 1, path.len() as u64 + 1, 0, 0, 1, size, 0, 0, 0, 0, 0, 0, head.len() as u64, 0, 0] { pack_uint(&mut out, v); }
+        out.extend_from_slice(&head);
+        out
+    }
+
+    #[test]
+    fn krdiff_batch_checks_space_for_all_groups_at_once() {
+        let dir = scratch_dir("kr-batch-space");
+        let (staging, game, out) = (dir.join("staging"), dir.join("game"), dir.join("out"));
+        fs::create_dir_all(&staging).unwrap();
+        fs::create_dir_all(&game).unwrap();
+        let path = |p: &Path| p.to_string_lossy().into_owned();
+        let batch = || KrDiffBatch::new(path(&staging), path(&game), path(&out));
+
+        // Learn what is free from a group that cannot fit.
+        fs::write(staging.join("1.0.0_1.1.0_group_1_1.krpdiff"), kr_head_only("a.bin", 1 << 50)).unwrap();
+        let err = batch().apply().unwrap_err();
+        // No free space figure on this platform.
+        let Some(space) = err.downcast_ref::<InsufficientSpaceError>() else { return; };
+        let size = space.available / 10 * 6;
+
+        // Each group fits on its own, so it gets as far as its missing diff data.
+        fs::write(staging.join("1.0.0_1.1.0_group_1_1.krpdiff"), kr_head_only("a.bin", size)).unwrap();
+        let results = batch().apply().unwrap();
+        assert!(results[0].result.is_err());
+
+        // Both would run side by side, and together they do not fit.
+        fs::write(staging.join("1.0.0_1.1.0_group_2_1.krpdiff"), kr_head_only("b.bin", size)).unwrap();
+        let err = batch().apply().unwrap_err();
+        let space = err.downcast_ref::<InsufficientSpaceError>().unwrap_or_else(|| panic!("unexpected error: {}", err));
+        assert_eq!(space.needed, 2 * size);
+        assert!(!out.join("a.bin").exists() && !out.join("b.bin").exists());
+    }
+
+    #[test]
+    fn krdiff_checksums_are_verified() {
+        let dir = scratch_dir("kr-checksums");
+        let a_old = sample_bytes(32, 50_000);
+        let mut a_new = a_old.clone();
+        a_new[2000..2100].copy_from_slice(&[4; 100]);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("b.bin", b"old b")];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("c.bin", b"brand new")];
+        let patch = build_kr_patch_with_checksums(&old, &new, true);
+        fs::write(dir.join("patch.krpdiff"), &patch).unwrap();
+        write_tree(&dir.join("game"), &old);
+
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+        let section_of = |out: &str, patch: &str| {
+            let err = KrDiff::new(path("game"), path(patch), path(out)).try_apply().unwrap_err();
+            err.downcast_ref::<ChecksumMismatchError>().unwrap_or_else(|| panic!("unexpected error: {}", err)).section
+        };
+        KrDiff::new(path("game"), path("patch.krpdiff"), path("out")).try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("out/a.bin")).unwrap(), a_new);
+
+        // Flipping a byte of the diff data is caught before anything is written.
+        let mut corrupt = patch.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        fs::write(dir.join("corrupt.krpdiff"), corrupt).unwrap();
+        assert_eq!(section_of("out-corrupt", "corrupt.krpdiff"), ChecksumSection::Head);
+        assert!(!dir.join("out-corrupt/c.bin").exists());
+
+        // Same size, different old bytes.
+        fs::write(dir.join("game/b.bin"), b"old B").unwrap();
+        assert_eq!(section_of("out-old", "patch.krpdiff"), ChecksumSection::OldReference);
+        fs::write(dir.join("game/b.bin"), b"old b").unwrap();
+
+        // A patch whose new-file checksum disagrees with what its covers produce.
+        let mut wrong_new = patch;
+        let slot = wrong_new.windows(4).position(|w| w == crc32_of([Ok(&[&a_new[..], b"brand new"].concat()[..])]).unwrap().to_le_bytes()).unwrap();
+        wrong_new[slot] ^= 1;
+        let rest_at = slot + 16;
+        let rest = crc32_of([Ok(&wrong_new[rest_at..])]).unwrap().to_le_bytes();
+        wrong_new[slot + 12..slot + 16].copy_from_slice(&rest);
+        fs::write(dir.join("wrong-new.krpdiff"), wrong_new).unwrap();
+        assert_eq!(section_of("out-new", "wrong-new.krpdiff"), ChecksumSection::NewReference);
+
+        // Checksum types that cannot be checked are refused instead of skipped.
+        let patch = fs::read(dir.join("patch.krpdiff")).unwrap();
+        for kind in ["fadler64", "md5"] {
+            let at = patch.windows(6).position(|w| w == b"crc32\0").unwrap();
+            let declared = [&patch[..at], kind.as_bytes(), &patch[at + 5..]].concat();
+            fs::write(dir.join("declared.krpdiff"), declared).unwrap();
+            let err = KrDiff::new(path("game"), path("declared.krpdiff"), path("out-declared")).try_apply().unwrap_err();
+            assert!(err.to_string().contains(&format!("declares {} checksums", kind)), "{}", err);
+            assert!(!dir.join("out-declared/c.bin").exists(), "{}", kind);
+        }
+    }
+
+    #[test]
+    fn krdiff_per_file_head_varint_is_skipped() {
+        let dir = scratch_dir("kr-unknown");
+        let a_old = sample_bytes(35, 5000);
+        let mut a_new = a_old.clone();
+        a_new[100..150].copy_from_slice(&[9; 50]);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old)];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("b/", b""), ("b/c.bin", b"fresh"), ("d.bin", b"")];
+        let mut patch = build_kr_patch(&old, &new);
+        // The fixture writes a one-byte 0 for every new file right before the inner diff; give each its own value.
+        let inner = patch.windows(9).position(|w| w == b"HDIFF13&\0").unwrap();
+        patch[inner - 3..inner].copy_from_slice(&[5, 0x7F, 42]);
+        fs::write(dir.join("patch.krpdiff"), &patch).unwrap();
+
+        let info = KrDiffInfo::read(dir.join("patch.krpdiff")).unwrap();
+        let new_files: Vec<_> = info.new_files.iter().map(|fe| (fe.path.as_str(), fe.size)).collect();
+        assert_eq!(new_files, [("a.bin", 5000), ("b/c.bin", 5), ("d.bin", 0)]);
+
+        // Nothing is checked against the values, so the patch applies as before.
+        write_tree(&dir.join("src"), &old);
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+        KrDiff::new(path("src"), path("patch.krpdiff"), path("out")).try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("out/a.bin")).unwrap(), a_new);
+        assert_eq!(fs::read(dir.join("out/b/c.bin")).unwrap(), b"fresh");
+    }
+
+    #[test]
+    fn krdiff_info_lists_patch_contents() {
+        let dir = scratch_dir("kr-info");
+        let a_old = sample_bytes(33, 20_000);
+        let mut a_new = a_old.clone();
+        a_new[500..600].copy_from_slice(&[5; 100]);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a_old), ("gone.txt", b"bye")];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &a_new), ("data/", b""), ("data/b.bin", b"fresh")];
+        let patch = build_kr_patch(&old, &new);
+        fs::write(dir.join("patch.krpdiff"), &patch).unwrap();
+
+        let info = KrDiffInfo::read(dir.join("patch.krpdiff")).unwrap();
+        let listed = |files: &[KrFileEntry]| files.iter().map(|fe| (fe.path.clone(), fe.size)).collect::<Vec<_>>();
+        assert_eq!(listed(&info.old_files), [("a.bin".to_string(), 20_000), ("gone.txt".to_string(), 3)]);
+        assert_eq!(listed(&info.new_files), [("a.bin".to_string(), 20_000), ("data/b.bin".to_string(), 5)]);
+        assert_eq!(info.new_directories, ["data/"]);
+        assert_eq!((info.compression, info.diff_compression), (CompressionMode::Nocomp, CompressionMode::Nocomp));
+        assert!(info.cover_count >= 2);
+        assert_eq!(info.new_data_size, 20_005);
+        assert_eq!(info.new_data_diff_compressed_size, 0);
+        let diff_end = info.new_data_diff_offset + info.new_data_diff_size;
+        assert_eq!(diff_end, patch.len() as u64);
+
+        let err = KrDiffInfo::read(dir.join("missing.krpdiff")).unwrap_err();
+        assert!(err.to_string().contains("missing.krpdiff"), "{}", err);
+    }
+
+    #[test]
+    fn krdiff_paths_are_read_exactly() {
+        let dir = scratch_dir("kr-paths");
+        let long_dir = format!("{}/", ["deep"; 80].join("/"));
+        let long_file = format!("{}asset.bin", long_dir);
+        assert!(long_file.len() > 255);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", b"old a")];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", b"new a"), (&long_dir, b""), (&long_file, b"behind a long path"), ("X.bin", b"x")];
+        let patch = build_kr_patch(&old, &new);
+        fs::write(dir.join("long.krpdiff"), &patch).unwrap();
+        write_tree(&dir.join("game"), &old);
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+
+        // Used to stop after 255 bytes and read the rest of the path as the next one.
+        KrDiff::new(path("game"), path("long.krpdiff"), path("out")).try_apply().unwrap();
+        assert_eq!(fs::read(dir.join("out").join(&long_file)).unwrap(), b"behind a long path");
+        assert_eq!(fs::read(dir.join("out/X.bin")).unwrap(), b"x");
+
+        // Invalid UTF-8 is an error instead of a path full of replacement characters.
+        let mut invalid = patch.clone();
+        let at = invalid.windows(6).position(|w| w == b"X.bin\0").unwrap();
+        invalid[at] = 0xFF;
+        fs::write(dir.join("invalid.krpdiff"), invalid).unwrap();
+        let err = KrDiff::new(path("game"), path("invalid.krpdiff"), path("out-invalid")).try_apply().unwrap_err();
+        assert!(err.to_string().contains("not valid UTF-8"), "{}", err);
+
+        // The old path sum is the second varint after the magic and the two dir flags; it has to match the paths.
+        let mut wrong_sum = patch;
+        let sum_at = b"HDIFF19&&\0".len() + 2 + 1;
+        assert_eq!(wrong_sum[sum_at], b"a.bin\0".len() as u8);
+        wrong_sum[sum_at] += 1;
+        fs::write(dir.join("wrong-sum.krpdiff"), wrong_sum).unwrap();
+        let err = KrDiffInfo::read(dir.join("wrong-sum.krpdiff")).unwrap_err();
+        assert!(err.to_string().contains("old paths"), "{}", err);
+    }
+
+    #[test]
+    fn krdiff_writer_round_trips_directories() {
+        let dir = scratch_dir("kr-writer");
+        let big = sample_bytes(34, 30_000);
+        let mut big_new = big[5000..].to_vec();
+        big_new[100..200].copy_from_slice(&[6; 100]);
+        write_tree(&dir.join("old"), &[("big.bin", &big), ("gone.txt", b"removed"), ("keep/", b""), ("keep/same.txt", b"unchanged file")]);
+        write_tree(&dir.join("new"), &[("big.bin", &big_new), ("empty/", b""), ("keep/same.txt", b"unchanged file"), ("moved/", b""), ("moved/big-copy.bin", &big), ("zero.bin", b"")]);
+
+        KrDiffWriter::new(dir.join("old"), dir.join("new")).with_crc32(true).write(dir.join("patch.krpdiff")).unwrap();
+        let info = KrDiffInfo::read(dir.join("patch.krpdiff")).unwrap();
+        let new_files: Vec<_> = info.new_files.iter().map(|fe| (fe.path.as_str(), fe.size)).collect();
+        assert_eq!(new_files, [("big.bin", 25_000), ("keep/same.txt", 14), ("moved/big-copy.bin", 30_000), ("zero.bin", 0)]);
+        assert_eq!(info.new_directories, ["empty/", "keep/", "moved/"]);
+        // Everything but the changed run comes from old data.
+        assert!(info.new_data_diff_size < 200, "{}", info.new_data_diff_size);
+
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+        KrDiff::new(path("old"), path("patch.krpdiff"), path("out")).try_apply().unwrap();
+        for file in ["big.bin", "keep/same.txt", "moved/big-copy.bin", "zero.bin"] { assert_eq!(fs::read(dir.join("out").join(file)).unwrap(), fs::read(dir.join("new").join(file)).unwrap(), "{}", file); }
+        assert!(dir.join("out/empty").is_dir());
+    }
+
+    #[test]
+    fn krdiff_stages_outputs_that_alias_old_files() {
+        let dir = scratch_dir("kr-alias");
+        let (a, b) = (sample_bytes(35, 40_000), sample_bytes(36, 20_000));
+        let mut b_new = a.clone();
+        b_new[1000..1100].copy_from_slice(&[7; 100]);
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", &a), ("b.bin", &b), ("gone/", b""), ("gone/old.txt", b"not in the new version")];
+        // a.bin and b.bin swap contents, so whichever is written first destroys what the other is built from.
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", &b), ("b.bin", &b_new), ("c.bin", &a)];
+        write_tree(&dir.join("old"), &old);
+        write_tree(&dir.join("new"), &new);
+        KrDiffWriter::new(dir.join("old"), dir.join("new")).with_crc32(true).write(dir.join("patch.krpdiff")).unwrap();
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+        let check = |root: &str| for (file, data) in &new { assert_eq!(&fs::read(dir.join(root).join(file)).unwrap(), data, "{}/{}", root, file); };
+
+        write_tree(&dir.join("game"), &old);
+        KrDiff::new(path("game"), path("patch.krpdiff"), path("game")).try_apply().unwrap();
+        check("game");
+        assert!(dir.join("game/gone/old.txt").exists());
+        assert!(!dir.join("game/.hdiffpatch-staging").exists());
+
+        let _ = fs::remove_dir_all(dir.join("game"));
+        write_tree(&dir.join("game"), &old);
+        let options = PatchOptions { remove_old_files: true, ..Default::default() };
+        KrDiff::new(path("game"), path("patch.krpdiff"), path("game")).with_options(options.clone()).try_apply().unwrap();
+        check("game");
+        assert!(!dir.join("game/gone").exists());
+
+        // A separate output directory whose a.bin is a hard link to the old one.
+        #[cfg(unix)]
+        {
+            let _ = fs::remove_dir_all(dir.join("game"));
+            write_tree(&dir.join("game"), &old);
+            fs::create_dir_all(dir.join("linked")).unwrap();
+            fs::hard_link(dir.join("game/a.bin"), dir.join("linked/a.bin")).unwrap();
+            KrDiff::new(path("game"), path("patch.krpdiff"), path("linked")).try_apply().unwrap();
+            check("linked");
+            assert_eq!(fs::read(dir.join("game/a.bin")).unwrap(), a);
+        }
+
+        let staging = dir.join("staging");
+        fs::create_dir_all(&staging).unwrap();
+        fs::copy(dir.join("patch.krpdiff"), staging.join("1.0.0_1.1.0_group_1_1.krpdiff")).unwrap();
+        let err = KrDiffBatch::new(path("staging"), path("game"), path("game")).with_options(options).apply().unwrap_err();
+        assert!(err.to_string().contains("remove_old_files"), "{}", err);
+    }
+
+    #[cfg(feature = "kuro-index")]
+    #[test]
+    fn krdiff_output_is_checked_against_resource_index() {
+        use crate::patchers::{KuroIndexReport, KuroResourceIndex};
+        let dir = scratch_dir("kuro-index");
+        let old: Vec<(&str, &[u8])> = vec![("a.bin", b"old contents of a")];
+        let new: Vec<(&str, &[u8])> = vec![("a.bin", b"new contents of a!"), ("data/", b""), ("data/b.bin", b"fresh"), ("empty.bin", b"")];
+        fs::write(dir.join("patch.krpdiff"), build_kr_patch(&old, &new)).unwrap();
+        write_tree(&dir.join("game"), &old);
+        write_tree(&dir.join("out"), &[("keep.txt", b"shortened"), ("logs/", b""), ("logs/extra.log", b"launcher log")]);
+        // Shaped like Kuro's own index, extra fields included; b.bin's hash is deliberately wrong.
+        fs::write(dir.join("index.json"), r#"{"resource": [
+            {"dest": "/a.bin", "md5": "E78A3BD382CD34DB7A279CB1D949E618", "sampleHash": "ignored", "size": 18},
+            {"dest": "/data/b.bin", "md5": "00000000000000000000000000000000", "size": 5},
+            {"dest": "/empty.bin", "md5": "d41d8cd98f00b204e9800998ecf8427e", "size": 0},
+            {"dest": "/keep.txt", "md5": "00000000000000000000000000000000", "size": 100},
+            {"dest": "/Client/missing.pak", "md5": "00000000000000000000000000000000", "size": 1}
+        ]}"#).unwrap();
+
+        let index = KuroResourceIndex::load(dir.join("index.json")).unwrap();
+        let path = |p: &str| dir.join(p).to_string_lossy().into_owned();
+        let mut kr = KrDiff::new(path("game"), path("patch.krpdiff"), path("out")).with_resource_index(index.clone());
+        kr.try_apply().unwrap();
+        let expected = KuroIndexReport {
+            missing: vec!["Client/missing.pak".into()],
+            extra: vec!["logs/extra.log".into()],
+            wrong_size: vec![("keep.txt".into(), 100, 9)],
+            wrong_hash: vec![("data/b.bin".into(), "00000000000000000000000000000000".into(), "76010858c8362d7302ef5f9436aa6639".into())],
+        };
+        assert_eq!(kr.index_report(), Some(&expected));
+        assert!(!expected.is_clean());
+        // Reading everything back from disk agrees with the hashes taken while writing.
+        assert_eq!(index.verify(dir.join("out")).unwrap(), expected);
+
+        let list = KuroResourceIndex::parse(r#"[{"dest": "/a.bin", "md5": "e78a3bd382cd34db7a279cb1d949e618", "size": 18}]"#).unwrap();
+        assert_eq!(list.resources.len(), 1);
+        assert!(KuroResourceIndex::parse(r#"[{"dest": "/../escape", "md5": "", "size": 0}]"#).unwrap().verify(dir.join("out")).unwrap_err().downcast_ref::<UnsafePathError>().is_some());
+        assert!(KuroResourceIndex::parse("{}").is_err());
+    }
 }
@@ -1,34 +1,76 @@
-use std::fs::File;
-use std::io::{BufWriter, Write};
-use crate::patchers::HDiff;
+use std::cell::RefCell;
+use std::fs::{self, File};
+use std::io::{BufWriter, Seek, SeekFrom};
+use std::path::Path;
+use std::rc::Rc;
+use crate::patchers::{progress_callback, HDiff, OutputAllocation, PatchOptions};
+use crate::utils::atomic_file::{is_same_file, AtomicFile};
+use crate::utils::checkpoint::{clear_checkpoint, start_checkpointing, verify_written_prefix, Checkpoint, Checkpointer};
+use crate::utils::disk_space::{ensure_free_space, existing_file_size, size_output_file};
 use crate::utils::header::Header;
+use crate::utils::paths::into_patch_error;
 use crate::utils::patch_dir::PatchDir;
 use crate::utils::patch_sf::PatchSF;
 use crate::utils::patch_single::PatchSingle;
-use crate::utils::structs::DataReferenceInfo;
+use crate::utils::structs::{DataReferenceInfo, HeaderInfo};
 
 impl HDiff {
     pub fn new(source_path: String, diff_path: String, dest_path: String) -> Self {
-        HDiff { source_path, diff_path, dest_path }
+        HDiff { source_path, diff_path, dest_path, options: PatchOptions::default(), progress: None, allocation: None }
+    }
+
+    pub fn with_options(mut self, options: PatchOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Reports every chunk of output written, in bytes.
+    pub fn with_progress(mut self, progress: impl FnMut(i64) + 'static) -> Self {
+        self.progress = Some(Rc::new(RefCell::new(progress)));
+        self
     }
 
     pub fn apply(&mut self) -> bool {
-        match self.apply_inner() {
+        match self.apply_inner(false) {
             Ok(()) => true,
             Err(e) => { eprintln!("[HDiff::apply] Error: {}", e); false }
         }
     }
 
-    fn apply_inner(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Same as `apply`, but hands back the error. Unsafe paths in the patch come back as an `UnsafePathError`.
+    pub fn try_apply(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.apply_inner(false)
+    }
+
+    /// How the output files were sized by the last `apply`/`resume`: `Preallocated` when `PatchOptions::preallocate`
+    /// reserved every file's blocks, `Sparse` otherwise. `None` before a patch got to sizing any output.
+    pub fn output_allocation(&self) -> Option<OutputAllocation> {
+        self.allocation
+    }
+
+    /// Continues an interrupted patch from the checkpoint in `PatchOptions::checkpoint_path`.
+    /// The output written before the interruption is verified against the checkpoint first.
+    pub fn resume(&mut self) -> bool {
+        match self.apply_inner(true) {
+            Ok(()) => true,
+            Err(e) => { eprintln!("[HDiff::resume] Error: {}", e); false }
+        }
+    }
+
+    fn apply_inner(&mut self, resume: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.allocation = None;
         let mut diff_file = File::open(&self.diff_path)?;
         let mut header_info = Default::default();
         let mut reference_info: DataReferenceInfo = Default::default();
         let is_dir_patch = Header::try_parse_header_info(&mut diff_file, &self.diff_path, &mut header_info, &mut reference_info)?;
 
-        if is_dir_patch && header_info.is_input_dir && header_info.is_output_dir {
-            let mut patcher = PatchDir::new(header_info, reference_info, self.diff_path.clone());
-            patcher.patch(&self.source_path, &self.dest_path, None)?;
-            return Ok(());
+        // HDIFF19 covers dir-to-dir as well as file-to-dir and dir-to-file; PatchDir treats a file side as a one-entry tree.
+        if is_dir_patch {
+            let mut patcher = PatchDir::new(header_info, reference_info, self.diff_path.clone(), self.options.clone());
+            patcher.set_resume(resume);
+            let result = patcher.patch(&self.source_path, &self.dest_path, progress_callback(&self.progress));
+            self.allocation = patcher.output_allocation();
+            return result.map_err(into_patch_error);
         }
 
         let mut old_file = File::open(&self.source_path)?;
@@ -38,10 +80,52 @@ impl HDiff {
         #[cfg(debug_assertions)]
         println!("[HDiff::apply] Old size: {} ✓ | New size: {}", old_len, header_info.new_data_size);
 
-        let out_file = File::create(&self.dest_path)?;
-        let mut out_writer = BufWriter::new(out_file);
-        if header_info.is_single_compressed_diff { PatchSF::new(header_info).patch(&mut old_file, &mut out_writer, &self.diff_path, None)?; } else { PatchSingle::new(header_info).patch(&mut old_file, &mut out_writer, &self.diff_path, None)?; }
-        out_writer.flush()?;
+        // The output goes to a temp file next to the destination, so a destination that is the source itself
+        // is only replaced once the source has been read in full.
+        let output = AtomicFile::new(Path::new(&self.dest_path))?;
+        let in_place = is_same_file(Path::new(&self.source_path), Path::new(&self.dest_path))?;
+        // The rename replaces only the destination's own name. Were that a second hard link to the source, the two
+        // names would part ways with just one of them patched, unlike a symlink, which is written through.
+        if in_place && fs::canonicalize(&self.source_path)? != fs::canonicalize(&self.dest_path)? {
+            return Err(format!("[HDiff::apply] {} is a hard link to the source {}, patch the source path itself or write to a separate file", self.dest_path, self.source_path).into());
+        }
+
+        #[cfg(debug_assertions)]
+        println!("[HDiff::apply] Writing {}{}", output.temp_path().display(), if in_place { " (in place)" } else { "" });
+
+        if !self.options.skip_disk_space_check {
+            // The old destination stays until the rename, so only a leftover temp file gives space back.
+            ensure_free_space(output.temp_path(), (header_info.new_data_size as u64).saturating_sub(existing_file_size(output.temp_path()))).map_err(into_patch_error)?;
+        }
+
+        let checkpointing = start_checkpointing(&self.options, &self.diff_path, header_info.new_data_size as u64, resume)?;
+        let out_file = match checkpointing.as_ref().and_then(|(_, cp)| cp.as_ref()) {
+            Some(cp) => {
+                let mut file = output.reopen()?;
+                verify_written_prefix(&mut file, cp)?;
+                file.seek(SeekFrom::Start(cp.new_pos))?;
+                file
+            }
+            None => output.create()?,
+        };
+        let result = self.write_output(header_info, &mut old_file, out_file, checkpointing);
+        drop(old_file);
+        let out_file = match result {
+            Ok(file) => file,
+            // A checkpointed run keeps its temp file for `resume`.
+            Err(e) => { if self.options.checkpoint_path.is_none() { output.discard(); } return Err(e); }
+        };
+        output.commit(out_file)?;
+        if let Some(path) = &self.options.checkpoint_path { clear_checkpoint(path)?; }
         Ok(())
     }
+
+    fn write_output(&mut self, header_info: HeaderInfo, old_file: &mut File, out_file: File, checkpointing: Option<(Checkpointer, Option<Checkpoint>)>) -> Result<File, Box<dyn std::error::Error>> {
+        // Only sized up front when asked to; a plain single-file patch just grows its output as it writes.
+        if self.options.preallocate { self.allocation = Some(size_output_file(&out_file, header_info.new_data_size as u64, true)?); }
+        let mut out_writer = BufWriter::new(out_file);
+        let cb = progress_callback(&self.progress);
+        if header_info.is_single_compressed_diff { PatchSF::new(header_info).with_checkpointing(checkpointing).patch(old_file, &mut out_writer, &self.diff_path, cb)?; } else { PatchSingle::new(header_info).with_checkpointing(checkpointing).patch(old_file, &mut out_writer, &self.diff_path, cb)?; }
+        Ok(out_writer.into_inner().map_err(|e| e.into_error())?)
+    }
 }
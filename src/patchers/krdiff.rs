@@ -1,6 +1,14 @@
+use std::cell::RefCell;
+#[cfg(feature = "kuro-index")]
+use std::collections::HashMap;
 use std::fs::create_dir_all;
+use std::io;
 use std::path::Path;
-use crate::patchers::KrDiff;
+use std::rc::Rc;
+use crate::patchers::{progress_callback, KrDiff, OutputAllocation, PatchOptions};
+#[cfg(feature = "kuro-index")]
+use crate::patchers::{KuroIndexReport, KuroResourceIndex};
+use crate::utils::paths::into_patch_error;
 use crate::utils::patch_krdir::KrPatchDir;
 
 /*
@@ -12,27 +20,122 @@ HERE BE DRAGONS you are warned!!!
 
 impl KrDiff {
     pub fn new(source_path: String, diff_path: String, dest_path: String) -> Self {
-        KrDiff { source_path, diff_path, dest_path }
+        KrDiff {
+            source_path,
+            diff_path,
+            dest_path,
+            options: PatchOptions::default(),
+            progress: None,
+            allocation: None,
+            #[cfg(feature = "kuro-index")]
+            resource_index: None,
+            #[cfg(feature = "kuro-index")]
+            index_report: None,
+        }
+    }
+
+    pub fn with_options(mut self, options: PatchOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Reports every chunk of output written, in bytes.
+    pub fn with_progress(mut self, progress: impl FnMut(i64) + 'static) -> Self {
+        self.progress = Some(Rc::new(RefCell::new(progress)));
+        self
     }
 
     pub fn apply(&mut self) -> bool {
-        match self.apply_inner() {
+        match self.apply_inner(false) {
             Ok(()) => true,
             Err(e) => { eprintln!("[KrDiff::apply] Error: {}", e); false }
         }
     }
 
-    fn apply_inner(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let src = Path::new(&self.source_path);
-        let diffp = Path::new(&self.diff_path);
+    /// Same as `apply`, but hands back the error. Unsafe paths in the patch come back as an `UnsafePathError`.
+    pub fn try_apply(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.apply_inner(false)
+    }
+
+    /// How the output files were sized by the last `apply`/`resume`: `Preallocated` when `PatchOptions::preallocate`
+    /// reserved every file's blocks, `Sparse` otherwise. `None` before a patch got to sizing any output.
+    pub fn output_allocation(&self) -> Option<OutputAllocation> {
+        self.allocation
+    }
+
+    /// Checks the output against Kuro's resource index after every successful `apply`/`resume`. New files are
+    /// hashed while they are written; the rest of the destination is read back. See `index_report`.
+    #[cfg(feature = "kuro-index")]
+    pub fn with_resource_index(mut self, index: KuroResourceIndex) -> Self {
+        self.resource_index = Some(index);
+        self
+    }
+
+    /// What the check against the resource index found, once a patch went through. Mismatches do not make
+    /// `apply` fail; they are only reported here.
+    #[cfg(feature = "kuro-index")]
+    pub fn index_report(&self) -> Option<&KuroIndexReport> {
+        self.index_report.as_ref()
+    }
+
+    /// Continues an interrupted patch from the checkpoint in `PatchOptions::checkpoint_path`.
+    pub fn resume(&mut self) -> bool {
+        match self.apply_inner(true) {
+            Ok(()) => true,
+            Err(e) => { eprintln!("[KrDiff::resume] Error: {}", e); false }
+        }
+    }
 
-        let dst = std::path::PathBuf::from(&self.dest_path);
-        if !src.exists() || !src.is_dir() { return Err(format!("[KrDiff] Source path {} does not exist or is not a directory", src.display()).into()); }
-        if !diffp.exists() || !diffp.is_file() { return Err(format!("[KrDiff] Diff file {} does not exist", diffp.display()).into()); }
-        if !dst.exists() { create_dir_all(&dst)?; }
+    fn apply_inner(&mut self, resume: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.allocation = None;
+        let mut outcome = KrOutcome::default();
+        #[cfg(feature = "kuro-index")]
+        {
+            self.index_report = None;
+            if self.resource_index.is_some() { outcome.output_md5s = Some(HashMap::new()); }
+        }
+        let result = run_kr_patch(&self.source_path, &self.diff_path, &self.dest_path, &self.options, resume, true, progress_callback(&self.progress), &mut outcome);
+        self.allocation = outcome.allocation;
+        result.map_err(into_patch_error)?;
 
-        let patcher = KrPatchDir::new(self.diff_path.clone());
-        patcher.patch(src.to_str().unwrap_or(""), dst.to_str().unwrap_or(""), None)?;
+        #[cfg(feature = "kuro-index")]
+        if let Some(index) = &self.resource_index {
+            let report = index.verify_with(Path::new(&self.dest_path), &outcome.output_md5s.unwrap_or_default()).map_err(into_patch_error)?;
+            self.index_report = Some(report);
+        }
         Ok(())
     }
 }
+
+/// What `run_kr_patch` hands back besides its result.
+#[derive(Default)]
+pub(crate) struct KrOutcome {
+    pub allocation: Option<OutputAllocation>,
+    /// Set to `Some` beforehand to have every new file hashed while it is written.
+    #[cfg(feature = "kuro-index")]
+    pub output_md5s: Option<HashMap<String, [u8; 16]>>,
+}
+
+/// `apply`/`resume` without a `KrDiff`, so `KrDiffBatch` can run it on its worker threads. `KrDiffBatch` recovers the
+/// destination's journal itself before any group starts, and passes `recover_journal: false`.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn run_kr_patch(source_path: &str, diff_path: &str, dest_path: &str, options: &PatchOptions, resume: bool, recover_journal: bool, write_bytes_cb: Option<Box<dyn FnMut(i64)>>, outcome: &mut KrOutcome) -> io::Result<()> {
+    let src = Path::new(source_path);
+    let diffp = Path::new(diff_path);
+
+    let dst = std::path::PathBuf::from(dest_path);
+    if !src.exists() || !src.is_dir() { return Err(io::Error::new(io::ErrorKind::NotFound, format!("[KrDiff] Source path {} does not exist or is not a directory", src.display()))); }
+    if !diffp.exists() || !diffp.is_file() { return Err(io::Error::new(io::ErrorKind::NotFound, format!("[KrDiff] Diff file {} does not exist", diffp.display()))); }
+    if !dst.exists() { create_dir_all(&dst)?; }
+
+    let mut patcher = KrPatchDir::new(diff_path.to_string(), options.clone());
+    patcher.set_resume(resume);
+    patcher.set_recover_journal(recover_journal);
+    #[cfg(feature = "kuro-index")]
+    patcher.set_hash_outputs(outcome.output_md5s.is_some());
+    let result = patcher.patch(src.to_str().unwrap_or(""), dst.to_str().unwrap_or(""), write_bytes_cb);
+    outcome.allocation = patcher.output_allocation();
+    #[cfg(feature = "kuro-index")]
+    { outcome.output_md5s = patcher.take_output_md5s(); }
+    result
+}
@@ -0,0 +1,226 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use crate::patchers::krdiff::{run_kr_patch, KrOutcome};
+use crate::patchers::{KrDiffBatch, PatchOptions};
+use crate::utils::atomic_file::is_same_file;
+use crate::utils::disk_space::{ensure_free_space, existing_file_size};
+use crate::utils::journal::Journal;
+use crate::utils::patch_krdir::{read_patch_files, KrPatchFiles};
+use crate::utils::paths::{into_patch_error, into_send_patch_error};
+
+/// One `<from>_<to>_group_<n>_<timestamp>.krpdiff` patch found in a staging directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrGroupPatch {
+    pub path: PathBuf,
+    pub from_version: String,
+    pub to_version: String,
+    pub group: u32,
+    pub timestamp: u64,
+}
+
+impl KrGroupPatch {
+    /// `None` when the file name does not follow the group patch pattern, or a version has a part that is not a
+    /// number, such as `2.7.0-beta`, which could not be ordered. `.krdiff` works as well as `.krpdiff`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        if ext != "krpdiff" && ext != "krdiff" { return None; }
+        let stem = path.file_stem()?.to_str()?;
+        let (versions, rest) = stem.split_once("_group_")?;
+        let (from_version, to_version) = versions.split_once('_')?;
+        version_key(from_version)?;
+        version_key(to_version)?;
+        let (group, timestamp) = rest.split_once('_')?;
+        Some(Self { path: path.to_path_buf(), from_version: from_version.to_string(), to_version: to_version.to_string(), group: group.parse().ok()?, timestamp: timestamp.parse().ok()? })
+    }
+
+    fn sort_key(&self) -> (Vec<u64>, Vec<u64>, u32, u64) {
+        let key = |version: &str| version_key(version).unwrap_or_default();
+        (key(&self.from_version), key(&self.to_version), self.group, self.timestamp)
+    }
+}
+
+// "2.10.0" sorts after "2.9.1". `None` when a part is not a number.
+fn version_key(version: &str) -> Option<Vec<u64>> {
+    version.split('.').map(|part| part.parse().ok()).collect()
+}
+
+/// How one group of a batch went.
+#[derive(Debug)]
+pub struct KrGroupResult {
+    pub patch: KrGroupPatch,
+    /// Errors are the ones `KrDiff::try_apply` returns. A group that never ran, because an earlier group
+    /// writing the same files failed, gets an error saying so.
+    pub result: Result<(), Box<dyn Error + Send + Sync>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum GroupState {
+    Pending,
+    Running,
+    Done,
+    Failed,
+}
+
+struct Schedule {
+    states: Vec<GroupState>,
+    results: Vec<Option<Result<(), Box<dyn Error + Send + Sync>>>>,
+}
+
+impl KrDiffBatch {
+    pub fn new(staging_path: String, source_path: String, dest_path: String) -> Self {
+        KrDiffBatch { staging_path, source_path, dest_path, options: PatchOptions::default(), parallelism: 0, progress: None }
+    }
+
+    /// Applied to every group. `transactional` batches run one group at a time, since all groups share the
+    /// destination's journal. `checkpoint_path` and `remove_old_files` are not supported.
+    pub fn with_options(mut self, options: PatchOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// How many groups may be patched at once. Defaults to the number of CPUs.
+    pub fn with_parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    /// Reports the bytes written by all groups so far, along with the total the batch writes.
+    pub fn with_progress(mut self, progress: impl FnMut(u64, u64) + Send + 'static) -> Self {
+        self.progress = Some(Arc::new(Mutex::new(progress)));
+        self
+    }
+
+    /// Lists the group patches in the staging directory in the order they are applied: by version, then group.
+    /// Files with other names are ignored. All patches have to go between the same two versions.
+    pub fn discover(&self) -> io::Result<Vec<KrGroupPatch>> {
+        let mut patches = Vec::new();
+        for entry in fs::read_dir(&self.staging_path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() { continue; }
+            if let Some(patch) = KrGroupPatch::from_path(&entry.path()) { patches.push(patch); }
+        }
+        patches.sort_by_key(KrGroupPatch::sort_key);
+
+        if let Some(first) = patches.first() && let Some(other) = patches.iter().find(|p| p.from_version != first.from_version || p.to_version != first.to_version) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("[KrDiffBatch::discover] {} goes from {} to {}, but {} goes from {} to {}", first.path.display(), first.from_version, first.to_version, other.path.display(), other.from_version, other.to_version)));
+        }
+        Ok(patches)
+    }
+
+    /// Applies every discovered group and reports how each one went. Groups whose output files overlap run in
+    /// order; with the source as destination, so do groups where one writes a file the other reads.
+    /// Only fails as a whole when the batch cannot start, such as with an `InsufficientSpaceError` when all groups
+    /// together need more space than is free.
+    pub fn apply(&mut self) -> Result<Vec<KrGroupResult>, Box<dyn Error>> {
+        if self.options.checkpoint_path.is_some() { return Err(io::Error::new(io::ErrorKind::InvalidInput, "[KrDiffBatch] Checkpoints are not supported for batches").into()); }
+        // An old file one group no longer needs may still be read by another.
+        if self.options.remove_old_files { return Err(io::Error::new(io::ErrorKind::InvalidInput, "[KrDiffBatch] remove_old_files is not supported for batches").into()); }
+        let patches = self.discover()?;
+        let files: Vec<KrPatchFiles> = patches.iter().map(|p| read_patch_files(&p.path, self.options.windows_paths)).collect::<io::Result<_>>()?;
+        let in_place = is_same_file(Path::new(&self.source_path), Path::new(&self.dest_path))?;
+        let deps = dependencies(&files, in_place);
+        let total: u64 = files.iter().map(|f| f.new_size).sum();
+
+        // Done once for all groups: parallel groups would each see the same free space, and race on the journal.
+        let dest = Path::new(&self.dest_path);
+        if !dest.exists() { fs::create_dir_all(dest)?; }
+        Journal::recover(dest)?;
+        if !self.options.skip_disk_space_check { ensure_free_space(dest, self.required_space(&files, in_place)).map_err(into_patch_error)?; }
+        let group_options = PatchOptions { skip_disk_space_check: true, ..self.options.clone() };
+
+        let mut workers = if self.parallelism == 0 { thread::available_parallelism().map(|n| n.get()).unwrap_or(1) } else { self.parallelism };
+        if self.options.transactional { workers = 1; }
+        workers = workers.min(patches.len());
+
+        #[cfg(debug_assertions)]
+        println!("[KrDiffBatch] {} groups, {} bytes, {} workers", patches.len(), total, workers);
+
+        let schedule = Mutex::new(Schedule { states: vec![GroupState::Pending; patches.len()], results: patches.iter().map(|_| None).collect() });
+        let ready = Condvar::new();
+        let written = Arc::new(Mutex::new(0u64));
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| {
+                    while let Some(index) = next_group(&schedule, &ready, &deps, &patches) {
+                        let result = self.run_group(&patches[index], &group_options, total, &written);
+                        let mut s = schedule.lock().unwrap();
+                        s.states[index] = if result.is_ok() { GroupState::Done } else { GroupState::Failed };
+                        s.results[index] = Some(result);
+                        ready.notify_all();
+                    }
+                });
+            }
+        });
+
+        let results = schedule.into_inner().unwrap().results;
+        Ok(patches.into_iter().zip(results).map(|(patch, result)| KrGroupResult { patch, result: result.unwrap_or_else(|| Err("[KrDiffBatch] Group was never run".into())) }).collect())
+    }
+
+    /// Bytes all groups together need beyond what truncating existing files gives back. Like a single `KrDiff`, outputs
+    /// that are staged next to an old file in place, and journaled ones, give nothing back.
+    fn required_space(&self, files: &[KrPatchFiles], in_place: bool) -> u64 {
+        let dest = Path::new(&self.dest_path);
+        let mut needed = 0u64;
+        for f in files {
+            let old: HashSet<&str> = if in_place { f.old_files.iter().map(String::as_str).collect() } else { HashSet::new() };
+            for (path, &size) in f.new_files.iter().zip(&f.new_sizes) {
+                let reused = if self.options.transactional || old.contains(path.as_str()) { 0 } else { existing_file_size(&dest.join(path)) };
+                needed += size.saturating_sub(reused);
+            }
+        }
+        needed
+    }
+
+    fn run_group(&self, patch: &KrGroupPatch, options: &PatchOptions, total: u64, written: &Arc<Mutex<u64>>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let cb: Option<Box<dyn FnMut(i64)>> = self.progress.clone().map(|progress| {
+            let written = written.clone();
+            Box::new(move |n: i64| {
+                // Held while calling back, so the reported totals only ever grow.
+                let mut written = written.lock().unwrap();
+                *written += n as u64;
+                (progress.lock().unwrap())(*written, total);
+            }) as Box<dyn FnMut(i64)>
+        });
+
+        let result = run_kr_patch(&self.source_path, &patch.path.to_string_lossy(), &self.dest_path, options, false, false, cb, &mut KrOutcome::default());
+
+        #[cfg(debug_assertions)]
+        println!("[KrDiffBatch] group {}: {}", patch.group, if result.is_ok() { "done" } else { "failed" });
+        result.map_err(into_send_patch_error)
+    }
+}
+
+/// For every group, the earlier groups it has to wait for.
+fn dependencies(files: &[KrPatchFiles], in_place: bool) -> Vec<Vec<usize>> {
+    let outputs: Vec<HashSet<&str>> = files.iter().map(|f| f.new_files.iter().map(String::as_str).collect()).collect();
+    let touches = |writer: usize, other: usize| files[other].new_files.iter().chain(if in_place { files[other].old_files.iter() } else { [].iter() }).any(|p| outputs[writer].contains(p.as_str()));
+    (0..files.len()).map(|j| (0..j).filter(|&i| touches(i, j) || (in_place && touches(j, i))).collect()).collect()
+}
+
+/// Blocks until a group can start, or returns `None` once none are left. Groups waiting on a failed group fail too.
+fn next_group(schedule: &Mutex<Schedule>, ready: &Condvar, deps: &[Vec<usize>], patches: &[KrGroupPatch]) -> Option<usize> {
+    let mut s = schedule.lock().unwrap();
+    loop {
+        let mut waiting = false;
+        for (i, group_deps) in deps.iter().enumerate() {
+            if s.states[i] != GroupState::Pending { continue; }
+            if let Some(&failed) = group_deps.iter().find(|&&d| s.states[d] == GroupState::Failed) {
+                s.states[i] = GroupState::Failed;
+                s.results[i] = Some(Err(format!("[KrDiffBatch] Skipped, group {} touching the same files failed", patches[failed].group).into()));
+                continue;
+            }
+            if group_deps.iter().all(|&d| s.states[d] == GroupState::Done) {
+                s.states[i] = GroupState::Running;
+                return Some(i);
+            }
+            waiting = true;
+        }
+        if !waiting { return None; }
+        s = ready.wait(s).unwrap();
+    }
+}
@@ -1,14 +1,62 @@
 pub mod krdiff;
+pub mod krdiff_batch;
 pub mod hdiff;
 
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+pub use crate::patchers::krdiff_batch::{KrGroupPatch, KrGroupResult};
+pub use crate::utils::checksum::{ChecksumMismatchError, ChecksumSection};
+pub use crate::utils::disk_space::{InsufficientSpaceError, OutputAllocation};
+pub use crate::utils::filter::FileFilter;
+pub use crate::utils::hdiff_writer::HDiffWriter;
+#[cfg(feature = "kuro-index")]
+pub use crate::utils::kuro_index::{KuroIndexReport, KuroResource, KuroResourceIndex};
+pub use crate::utils::kr_writer::KrDiffWriter;
+pub use crate::utils::patch_krdir::{KrDiffInfo, KrFileEntry};
+pub use crate::utils::paths::UnsafePathError;
+pub use crate::utils::structs::{CompressionMode, OutputTimestamps, PatchOptions, SameFileStrategy, SymlinkPolicy};
+
+/// Called with the number of bytes just written to the output.
+type ProgressCallback = Rc<RefCell<dyn FnMut(i64)>>;
+
 pub struct KrDiff {
     source_path: String,
     diff_path: String,
     dest_path: String,
+    options: PatchOptions,
+    progress: Option<ProgressCallback>,
+    allocation: Option<OutputAllocation>,
+    #[cfg(feature = "kuro-index")]
+    resource_index: Option<KuroResourceIndex>,
+    #[cfg(feature = "kuro-index")]
+    index_report: Option<KuroIndexReport>,
+}
+
+/// Called with the bytes written by all groups of a batch so far and the total the batch writes.
+type BatchProgressCallback = Arc<Mutex<dyn FnMut(u64, u64) + Send>>;
+
+/// Applies every KrDiff group patch found in a staging directory, running groups that touch different files in parallel.
+pub struct KrDiffBatch {
+    staging_path: String,
+    source_path: String,
+    dest_path: String,
+    options: PatchOptions,
+    parallelism: usize,
+    progress: Option<BatchProgressCallback>,
 }
 
 pub struct HDiff {
     source_path: String,
     diff_path: String,
     dest_path: String,
-}
\ No newline at end of file
+    options: PatchOptions,
+    progress: Option<ProgressCallback>,
+    allocation: Option<OutputAllocation>,
+}
+
+fn progress_callback(progress: &Option<ProgressCallback>) -> Option<Box<dyn FnMut(i64)>> {
+    let progress = progress.clone()?;
+    Some(Box::new(move |written| (progress.borrow_mut())(written)))
+}
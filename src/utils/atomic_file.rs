@@ -0,0 +1,118 @@
+use std::fs::{self, File, Permissions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A single-file output that is written to a sibling temp file and renamed over the destination once complete,
+/// so a crash never leaves a truncated file at the final path.
+pub(crate) struct AtomicFile {
+    dest: PathBuf,
+    temp: PathBuf,
+    /// Permissions of the file being replaced, read before patching.
+    permissions: Option<Permissions>,
+}
+
+impl AtomicFile {
+    /// A destination that is a symlink is resolved first, so the file it points to gets replaced, as a plain
+    /// `File::create` would have written through the link.
+    pub fn new(dest: &Path) -> io::Result<Self> {
+        let dest = match fs::symlink_metadata(dest) {
+            Ok(meta) if meta.file_type().is_symlink() => fs::canonicalize(dest)?,
+            _ => dest.to_path_buf(),
+        };
+        let permissions = match fs::metadata(&dest) {
+            Ok(meta) if meta.is_file() => Some(meta.permissions()),
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("[AtomicFile::new] {} is not a file", dest.display()))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let temp = temp_path(&dest);
+        Ok(Self { dest, temp, permissions })
+    }
+
+    /// The temp file's name only depends on the destination, which lets `resume` find it again.
+    pub fn temp_path(&self) -> &Path {
+        &self.temp
+    }
+
+    pub fn create(&self) -> io::Result<File> {
+        File::options().read(true).write(true).create(true).truncate(true).open(&self.temp)
+    }
+
+    /// Opens the temp file left behind by an interrupted run.
+    pub fn reopen(&self) -> io::Result<File> {
+        File::options().read(true).write(true).open(&self.temp).map_err(|e| io::Error::new(e.kind(), format!("[AtomicFile::reopen] Cannot open {}: {}", self.temp.display(), e)))
+    }
+
+    /// Syncs the finished temp file and renames it over the destination. The caller must have closed every
+    /// other handle to the destination by now; Windows refuses to replace a file that is still open.
+    pub fn commit(self, file: File) -> io::Result<()> {
+        if let Some(permissions) = &self.permissions { file.set_permissions(permissions.clone())?; }
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.temp, &self.dest).map_err(|e| io::Error::new(e.kind(), format!("[AtomicFile::commit] Cannot rename {} to {}: {}", self.temp.display(), self.dest.display(), e)))?;
+        sync_parent_dir(&self.dest)
+    }
+
+    /// Drops the temp file of a failed run. Errors are ignored; there is already one being reported.
+    pub fn discard(self) {
+        let _ = fs::remove_file(&self.temp);
+    }
+}
+
+fn temp_path(dest: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(dest.file_name().unwrap_or_default());
+    name.push(".hdiffpatch-tmp");
+    dest.with_file_name(name)
+}
+
+// Makes the rename itself durable.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether both paths lead to the same file, through symlinks and hard links alike. A missing path is never the same.
+pub(crate) fn is_same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    match (file_identity(a)?, file_identity(b)?) {
+        (Some(a), Some(b)) => Ok(a == b),
+        _ => Ok(false),
+    }
+}
+
+#[cfg(unix)]
+type FileKey = (u64, u64);
+#[cfg(not(unix))]
+type FileKey = PathBuf;
+
+/// What a path leads to: device and inode on Unix, the canonical path elsewhere. Equal for every path to one file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct FileIdentity(FileKey);
+
+/// `None` when nothing exists at `path`.
+pub(crate) fn file_identity(path: &Path) -> io::Result<Option<FileIdentity>> {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Ok(Some(FileIdentity((meta.dev(), meta.ino()))))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        Ok(Some(FileIdentity(fs::canonicalize(path)?)))
+    }
+}
@@ -0,0 +1,210 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use crate::utils::checksum::Crc32;
+use crate::utils::structs::{PatchOptions, RleRefClip};
+
+const CHECKPOINT_MAGIC: &[u8; 8] = b"HDPCKPT1";
+const DEFAULT_CHECKPOINT_INTERVAL: u64 = 64 << 20;
+
+/// Everything needed to pick a patch loop back up at a cover boundary.
+/// `clip_offsets` counts decompressed bytes already consumed from the rle ctrl, rle code and new data streams;
+/// single-stream formats (HDIFFSF20, KrDiff) only use the last slot. Compressed streams restart from their
+/// beginning and skip that many bytes.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Checkpoint {
+    pub patch_len: u64,
+    pub new_size: u64,
+    pub cover_index: u64,
+    pub new_pos: u64,
+    pub written_crc: u32,
+    pub clip_offsets: [u64; 3],
+    pub rle: RleRefClip,
+    pub last_old_end: i64,
+    pub last_new_end: u64,
+}
+
+impl Checkpoint {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = CHECKPOINT_MAGIC.to_vec();
+        for v in [self.patch_len, self.new_size, self.cover_index, self.new_pos, self.written_crc as u64, self.clip_offsets[0], self.clip_offsets[1], self.clip_offsets[2]] { out.extend_from_slice(&v.to_le_bytes()); }
+        for v in [self.rle.mem_copy_length, self.rle.mem_set_length, self.rle.mem_set_value as i64, self.last_old_end, self.last_new_end as i64] { out.extend_from_slice(&v.to_le_bytes()); }
+        out
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let body = buf.strip_prefix(CHECKPOINT_MAGIC)?;
+        if body.len() != 13 * 8 { return None; }
+        let v = |i: usize| u64::from_le_bytes(body[i * 8..i * 8 + 8].try_into().unwrap());
+        Some(Self {
+            patch_len: v(0),
+            new_size: v(1),
+            cover_index: v(2),
+            new_pos: v(3),
+            written_crc: v(4) as u32,
+            clip_offsets: [v(5), v(6), v(7)],
+            rle: RleRefClip { mem_copy_length: v(8) as i64, mem_set_length: v(9) as i64, mem_set_value: v(10) as u8 },
+            last_old_end: v(11) as i64,
+            last_new_end: v(12),
+        })
+    }
+}
+
+/// Persists checkpoints for one patch run and keeps a running CRC of everything written so far,
+/// which lets `resume` prove the output prefix on disk is the one the checkpoint describes.
+pub(crate) struct Checkpointer {
+    path: PathBuf,
+    interval: u64,
+    patch_len: u64,
+    new_size: u64,
+    next_due: u64,
+    written: u64,
+    crc: Crc32,
+}
+
+impl Checkpointer {
+    pub fn new(path: &Path, interval: u64, patch_path: &str, new_size: u64) -> io::Result<Self> {
+        let interval = if interval == 0 { DEFAULT_CHECKPOINT_INTERVAL } else { interval };
+        let patch_len = fs::metadata(patch_path)?.len();
+        Ok(Self { path: path.to_path_buf(), interval, patch_len, new_size, next_due: interval, written: 0, crc: Crc32::new() })
+    }
+
+    /// Reads the last checkpoint and makes sure it was taken for this very patch.
+    pub fn load(&self) -> io::Result<Checkpoint> {
+        let raw = fs::read(&self.path).map_err(|e| io::Error::new(e.kind(), format!("[Checkpointer::load] Cannot read checkpoint {}: {}", self.path.display(), e)))?;
+        let cp = Checkpoint::decode(&raw).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("[Checkpointer::load] {} is not a valid checkpoint", self.path.display())))?;
+        if cp.patch_len != self.patch_len || cp.new_size != self.new_size { return Err(io::Error::new(io::ErrorKind::InvalidData, format!("[Checkpointer::load] Checkpoint {} was taken for a different patch", self.path.display()))); }
+        Ok(cp)
+    }
+
+    pub fn continue_from(&mut self, cp: &Checkpoint) {
+        self.written = cp.new_pos;
+        self.crc = Crc32::resume(cp.written_crc);
+        self.next_due = cp.new_pos + self.interval;
+    }
+
+    pub fn track(&mut self, data: &[u8]) {
+        self.crc.update(data);
+        self.written += data.len() as u64;
+    }
+
+    /// True once `pending` more bytes would reach the next checkpoint.
+    pub fn is_due(&self, pending: u64) -> bool {
+        self.written + pending >= self.next_due
+    }
+
+    /// The output must be synced with `SyncWrite::sync_data` before this is called; the position and CRC are filled in here.
+    pub fn save(&mut self, mut cp: Checkpoint) -> io::Result<()> {
+        cp.patch_len = self.patch_len;
+        cp.new_size = self.new_size;
+        cp.new_pos = self.written;
+        cp.written_crc = self.crc.value();
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&cp.encode())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.next_due = self.written + self.interval;
+
+        #[cfg(debug_assertions)]
+        println!("[Checkpointer::save] cover {} | new pos {}", cp.cover_index, cp.new_pos);
+        Ok(())
+    }
+}
+
+/// Builds the checkpointer requested by `options`, loading the last checkpoint when resuming.
+pub(crate) fn start_checkpointing(options: &PatchOptions, patch_path: &str, new_size: u64, resume: bool) -> io::Result<Option<(Checkpointer, Option<Checkpoint>)>> {
+    let Some(path) = &options.checkpoint_path else {
+        if resume { return Err(io::Error::new(io::ErrorKind::InvalidInput, "[start_checkpointing] Resuming needs PatchOptions::checkpoint_path")); }
+        return Ok(None);
+    };
+    if options.transactional { return Err(io::Error::new(io::ErrorKind::InvalidInput, "[start_checkpointing] Checkpoints cannot be combined with transactional patching")); }
+
+    let mut checkpointer = Checkpointer::new(path, options.checkpoint_interval, patch_path, new_size)?;
+    if !resume { return Ok(Some((checkpointer, None))); }
+    let cp = checkpointer.load()?;
+    checkpointer.continue_from(&cp);
+    Ok(Some((checkpointer, Some(cp))))
+}
+
+pub(crate) fn clear_checkpoint(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Re-hashes the first `cp.new_pos` output bytes and compares them with the checkpoint. Leaves `reader` right after them.
+pub(crate) fn verify_written_prefix(reader: &mut dyn Read, cp: &Checkpoint) -> io::Result<()> {
+    let mut crc = Crc32::new();
+    let mut buf = vec![0u8; 1 << 20];
+    let mut left = cp.new_pos;
+    while left > 0 {
+        let take = (buf.len() as u64).min(left) as usize;
+        reader.read_exact(&mut buf[..take])?;
+        crc.update(&buf[..take]);
+        left -= take as u64;
+    }
+    if crc.value() != cp.written_crc { return Err(io::Error::new(io::ErrorKind::InvalidData, "[verify_written_prefix] Output written before the interruption does not match the checkpoint, cannot resume")); }
+    Ok(())
+}
+
+/// Counts how many bytes were pulled out of a clip stream so checkpoints can record it.
+pub(crate) struct CountingReader<'a> {
+    inner: &'a mut dyn Read,
+    pub count: u64,
+}
+
+impl<'a> CountingReader<'a> {
+    pub fn new(inner: &'a mut dyn Read) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn skip(&mut self, n: u64) -> io::Result<()> {
+        let copied = io::copy(&mut self.by_ref().take(n), &mut io::sink())?;
+        if copied != n { return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "[CountingReader::skip] Stream ended before the checkpointed offset")); }
+        Ok(())
+    }
+}
+
+impl Read for CountingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Output a checkpoint can point into. `sync_data` flushes whatever is buffered and forces it to disk, so a saved
+/// checkpoint never claims bytes that a power loss would take back.
+pub(crate) trait SyncWrite: Write {
+    fn sync_data(&mut self) -> io::Result<()>;
+}
+
+impl SyncWrite for BufWriter<File> {
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.get_ref().sync_data()
+    }
+}
+
+/// Output wrapper that feeds every written byte to the checkpointer and the progress callback.
+pub(crate) struct TrackedWriter<'a> {
+    pub inner: &'a mut dyn SyncWrite,
+    pub checkpointer: Option<&'a mut Checkpointer>,
+    pub callback: &'a mut Option<Box<dyn FnMut(i64)>>,
+}
+
+impl Write for TrackedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(cp) = self.checkpointer.as_mut() { cp.track(&buf[..n]); }
+        if let Some(cb) = self.callback.as_mut() { cb(n as i64); }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,110 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+
+const CRC32_TABLE: [u32; 256] = build_crc32_table();
+
+const fn build_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// Streaming CRC-32 (IEEE, same as zlib's `crc32`), which is what HDiffPatch's `crc32` checksum plugin stores.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(0xFFFF_FFFF)
+    }
+
+    /// Continues a checksum from a previously finished value.
+    pub fn resume(value: u32) -> Self {
+        Self(!value)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut c = self.0;
+        for &b in data { c = CRC32_TABLE[((c ^ b as u32) & 0xFF) as usize] ^ (c >> 8); }
+        self.0 = c;
+    }
+
+    pub fn value(&self) -> u32 {
+        !self.0
+    }
+}
+
+/// The part of a patch a stored checksum covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumSection {
+    /// The patch itself, from its head to the end of the diff data.
+    Head,
+    /// All old files the patch reads, one after another.
+    OldReference,
+    /// All new files the patch writes, one after another.
+    NewReference,
+}
+
+impl fmt::Display for ChecksumSection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Head => "patch head and diff data",
+            Self::OldReference => "old files",
+            Self::NewReference => "new files",
+        })
+    }
+}
+
+/// A section of the patch does not match the checksum stored for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChecksumMismatchError {
+    pub section: ChecksumSection,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl fmt::Display for ChecksumMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Checksum mismatch for the {}: expected crc32 {:08x}, got {:08x}", self.section, self.expected, self.actual)
+    }
+}
+
+impl Error for ChecksumMismatchError {}
+
+/// CRC-32 of everything `readers` yield, one after another.
+pub(crate) fn crc32_of(readers: impl IntoIterator<Item = io::Result<impl Read>>) -> io::Result<u32> {
+    let mut crc = Crc32::new();
+    let mut buf = vec![0u8; 1 << 16];
+    for reader in readers {
+        let mut reader = reader?;
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 { break; }
+            crc.update(&buf[..n]);
+        }
+    }
+    Ok(crc.value())
+}
+
+/// Compares a section's CRC-32 with the one stored in the patch.
+pub(crate) fn verify_crc32(section: ChecksumSection, expected: u32, files: impl IntoIterator<Item = io::Result<File>>) -> io::Result<()> {
+    let actual = crc32_of(files)?;
+
+    #[cfg(debug_assertions)]
+    println!("[verify_crc32] {}: expected {:08x}, got {:08x}", section, expected, actual);
+
+    if actual == expected { return Ok(()); }
+    Err(io::Error::new(io::ErrorKind::InvalidData, ChecksumMismatchError { section, expected, actual }))
+}
@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The destination filesystem does not have room for the patch output; nothing has been written yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InsufficientSpaceError {
+    pub path: PathBuf,
+    pub needed: u64,
+    pub available: u64,
+}
+
+impl fmt::Display for InsufficientSpaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Not enough disk space for {}: need {} bytes, have {} bytes", self.path.display(), self.needed, self.available)
+    }
+}
+
+impl Error for InsufficientSpaceError {}
+
+/// How output files were given their final size before the patch wrote into them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputAllocation {
+    /// `set_len` only: the file starts out sparse and blocks are allocated as data is written.
+    Sparse,
+    /// Blocks reserved up front with `fallocate`.
+    Preallocated,
+}
+
+impl OutputAllocation {
+    /// A patch reports `Preallocated` only if no file had to fall back.
+    pub(crate) fn combine(previous: Option<Self>, next: Self) -> Option<Self> {
+        match previous {
+            Some(Self::Sparse) => Some(Self::Sparse),
+            _ => Some(next),
+        }
+    }
+}
+
+/// Sizes an output file to `len`, reserving its blocks when `preallocate` is set and the filesystem allows it.
+/// Running out of space is an error; a filesystem without `fallocate` falls back to a sparse file.
+pub(crate) fn size_output_file(file: &File, len: u64, preallocate: bool) -> io::Result<OutputAllocation> {
+    file.set_len(len)?;
+    if !preallocate { return Ok(OutputAllocation::Sparse); }
+    match reserve_blocks(file, len) {
+        Ok(()) => Ok(OutputAllocation::Preallocated),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported || is_not_supported_code(&e) => Ok(OutputAllocation::Sparse),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn reserve_blocks(file: &File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    if len == 0 { return Ok(()); }
+    // SAFETY: the descriptor stays open for the duration of the call; mode 0 only allocates, it never changes data.
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, len as libc::off_t) };
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reserve_blocks(_file: &File, _len: u64) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+fn is_not_supported_code(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() { return code == libc::EOPNOTSUPP || code == libc::ENOSYS; }
+    let _ = e;
+    false
+}
+
+/// Fails with an `InsufficientSpaceError` when fewer than `needed` bytes are free where `dest` lives.
+/// Platforms without a free-space query skip the check.
+pub(crate) fn ensure_free_space(dest: &Path, needed: u64) -> io::Result<()> {
+    if needed == 0 { return Ok(()); }
+    let Some(available) = free_space(dest)? else { return Ok(()); };
+
+    #[cfg(debug_assertions)]
+    println!("[ensure_free_space] {}: need {} bytes, have {} bytes", dest.display(), needed, available);
+
+    if available >= needed { return Ok(()); }
+    Err(io::Error::new(io::ErrorKind::StorageFull, InsufficientSpaceError { path: dest.to_path_buf(), needed, available }))
+}
+
+/// Size of the regular file at `path`, 0 when there is none. For outputs, that is what truncating it gives back.
+pub(crate) fn existing_file_size(path: &Path) -> u64 {
+    std::fs::symlink_metadata(path).map(|meta| if meta.is_file() { meta.len() } else { 0 }).unwrap_or(0)
+}
+
+// The destination may not exist yet, so ask about the closest ancestor that does.
+fn existing_ancestor(path: &Path) -> &Path {
+    path.ancestors().find(|p| !p.as_os_str().is_empty() && p.exists()).unwrap_or(Path::new("."))
+}
+
+#[cfg(unix)]
+fn free_space(dest: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    let c_path = CString::new(existing_ancestor(dest).as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stat` is a properly sized out parameter.
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 { return Err(io::Error::last_os_error()); }
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_dest: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::utils::suffix_array::SuffixArray;
+
+/// Writes `v` as a big-endian 7-bit varint, the inverse of `read_long_7bit`. The top `tag_bit` bits of the first
+/// byte hold `tag` instead of value bits, as `read_long_7bit_tagged` expects.
+pub(crate) fn pack_uint_tagged(out: &mut Vec<u8>, mut v: u64, tag_bit: u8, tag: u8) {
+    let first_bits = 7 - tag_bit;
+    let mut groups = Vec::new();
+    while v >> first_bits != 0 { groups.push((v & 0x7F) as u8); v >>= 7; }
+    let mut first = v as u8;
+    if tag_bit > 0 { first |= tag << (8 - tag_bit); }
+    if !groups.is_empty() { first |= 1 << first_bits; }
+    out.push(first);
+    for (i, g) in groups.iter().rev().enumerate() { out.push(if i + 1 < groups.len() { g | 0x80 } else { *g }); }
+}
+
+pub(crate) fn pack_uint(out: &mut Vec<u8>, v: u64) {
+    pack_uint_tagged(out, v, 0, 0);
+}
+
+/// Increasing indices, each stored as its distance to the previous one minus one.
+pub(crate) fn pack_deltas(out: &mut Vec<u8>, indices: &[usize]) {
+    let mut back = -1i64;
+    for &i in indices { pack_uint(out, (i as i64 - back - 1) as u64); back = i as i64; }
+}
+
+/// `(old_pos, new_pos, length)` of the covers, in new order. Every exact match of at least `MIN_MATCH` bytes starts
+/// a cover. With `stretch`, the cover then grows in both directions for as long as matching bytes outnumber
+/// differing ones, leaving the differences to the RLE streams; without, covers copy old bytes verbatim. `old` has to
+/// be smaller than 4 GiB.
+pub(crate) fn search_covers(old: &[u8], new: &[u8], stretch: bool) -> Vec<(usize, usize, usize)> {
+    const MIN_MATCH: usize = 8;
+    if old.len() < MIN_MATCH { return Vec::new(); }
+    let sa = SuffixArray::new(old);
+    let mut covers = Vec::new();
+    let (mut i, mut last_new_end) = (0, 0);
+    while i + MIN_MATCH <= new.len() {
+        let (pos, len) = sa.longest_match(&new[i..]);
+        if len < MIN_MATCH { i += 1; continue; }
+        let (back, ahead) = if !stretch { (0, 0) } else {
+            (extend(old[..pos].iter().rev(), new[last_new_end..i].iter().rev()), extend(old[pos + len..].iter(), new[i + len..].iter()))
+        };
+        covers.push((pos - back, i - back, back + len + ahead));
+        i += len + ahead;
+        last_new_end = i;
+    }
+    covers
+}
+
+/// How far a cover can grow along `old` and `new` with more matching than differing bytes in what it gains.
+fn extend<'a>(old: impl Iterator<Item = &'a u8>, new: impl Iterator<Item = &'a u8>) -> usize {
+    // Once this far behind the best point, a better one is unlikely to follow.
+    const GIVE_UP: i64 = 16;
+    let (mut score, mut best_score, mut best_len) = (0i64, 0i64, 0usize);
+    for (k, (a, b)) in old.zip(new).enumerate() {
+        score += if a == b { 1 } else { -1 };
+        if score > best_score { best_score = score; best_len = k + 1; } else if score < best_score - GIVE_UP { break; }
+    }
+    best_len
+}
+
+/// The cover buffer of an `HDIFF13` diff, along with the bytes of `new` no cover reaches, in order. Each cover is
+/// stored relative to where the previous one ended in old and new.
+pub(crate) fn pack_covers(covers: &[(usize, usize, usize)], new: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let (mut cover_buf, mut literals) = (Vec::new(), Vec::new());
+    let (mut last_old_end, mut last_new_end) = (0i64, 0usize);
+    for &(o, n, l) in covers {
+        let inc = o as i64 - last_old_end;
+        pack_uint_tagged(&mut cover_buf, inc.unsigned_abs(), 1, (inc < 0) as u8);
+        pack_uint(&mut cover_buf, (n - last_new_end) as u64);
+        pack_uint(&mut cover_buf, l as u64);
+        literals.extend_from_slice(&new[last_new_end..n]);
+        last_old_end = (o + l) as i64;
+        last_new_end = n + l;
+    }
+    literals.extend_from_slice(&new[last_new_end.min(new.len())..]);
+    (cover_buf, literals)
+}
+
+/// Byte RLE of `data` in the ctrl and code streams `PatchCore` decodes. Runs of one value become set runs, with 0
+/// and 0xFF stored in the ctrl byte alone; everything else is copied from the code stream.
+pub(crate) fn pack_rle(data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    // PatchCore decodes a whole run in one half of its 4 MiB buffer, so runs are split well below that.
+    const MAX_RUN: usize = 1 << 20;
+    let (mut ctrl, mut code) = (Vec::new(), Vec::new());
+    let flush_copy = |ctrl: &mut Vec<u8>, code: &mut Vec<u8>, copy: &[u8]| for chunk in copy.chunks(MAX_RUN) {
+        pack_uint_tagged(ctrl, chunk.len() as u64 - 1, 2, 3);
+        code.extend_from_slice(chunk);
+    };
+
+    let (mut i, mut copy_start) = (0, 0);
+    while i < data.len() {
+        let v = data[i];
+        let run = data[i..].iter().take_while(|&&b| b == v).count();
+        // Shorter runs cost more as their own ctrl entry than as part of a copy.
+        let min_run = if v == 0 || v == 0xFF { 3 } else { 4 };
+        if run < min_run { i += run; continue; }
+
+        flush_copy(&mut ctrl, &mut code, &data[copy_start..i]);
+        let rle_type = match v { 0 => 0, 0xFF => 1, _ => 2 };
+        for start in (0..run).step_by(MAX_RUN) {
+            pack_uint_tagged(&mut ctrl, (run - start).min(MAX_RUN) as u64 - 1, 2, rle_type);
+            if rle_type == 2 { code.push(v); }
+        }
+        i += run;
+        copy_start = i;
+    }
+    flush_copy(&mut ctrl, &mut code, &data[copy_start..]);
+    (ctrl, code)
+}
+
+/// A file or directory found by `list_tree`.
+pub(crate) struct TreeEntry {
+    /// `/`-separated, directories with a trailing `/`.
+    pub path: String,
+    /// The file's contents; `None` for directories.
+    pub data: Option<Vec<u8>>,
+    /// Any execute bit is set. Always false outside Unix.
+    pub executable: bool,
+}
+
+/// Everything below `root`, read into memory and sorted by path, so a directory comes right before its contents.
+/// The root itself is not listed. Symlinks and paths that are not valid UTF-8 are refused.
+pub(crate) fn list_tree(root: &Path) -> io::Result<Vec<TreeEntry>> {
+    let mut entries = Vec::new();
+    collect_tree(root, root, &mut entries)?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+fn collect_tree(root: &Path, dir: &Path, entries: &mut Vec<TreeEntry>) -> io::Result<()> {
+    let listing = fs::read_dir(dir).map_err(|e| io::Error::new(e.kind(), format!("[list_tree] Cannot list {}: {}", dir.display(), e)))?;
+    for entry in listing {
+        let full = entry?.path();
+        let rel = full.strip_prefix(root).unwrap_or(&full);
+        let Some(rel) = rel.to_str() else { return Err(io::Error::new(io::ErrorKind::InvalidData, format!("[list_tree] {} is not valid UTF-8", full.display()))); };
+        let rel = rel.replace('\\', "/");
+        let meta = fs::symlink_metadata(&full)?;
+        if meta.file_type().is_symlink() { return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("[list_tree] {} is a symlink", full.display()))); }
+        if meta.is_dir() {
+            entries.push(TreeEntry { path: format!("{}/", rel), data: None, executable: false });
+            collect_tree(root, &full, entries)?;
+        } else {
+            entries.push(TreeEntry { path: rel, data: Some(fs::read(&full)?), executable: is_executable(&meta) });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_meta: &fs::Metadata) -> bool {
+    false
+}
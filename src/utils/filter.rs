@@ -0,0 +1,160 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use crate::utils::checkpoint::SyncWrite;
+
+/// Picks which new files of a directory patch get written. Paths are the normalised patch paths,
+/// `/`-separated and relative to the output root, directories without their trailing `/`.
+#[derive(Clone)]
+pub struct FileFilter(Arc<dyn Fn(&str) -> bool + Send + Sync>);
+
+impl FileFilter {
+    pub fn new(predicate: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(predicate))
+    }
+
+    /// `*` and `?` stay within one path component, `**` spans any number of them (`voice/**`, `**/*.pak`).
+    pub fn glob(pattern: &str) -> Self {
+        let pattern = pattern.to_string();
+        Self::new(move |path| glob_match(pattern.as_bytes(), path.as_bytes()))
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        (self.0)(path.trim_end_matches('/'))
+    }
+}
+
+impl fmt::Debug for FileFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("FileFilter(..)")
+    }
+}
+
+fn glob_match(p: &[u8], s: &[u8]) -> bool {
+    match p {
+        [] => s.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => glob_match(rest, s) || s.iter().enumerate().any(|(i, &c)| c == b'/' && glob_match(rest, &s[i + 1..])),
+        [b'*', b'*', rest @ ..] => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        [b'*', rest @ ..] => (0..=s.len()).take_while(|&i| i == 0 || s[i - 1] != b'/').any(|i| glob_match(rest, &s[i..])),
+        [b'?', rest @ ..] => s.first().is_some_and(|&c| c != b'/') && glob_match(rest, &s[1..]),
+        [c, rest @ ..] => s.first() == Some(c) && glob_match(rest, &s[1..]),
+    }
+}
+
+/// Marks which old segments (`(start, len)` in old stream order) are read by covers landing in a kept new segment.
+/// Covers are `(old_pos, new_pos, length)`; kept new segments are `(start, len)` ranges of the new stream. Both segment
+/// lists are in stream order, so each cover only looks at the segments it overlaps.
+pub(crate) fn needed_old_segments(covers: impl IntoIterator<Item = (u64, u64, u64)>, kept_new: &[(u64, u64)], old_segments: &[(u64, u64)]) -> Vec<bool> {
+    let mut needed = vec![false; old_segments.len()];
+    for (old_pos, new_pos, length) in covers {
+        for &(start, len) in overlapping(kept_new, new_pos, new_pos + length) {
+            let a = start.max(new_pos);
+            let b = (start + len).min(new_pos + length);
+            if a >= b { continue; }
+            let (old_a, old_b) = (old_pos + (a - new_pos), old_pos + (b - new_pos));
+            let first = old_segments.partition_point(|&(o_start, o_len)| o_start + o_len <= old_a);
+            for (i, &(o_start, o_len)) in old_segments.iter().enumerate().skip(first).take_while(|(_, (o_start, _))| *o_start < old_b) {
+                if old_a < o_start + o_len { needed[i] = true; }
+            }
+        }
+    }
+    needed
+}
+
+/// The `(start, len)` segments, sorted and not overlapping, that may intersect `from..to`.
+fn overlapping(segments: &[(u64, u64)], from: u64, to: u64) -> &[(u64, u64)] {
+    let first = segments.partition_point(|&(start, len)| start + len <= from);
+    let last = first + segments[first..].partition_point(|&(start, _)| start < to);
+    &segments[first..last]
+}
+
+/// Old data made of files laid end to end where some may be missing; missing ones read as zeros.
+/// Only used for selective patches, where nothing read from a missing file ends up in a kept output.
+pub(crate) struct SparseReader {
+    segments: Vec<(u64, u64, Option<File>)>,
+    position: u64,
+    total_length: u64,
+}
+
+impl SparseReader {
+    pub fn new(files: Vec<(u64, Option<File>)>) -> Self {
+        let mut segments = Vec::with_capacity(files.len());
+        let mut start = 0u64;
+        for (len, file) in files {
+            segments.push((start, len, file));
+            start += len;
+        }
+        Self { segments, position: 0, total_length: start }
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pos = self.position;
+        let Some((start, len, file)) = self.segments.iter_mut().find(|(start, len, _)| pos >= *start && pos < *start + *len) else { return Ok(0); };
+        let take = ((*start + *len - pos) as usize).min(buf.len());
+        let n = match file {
+            Some(file) => {
+                file.seek(SeekFrom::Start(pos - *start))?;
+                file.read(&mut buf[..take])?
+            }
+            None => {
+                buf[..take].fill(0);
+                take
+            }
+        };
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SparseReader {
+    fn seek(&mut self, origin: SeekFrom) -> io::Result<u64> {
+        let new_pos = match origin {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+            SeekFrom::End(offset) => self.total_length as i64 + offset,
+        };
+        if new_pos < 0 { return Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before start")); }
+        self.position = new_pos as u64;
+        Ok(self.position)
+    }
+}
+
+/// Passes through only the bytes that fall into kept `(start, len)` ranges of the full output, which are in order.
+/// Everything else is dropped, so the patch streams still advance exactly as for a full patch.
+pub(crate) struct SkippingWriter<'a> {
+    inner: &'a mut dyn SyncWrite,
+    kept: Vec<(u64, u64)>,
+    position: u64,
+}
+
+impl<'a> SkippingWriter<'a> {
+    pub fn new(inner: &'a mut dyn SyncWrite, kept: Vec<(u64, u64)>) -> Self {
+        Self { inner, kept, position: 0 }
+    }
+}
+
+impl Write for SkippingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let (from, to) = (self.position, self.position + buf.len() as u64);
+        for &(start, len) in overlapping(&self.kept, from, to) {
+            let a = start.max(from);
+            let b = (start + len).min(to);
+            if a < b { self.inner.write_all(&buf[(a - from) as usize..(b - from) as usize])?; }
+        }
+        self.position = to;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SyncWrite for SkippingWriter<'_> {
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.inner.sync_data()
+    }
+}
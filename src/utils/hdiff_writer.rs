@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::utils::checksum::crc32_of;
+use crate::utils::encoder::{list_tree, pack_covers, pack_deltas, pack_rle, pack_uint, pack_uint_tagged, search_covers, TreeEntry};
+use crate::utils::structs::CompressionMode;
+
+/// Creates HDiffPatch patches, the counterpart of `HDiff`. Covers are found through a suffix array of the old data
+/// and stretched over nearby bytes that mostly match; whatever differs inside a cover goes into the RLE streams.
+/// Old and new data are held in memory while diffing.
+#[derive(Debug, Clone, Default)]
+pub struct HDiffWriter {
+    compression: CompressionMode,
+    compression_level: i32,
+    crc32: bool,
+}
+
+impl HDiffWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only `Nocomp`, the default, and `Zstd` are supported, as those are the ones `HDiff` can apply.
+    pub fn with_compression(mut self, compression: CompressionMode) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// The zstd level; 0 picks zstd's default.
+    pub fn with_compression_level(mut self, level: i32) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Stores `crc32` checksums in directory patches, in the slot order this crate's `KrDiff` reader expects. Upstream
+    /// `hpatchz` has not verified that layout yet. Single-file patches have no room for checksums.
+    pub fn with_crc32(mut self, crc32: bool) -> Self {
+        self.crc32 = crc32;
+        self
+    }
+
+    /// An `HDIFF13` patch turning `old` into `new`. `old` has to be smaller than 4 GiB.
+    pub fn encode(&self, old: &[u8], new: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(encode_hdiff13(old, new, self.compression, self.compression_level)?)
+    }
+
+    /// Diffs the file at `old_path` against the one at `new_path` and writes the patch to `patch_path`.
+    pub fn write(&self, old_path: impl AsRef<Path>, new_path: impl AsRef<Path>, patch_path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let read = |path: &Path| fs::read(path).map_err(|e| format!("[HDiffWriter::write] Cannot read {}: {}", path.display(), e));
+        let patch = self.encode(&read(old_path.as_ref())?, &read(new_path.as_ref())?)?;
+        fs::write(patch_path.as_ref(), patch).map_err(|e| format!("[HDiffWriter::write] Cannot write {}: {}", patch_path.as_ref().display(), e))?;
+        Ok(())
+    }
+
+    /// An `HDIFF19` patch turning the directory `old_dir` into `new_dir`. New files with the same contents as an old
+    /// one are stored as same-file pairs, files with an execute bit are listed as executable, and every other
+    /// non-empty file is diffed against all old files, back to back. Symlinks and paths that are not valid UTF-8 are
+    /// refused. Only `HDiff` is known to apply the result; it has not been run through upstream `hpatchz` yet.
+    pub fn encode_dir(&self, old_dir: impl AsRef<Path>, new_dir: impl AsRef<Path>) -> Result<Vec<u8>, Box<dyn Error>> {
+        for dir in [old_dir.as_ref(), new_dir.as_ref()] {
+            if !dir.is_dir() { return Err(format!("[HDiffWriter::encode_dir] {} is not a directory", dir.display()).into()); }
+        }
+        let name = compression_name(self.compression)?;
+        let old = list_tree(old_dir.as_ref())?;
+        let new = list_tree(new_dir.as_ref())?;
+
+        // Indices are into the path lists, which start with the root "".
+        let old_refs: Vec<usize> = (0..old.len()).filter(|&i| contents(&old[i]).is_some()).map(|i| i + 1).collect();
+        let mut by_contents: HashMap<&[u8], usize> = HashMap::new();
+        for &i in &old_refs { by_contents.entry(contents(&old[i - 1]).unwrap_or_default()).or_insert(i); }
+        let old_by_path: HashMap<&str, usize> = old.iter().enumerate().map(|(i, e)| (e.path.as_str(), i + 1)).collect();
+        let (mut new_refs, mut same_pairs) = (Vec::new(), Vec::new());
+        for (i, e) in new.iter().enumerate() {
+            let Some(data) = contents(e) else { continue; };
+            // The old file at the same path is preferred, so unchanged files do not turn into moves.
+            let same = old_by_path.get(e.path.as_str()).copied().filter(|&j| old[j - 1].data.as_deref() == Some(data)).or_else(|| by_contents.get(data).copied());
+            match same {
+                Some(j) => same_pairs.push((i + 1, j)),
+                None => new_refs.push(i + 1),
+            }
+        }
+        let executables: Vec<usize> = (0..new.len()).filter(|&i| new[i].executable).map(|i| i + 1).collect();
+
+        let concat = |entries: &[TreeEntry], indices: &[usize]| indices.iter().flat_map(|&i| contents(&entries[i - 1]).unwrap_or_default().iter().copied()).collect::<Vec<u8>>();
+        let old_data = concat(&old, &old_refs);
+        let new_data = concat(&new, &new_refs);
+        let same_data = concat(&new, &same_pairs.iter().map(|&(n, _)| n).collect::<Vec<_>>());
+
+        let mut head = Vec::new();
+        for path in [""].into_iter().chain(old.iter().map(|e| e.path.as_str())).chain([""]).chain(new.iter().map(|e| e.path.as_str())) {
+            head.extend_from_slice(path.as_bytes());
+            head.push(0);
+        }
+        pack_deltas(&mut head, &old_refs);
+        pack_deltas(&mut head, &new_refs);
+        for &i in &new_refs { pack_uint(&mut head, contents(&new[i - 1]).map_or(0, |d| d.len() as u64)); }
+        let (mut back_new, mut back_old) = (-1i64, -1i64);
+        for &(n, o) in &same_pairs {
+            pack_uint(&mut head, (n as i64 - back_new - 1) as u64);
+            let inc = o as i64 - (back_old + 1);
+            pack_uint_tagged(&mut head, inc.unsigned_abs(), 1, (inc < 0) as u8);
+            (back_new, back_old) = (n as i64, o as i64);
+        }
+        pack_deltas(&mut head, &executables);
+        let packed_head = compress(&head, self.compression, self.compression_level)?;
+
+        let sum_size = |entries: &[TreeEntry]| 1 + entries.iter().map(|e| e.path.len() + 1).sum::<usize>();
+        let mut out = format!("HDIFF19&{}&{}\0", name, if self.crc32 { "crc32" } else { "" }).into_bytes();
+        // Both sides are directories.
+        out.extend_from_slice(&[1, 1]);
+        for v in [
+            old.len() + 1, sum_size(&old), new.len() + 1, sum_size(&new),
+            old_refs.len(), old_data.len(), new_refs.len(), new_data.len(),
+            same_pairs.len(), same_data.len(), executables.len(), 0, 0, 0,
+            head.len(), packed_head.as_ref().map_or(0, |p| p.len()), if self.crc32 { 4 } else { 0 },
+        ] { pack_uint(&mut out, v as u64); }
+        let checksum_at = out.len();
+        out.extend_from_slice(packed_head.as_deref().unwrap_or(&head));
+        out.extend_from_slice(&encode_hdiff13(&old_data, &new_data, self.compression, self.compression_level)?);
+
+        if self.crc32 {
+            // New data, old data, same files, then everything after the checksums, as `KrDiff` reads them.
+            let rest = crc32_of([Ok(&out[checksum_at..])])?;
+            let checksums: Vec<u8> = [crc32_of([Ok(&new_data[..])])?, crc32_of([Ok(&old_data[..])])?, crc32_of([Ok(&same_data[..])])?, rest].iter().flat_map(|c| c.to_le_bytes()).collect();
+            out.splice(checksum_at..checksum_at, checksums);
+        }
+
+        #[cfg(debug_assertions)]
+        println!("[HDiffWriter] {} old files | {} new files | {} same pairs | {} executables | patch {} bytes", old_refs.len(), new_refs.len(), same_pairs.len(), executables.len(), out.len());
+        Ok(out)
+    }
+
+    /// Diffs the directory `old_dir` against `new_dir` and writes the patch to `patch_path`.
+    pub fn write_dir(&self, old_dir: impl AsRef<Path>, new_dir: impl AsRef<Path>, patch_path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let patch = self.encode_dir(old_dir, new_dir)?;
+        fs::write(patch_path.as_ref(), patch).map_err(|e| format!("[HDiffWriter::write_dir] Cannot write {}: {}", patch_path.as_ref().display(), e))?;
+        Ok(())
+    }
+}
+
+/// A file's contents, `None` for directories and empty files, which are neither diffed nor paired.
+fn contents(entry: &TreeEntry) -> Option<&[u8]> {
+    entry.data.as_deref().filter(|d| !d.is_empty())
+}
+
+fn compression_name(compression: CompressionMode) -> io::Result<&'static str> {
+    match compression {
+        CompressionMode::Nocomp => Ok(""),
+        CompressionMode::Zstd => Ok("zstd"),
+        other => Err(io::Error::new(io::ErrorKind::Unsupported, format!("[HDiffWriter] {:?} compression is not supported", other))),
+    }
+}
+
+/// A complete `HDIFF13` patch, signature included. Each of the four streams is compressed on its own and stored
+/// as is when that does not make it smaller.
+pub(crate) fn encode_hdiff13(old: &[u8], new: &[u8], compression: CompressionMode, level: i32) -> io::Result<Vec<u8>> {
+    let name = compression_name(compression)?;
+    if old.len() >= u32::MAX as usize { return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("[HDiffWriter] Old data of {} bytes is too large", old.len()))); }
+
+    let covers = search_covers(old, new, true);
+    let (cover_buf, new_data_diff) = pack_covers(&covers, new);
+    let (rle_ctrl, rle_code) = pack_rle(&sub_diff(old, new, &covers));
+
+    let streams = [cover_buf, rle_ctrl, rle_code, new_data_diff];
+    let mut compressed = Vec::with_capacity(streams.len());
+    for stream in &streams { compressed.push(compress(stream, compression, level)?); }
+
+    let mut out = format!("HDIFF13&{}\0", name).into_bytes();
+    for v in [new.len(), old.len(), covers.len()] { pack_uint(&mut out, v as u64); }
+    for (stream, packed) in streams.iter().zip(&compressed) {
+        pack_uint(&mut out, stream.len() as u64);
+        pack_uint(&mut out, packed.as_ref().map_or(0, |p| p.len() as u64));
+    }
+    for (stream, packed) in streams.iter().zip(&compressed) { out.extend_from_slice(packed.as_deref().unwrap_or(stream)); }
+
+    #[cfg(debug_assertions)]
+    println!("[HDiffWriter] {} -> {} bytes | {} covers | patch {} bytes", old.len(), new.len(), covers.len(), out.len());
+    Ok(out)
+}
+
+/// `None` when the stream is better stored uncompressed.
+fn compress(stream: &[u8], compression: CompressionMode, level: i32) -> io::Result<Option<Vec<u8>>> {
+    if compression == CompressionMode::Nocomp || stream.is_empty() { return Ok(None); }
+    let packed = zstd::bulk::compress(stream, level)?;
+    Ok((packed.len() < stream.len()).then_some(packed))
+}
+
+/// What has to be added to the old bytes under each cover to get the new ones, 0 everywhere else.
+fn sub_diff(old: &[u8], new: &[u8], covers: &[(usize, usize, usize)]) -> Vec<u8> {
+    let mut diff = vec![0u8; new.len()];
+    for &(o, n, l) in covers {
+        for (d, (a, b)) in diff[n..n + l].iter_mut().zip(old[o..o + l].iter().zip(&new[n..n + l])) { *d = b.wrapping_sub(*a); }
+    }
+    diff
+}
@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::utils::journal::OutputFs;
+
+const STAGING_DIR_NAME: &str = ".hdiffpatch-staging";
+
+/// Tracks outputs of an in-place patch (source == destination) that would clobber old files still
+/// needed as patch input. Those outputs are written under a scratch directory inside the output root
+/// and only renamed over the originals once the whole patch went through.
+#[derive(Debug, Clone)]
+pub(crate) struct InPlacePlan {
+    output_root: PathBuf,
+    staging_root: PathBuf,
+    staged: HashSet<String>,
+}
+
+impl InPlacePlan {
+    pub fn new<'a>(output_root: &Path, referenced_old: impl IntoIterator<Item = &'a str>, outputs: impl IntoIterator<Item = &'a str>) -> Self {
+        let referenced: HashSet<&str> = referenced_old.into_iter().collect();
+        let staged = outputs.into_iter().filter(|p| referenced.contains(p)).map(str::to_string).collect();
+        Self { output_root: output_root.to_path_buf(), staging_root: output_root.join(STAGING_DIR_NAME), staged }
+    }
+
+    pub fn is_staged(&self, rel: &str) -> bool {
+        self.staged.contains(rel)
+    }
+
+    /// Where an output should be written right now: the staging copy for overlapping outputs, the final path otherwise.
+    pub fn target(&self, rel: &str) -> PathBuf {
+        if self.is_staged(rel) { self.staging_root.join(rel) } else { self.output_root.join(rel) }
+    }
+
+    pub fn commit(&self, output_fs: &OutputFs) -> io::Result<()> {
+        for rel in &self.staged {
+            let staged = self.staging_root.join(rel);
+            if !staged.exists() { continue; }
+            output_fs.rename(&staged, &self.output_root.join(rel))?;
+        }
+        self.discard();
+        Ok(())
+    }
+
+    /// Deletes old files and directories that are not part of the new tree, then prunes directories left empty.
+    pub fn remove_obsolete<'a>(&self, output_fs: &OutputFs, old_paths: impl IntoIterator<Item = &'a str>, new_paths: impl IntoIterator<Item = &'a str>) -> io::Result<()> {
+        let keep: HashSet<&str> = new_paths.into_iter().map(|p| p.trim_end_matches('/')).collect();
+        let mut dirs: Vec<PathBuf> = Vec::new();
+
+        for old in old_paths {
+            let rel = old.trim_end_matches('/');
+            if rel.is_empty() || keep.contains(rel) { continue; }
+            let full = self.output_root.join(rel);
+            if old.ends_with('/') { dirs.push(full); continue; }
+            output_fs.remove_file(&full)?;
+            let mut parent = full.parent();
+            while let Some(p) = parent {
+                if p == self.output_root { break; }
+                dirs.push(p.to_path_buf());
+                parent = p.parent();
+            }
+        }
+
+        dirs.sort_by(|a, b| b.components().count().cmp(&a.components().count()).then_with(|| a.cmp(b)));
+        dirs.dedup();
+        for dir in dirs {
+            let rel = dir.strip_prefix(&self.output_root).ok().and_then(|r| r.to_str()).unwrap_or("");
+            if keep.contains(rel) { continue; }
+            let is_empty = fs::read_dir(&dir).map(|mut it| it.next().is_none()).unwrap_or(false);
+            if is_empty { output_fs.remove_dir(&dir)?; }
+        }
+        Ok(())
+    }
+
+    pub fn discard(&self) {
+        if self.staging_root.exists() { let _ = fs::remove_dir_all(&self.staging_root); }
+    }
+}
+
+pub(crate) fn is_same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
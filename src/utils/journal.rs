@@ -0,0 +1,298 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::utils::structs::SymlinkPolicy;
+use crate::utils::symlinks::{for_each_link_below, refused};
+
+const JOURNAL_DIR_NAME: &str = ".hdiffpatch-journal";
+const JOURNAL_FILE_NAME: &str = "journal";
+const BACKUP_DIR_NAME: &str = "backups";
+
+// Record kinds. Every record is `kind, relative path, \0, backup name, \0` and is synced before the change it describes.
+const REC_CREATED: u8 = b'C';
+const REC_BACKED_UP: u8 = b'B';
+const REC_MADE_DIR: u8 = b'M';
+const REC_REMOVED_DIR: u8 = b'X';
+
+/// Write-ahead log of every change a transactional patch makes below its output root.
+/// Replaced and deleted files are moved into a backup folder next to the journal so they can be put back.
+pub(crate) struct Journal {
+    root: PathBuf,
+    dir: PathBuf,
+    file: File,
+    touched: HashSet<PathBuf>,
+    backup_count: u64,
+}
+
+impl Journal {
+    pub fn begin(root: &Path) -> io::Result<Self> {
+        let created_root = !root.exists();
+        if created_root { fs::create_dir_all(root)?; }
+
+        let dir = root.join(JOURNAL_DIR_NAME);
+        fs::create_dir_all(dir.join(BACKUP_DIR_NAME))?;
+        let file = File::options().create(true).truncate(true).write(true).open(dir.join(JOURNAL_FILE_NAME))?;
+        let mut journal = Self { root: root.to_path_buf(), dir, file, touched: HashSet::new(), backup_count: 0 };
+        if created_root { journal.append(REC_MADE_DIR, Path::new(""), "")?; }
+        Ok(journal)
+    }
+
+    /// Rolls back a journal left behind by a crashed or killed transactional patch. Returns true if there was one.
+    pub fn recover(root: &Path) -> io::Result<bool> {
+        let dir = root.join(JOURNAL_DIR_NAME);
+        let path = dir.join(JOURNAL_FILE_NAME);
+        if !path.exists() {
+            if dir.exists() { fs::remove_dir_all(&dir)?; }
+            return Ok(false);
+        }
+
+        #[cfg(debug_assertions)]
+        println!("[Journal::recover] Rolling back interrupted patch in {}", root.display());
+
+        let mut raw = Vec::new();
+        File::open(&path)?.read_to_end(&mut raw)?;
+        Self::replay_backwards(root, &dir, &parse_records(&raw))?;
+        Ok(true)
+    }
+
+    fn replay_backwards(root: &Path, dir: &Path, records: &[(u8, String, String)]) -> io::Result<()> {
+        for (kind, rel, backup) in records.iter().rev() {
+            let full = root.join(rel);
+            match *kind {
+                REC_CREATED => remove_if_exists(&full)?,
+                REC_BACKED_UP => {
+                    let saved = dir.join(BACKUP_DIR_NAME).join(backup);
+                    if saved.exists() {
+                        remove_if_exists(&full)?;
+                        fs::rename(&saved, &full)?;
+                    }
+                }
+                REC_MADE_DIR if rel.is_empty() => {
+                    fs::remove_dir_all(dir)?;
+                    let _ = fs::remove_dir(root);
+                    return Ok(());
+                }
+                REC_MADE_DIR => { let _ = fs::remove_dir(&full); }
+                REC_REMOVED_DIR => fs::create_dir_all(&full)?,
+                _ => {}
+            }
+        }
+        fs::remove_dir_all(dir)
+    }
+
+    fn append(&mut self, kind: u8, full: &Path, backup: &str) -> io::Result<()> {
+        let rel = full.strip_prefix(&self.root).unwrap_or(full).to_string_lossy();
+        let mut rec = Vec::with_capacity(rel.len() + backup.len() + 3);
+        rec.push(kind);
+        rec.extend_from_slice(rel.as_bytes());
+        rec.push(0);
+        rec.extend_from_slice(backup.as_bytes());
+        rec.push(0);
+        self.file.write_all(&rec)?;
+        self.file.sync_data()
+    }
+
+    /// Must be called before anything is written to, renamed onto or removed at `full`.
+    pub fn before_write(&mut self, full: &Path) -> io::Result<()> {
+        if !self.touched.insert(full.to_path_buf()) { return Ok(()); }
+        if fs::symlink_metadata(full).is_err() { return self.append(REC_CREATED, full, ""); }
+
+        let backup = self.backup_count.to_string();
+        self.backup_count += 1;
+        self.append(REC_BACKED_UP, full, &backup)?;
+        fs::rename(full, self.dir.join(BACKUP_DIR_NAME).join(&backup))
+    }
+
+    pub fn before_create_dir(&mut self, full: &Path) -> io::Result<()> {
+        if full.exists() || !self.touched.insert(full.to_path_buf()) { return Ok(()); }
+        self.append(REC_MADE_DIR, full, "")
+    }
+
+    pub fn before_remove_dir(&mut self, full: &Path) -> io::Result<()> {
+        self.touched.insert(full.to_path_buf());
+        self.append(REC_REMOVED_DIR, full, "")
+    }
+
+    pub fn rollback(&mut self) -> io::Result<()> {
+        self.file.sync_all()?;
+        let mut raw = Vec::new();
+        File::open(self.dir.join(JOURNAL_FILE_NAME))?.read_to_end(&mut raw)?;
+        Self::replay_backwards(&self.root, &self.dir, &parse_records(&raw))
+    }
+
+    /// Drops the journal first so a crash during cleanup can never be mistaken for an unfinished patch.
+    pub fn commit(&mut self) -> io::Result<()> {
+        fs::remove_file(self.dir.join(JOURNAL_FILE_NAME))?;
+        fs::remove_dir_all(&self.dir)
+    }
+}
+
+fn parse_records(raw: &[u8]) -> Vec<(u8, String, String)> {
+    let mut records = Vec::new();
+    let mut rest = raw;
+    while let Some((&kind, tail)) = rest.split_first() {
+        let Some(path_end) = tail.iter().position(|&b| b == 0) else { break; };
+        let Some(backup_end) = tail[path_end + 1..].iter().position(|&b| b == 0) else { break; };
+        let path = String::from_utf8_lossy(&tail[..path_end]).into_owned();
+        let backup = String::from_utf8_lossy(&tail[path_end + 1..path_end + 1 + backup_end]).into_owned();
+        records.push((kind, path, backup));
+        rest = &tail[path_end + 1 + backup_end + 1..];
+    }
+    records
+}
+
+fn remove_if_exists(full: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(full) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(full),
+        Ok(_) => fs::remove_file(full),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Every filesystem change below a patch's output root goes through here, so transactional patches get journaled
+/// and symlinks on the way to an output are handled by the patch's `SymlinkPolicy`.
+#[derive(Clone, Default)]
+pub(crate) struct OutputFs {
+    journal: Option<Rc<RefCell<Journal>>>,
+    root: PathBuf,
+    symlink_policy: SymlinkPolicy,
+}
+
+impl OutputFs {
+    pub fn new(root: &Path, symlink_policy: SymlinkPolicy) -> Self {
+        Self { journal: None, root: root.to_path_buf(), symlink_policy }
+    }
+
+    pub fn transactional(root: &Path, symlink_policy: SymlinkPolicy) -> io::Result<Self> {
+        Ok(Self { journal: Some(Rc::new(RefCell::new(Journal::begin(root)?))), root: root.to_path_buf(), symlink_policy })
+    }
+
+    pub fn symlink_policy(&self) -> SymlinkPolicy {
+        self.symlink_policy
+    }
+
+    /// Applies the symlink policy to every existing component of `path` below the root. `Follow` keeps links to
+    /// directories but replaces one sitting where the file `path` goes; `Opaque` replaces all of them.
+    fn clear_links(&self, path: &Path, is_file: bool) -> io::Result<()> {
+        for_each_link_below(&self.root, path, |link, is_last| match self.symlink_policy {
+            SymlinkPolicy::Refuse => Err(refused(link, self.symlink_policy)),
+            SymlinkPolicy::Follow if !(is_last && is_file) => Ok(()),
+            _ => self.unlink(link),
+        })
+    }
+
+    fn clear_parent_links(&self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) => self.clear_links(parent, false),
+            None => Ok(()),
+        }
+    }
+
+    pub fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.clear_links(path, false)?;
+        if let Some(journal) = &self.journal {
+            let mut missing = Vec::new();
+            let mut cur = Some(path);
+            while let Some(p) = cur {
+                if p.as_os_str().is_empty() || p.exists() { break; }
+                missing.push(p);
+                cur = p.parent();
+            }
+            let mut journal = journal.borrow_mut();
+            for p in missing.iter().rev() { journal.before_create_dir(p)?; }
+        }
+        fs::create_dir_all(path)
+    }
+
+    /// Opens `path` for reading and writing as a fresh, empty file, creating parent directories as needed.
+    pub fn create_file(&self, path: &Path) -> io::Result<File> {
+        self.prepare_target(path)?;
+        no_follow(File::options().read(true).write(true).create(true).truncate(true)).open(path)
+    }
+
+    /// Opens `path` for reading and writing without dropping what is already in it, used when resuming a patch.
+    pub fn reopen_file(&self, path: &Path) -> io::Result<File> {
+        self.prepare_target(path)?;
+        no_follow(File::options().read(true).write(true).create(true).truncate(false)).open(path)
+    }
+
+    // Parent directories exist, links are dealt with and the journal knows about `path`.
+    fn prepare_target(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() { self.create_dir_all(parent)?; }
+        self.clear_links(path, true)?;
+        if let Some(journal) = &self.journal { journal.borrow_mut().before_write(path)?; }
+        Ok(())
+    }
+
+    fn unlink(&self, link: &Path) -> io::Result<()> {
+        if let Some(journal) = &self.journal { journal.borrow_mut().before_write(link)?; }
+        match fs::remove_file(link) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+
+    pub fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        self.prepare_target(to)?;
+        fs::copy(from, to)
+    }
+
+    pub fn hard_link(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.prepare_target(to)?;
+        match fs::remove_file(to) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        fs::hard_link(from, to)
+    }
+
+    pub fn is_transactional(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    pub fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.prepare_target(to)?;
+        fs::rename(from, to)
+    }
+
+    pub fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.clear_parent_links(path)?;
+        // A journaled file is moved into the backups here, so there may be nothing left to remove.
+        if let Some(journal) = &self.journal { journal.borrow_mut().before_write(path)?; }
+        match fs::remove_file(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        }
+    }
+
+    pub fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.clear_parent_links(path)?;
+        if let Some(journal) = &self.journal { journal.borrow_mut().before_remove_dir(path)?; }
+        fs::remove_dir(path)
+    }
+
+    pub fn commit(&self) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.borrow_mut().commit(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn rollback(&self) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.borrow_mut().rollback(),
+            None => Ok(()),
+        }
+    }
+}
+
+// The last component is never followed, so a link swapped in after `clear_links` still cannot redirect the write.
+fn no_follow(options: &mut OpenOptions) -> &mut OpenOptions {
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::custom_flags(options, libc::O_NOFOLLOW);
+    options
+}
@@ -0,0 +1,81 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::utils::checksum::crc32_of;
+use crate::utils::encoder::{list_tree, pack_covers, pack_deltas, pack_uint, search_covers, TreeEntry};
+
+/// Encodes the difference between two directories as an uncompressed KrDiff patch: an HDIFF19 head listing every
+/// old and new file with its size, followed by an HDIFF13 diff turning all old files, back to back, into all new
+/// files. Matches are found through a suffix array of the old data. Every file is read into memory, and nothing is
+/// compressed.
+#[derive(Debug, Clone)]
+pub struct KrDiffWriter {
+    old_dir: PathBuf,
+    new_dir: PathBuf,
+    crc32: bool,
+}
+
+impl KrDiffWriter {
+    pub fn new(old_dir: impl AsRef<Path>, new_dir: impl AsRef<Path>) -> Self {
+        Self { old_dir: old_dir.as_ref().to_path_buf(), new_dir: new_dir.as_ref().to_path_buf(), crc32: false }
+    }
+
+    /// Stores `crc32` checksums of the old data, the new data and the patch itself, as `KrDiff` verifies them.
+    pub fn with_crc32(mut self, crc32: bool) -> Self {
+        self.crc32 = crc32;
+        self
+    }
+
+    /// Builds the whole patch in memory. Symlinks and paths that are not valid UTF-8 are refused.
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let old = list_tree(&self.old_dir)?;
+        let new = list_tree(&self.new_dir)?;
+
+        let old_files: Vec<usize> = (0..old.len()).filter(|&i| old[i].data.is_some()).collect();
+        let new_files: Vec<usize> = (0..new.len()).filter(|&i| new[i].data.is_some()).collect();
+        let data = |entries: &[TreeEntry], files: &[usize]| files.iter().flat_map(|&i| entries[i].data.as_deref().unwrap_or_default().iter().copied()).collect::<Vec<u8>>();
+        let (old_data, new_data) = (data(&old, &old_files), data(&new, &new_files));
+        if old_data.len() >= u32::MAX as usize { return Err(format!("[KrDiffWriter::encode] Old files of {} bytes are too large", old_data.len()).into()); }
+
+        let mut head = Vec::new();
+        for e in old.iter().chain(new.iter()) { head.extend_from_slice(e.path.as_bytes()); head.push(0); }
+        pack_deltas(&mut head, &old_files);
+        pack_deltas(&mut head, &new_files);
+        for e in old_files.iter().map(|&i| &old[i]).chain(new_files.iter().map(|&i| &new[i])) { pack_uint(&mut head, e.data.as_ref().map_or(0, |d| d.len() as u64)); }
+        // KrDiff's own per-file varint, which `KrDiff` reads past.
+        for _ in &new_files { pack_uint(&mut head, 0); }
+
+        let sum_size = |entries: &[TreeEntry]| entries.iter().map(|e| e.path.len() + 1).sum::<usize>();
+        let mut out = if self.crc32 { b"HDIFF19&&crc32\0".to_vec() } else { b"HDIFF19&&\0".to_vec() };
+        // Both sides are directories.
+        out.extend_from_slice(&[1, 1]);
+        for v in [old.len(), sum_size(&old), new.len(), sum_size(&new), old_files.len(), old_data.len(), new_files.len(), new_data.len(), 0, 0, 0, 0, 0, 0, head.len(), 0, if self.crc32 { 4 } else { 0 }] { pack_uint(&mut out, v as u64); }
+        let checksum_at = out.len();
+        out.extend_from_slice(&head);
+
+        // KrDiff copies old bytes verbatim, so covers have to match exactly.
+        let covers = search_covers(&old_data, &new_data, false);
+        let (cover_buf, diff_buf) = pack_covers(&covers, &new_data);
+        out.extend_from_slice(b"HDIFF13&\0");
+        for v in [new_data.len(), old_data.len(), covers.len(), cover_buf.len(), 0, 0, 0, 0, 0, diff_buf.len(), 0] { pack_uint(&mut out, v as u64); }
+        out.extend_from_slice(&cover_buf);
+        out.extend_from_slice(&diff_buf);
+
+        if self.crc32 {
+            // New data, old data, same files (KrDiff has none), then everything after the checksums.
+            let rest = crc32_of([Ok(&out[checksum_at..])])?;
+            let checksums: Vec<u8> = [crc32_of([Ok(&new_data[..])])?, crc32_of([Ok(&old_data[..])])?, 0, rest].iter().flat_map(|c| c.to_le_bytes()).collect();
+            out.splice(checksum_at..checksum_at, checksums);
+        }
+
+        #[cfg(debug_assertions)]
+        println!("[KrDiffWriter] {} old files | {} new files | {} covers | {} bytes", old_files.len(), new_files.len(), covers.len(), out.len());
+        Ok(out)
+    }
+
+    pub fn write(&self, patch_path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let patch = self.encode()?;
+        fs::write(patch_path.as_ref(), patch).map_err(|e| format!("[KrDiffWriter::write] Cannot write {}: {}", patch_path.as_ref().display(), e))?;
+        Ok(())
+    }
+}
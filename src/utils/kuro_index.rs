@@ -0,0 +1,203 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use md5::{Digest, Md5};
+use serde::Deserialize;
+use crate::utils::checkpoint::SyncWrite;
+use crate::utils::patch_krdir::KrFileEntry;
+use crate::utils::paths::{from_windows_path, into_patch_error};
+
+/// One file listed in Kuro's resource index.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct KuroResource {
+    /// Relative to the game directory, usually with a leading `/`.
+    pub dest: String,
+    pub size: u64,
+    /// Lowercase hex.
+    pub md5: String,
+}
+
+/// The resource index Kuro publishes for every game version: a list of `dest`, `size` and `md5` entries,
+/// either on its own or under a `resource` key. Other fields are ignored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KuroResourceIndex {
+    pub resources: Vec<KuroResource>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IndexFile {
+    Wrapped { resource: Vec<KuroResource> },
+    List(Vec<KuroResource>),
+}
+
+/// What `KuroResourceIndex::verify` found. Paths are relative to the checked directory, `/`-separated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KuroIndexReport {
+    /// Listed in the index, not on disk.
+    pub missing: Vec<String>,
+    /// On disk, not listed in the index.
+    pub extra: Vec<String>,
+    /// `(path, expected, actual)` sizes. These files are not hashed.
+    pub wrong_size: Vec<(String, u64, u64)>,
+    /// `(path, expected, actual)` MD5s in lowercase hex.
+    pub wrong_hash: Vec<(String, String, String)>,
+}
+
+impl KuroIndexReport {
+    /// Nothing missing, nothing extra and every listed file matches.
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.wrong_size.is_empty() && self.wrong_hash.is_empty()
+    }
+}
+
+impl KuroResourceIndex {
+    pub fn parse(json: &str) -> Result<Self, Box<dyn Error>> {
+        let resources = match serde_json::from_str(json).map_err(|e| format!("[KuroResourceIndex::parse] Not a resource index: {}", e))? {
+            IndexFile::Wrapped { resource } => resource,
+            IndexFile::List(resources) => resources,
+        };
+        Ok(Self { resources })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let json = fs::read_to_string(path.as_ref()).map_err(|e| format!("[KuroResourceIndex::load] Cannot read {}: {}", path.as_ref().display(), e))?;
+        Self::parse(&json)
+    }
+
+    /// Checks every file below `root` against the index, hashing each listed file of the right size.
+    /// Index entries that would point outside `root` are refused with an `UnsafePathError`.
+    pub fn verify(&self, root: impl AsRef<Path>) -> Result<KuroIndexReport, Box<dyn Error>> {
+        self.verify_with(root.as_ref(), &HashMap::new()).map_err(into_patch_error)
+    }
+
+    /// `known_md5s` holds hashes already taken while the files were written, so those are not read again.
+    pub(crate) fn verify_with(&self, root: &Path, known_md5s: &HashMap<String, [u8; 16]>) -> io::Result<KuroIndexReport> {
+        let mut report = KuroIndexReport::default();
+        let mut listed = HashSet::new();
+        for resource in &self.resources {
+            let path = from_windows_path(resource.dest.trim_start_matches(['/', '\\']))?;
+            let full = root.join(&path);
+            listed.insert(path.clone());
+
+            let actual_size = match fs::metadata(&full) {
+                Ok(meta) if meta.is_file() => meta.len(),
+                Ok(_) => { report.missing.push(path); continue; }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => { report.missing.push(path); continue; }
+                Err(e) => return Err(e),
+            };
+            if actual_size != resource.size { report.wrong_size.push((path, resource.size, actual_size)); continue; }
+
+            let actual_md5 = match known_md5s.get(&path) {
+                Some(md5) => to_hex(md5),
+                None => to_hex(&md5_of_file(&full)?),
+            };
+            if !actual_md5.eq_ignore_ascii_case(&resource.md5) { report.wrong_hash.push((path, resource.md5.to_lowercase(), actual_md5)); }
+        }
+
+        collect_extra(root, root, &listed, &mut report.extra)?;
+        report.extra.sort();
+
+        #[cfg(debug_assertions)]
+        println!("[KuroResourceIndex::verify] {} listed | {} missing | {} extra | {} wrong size | {} wrong hash", self.resources.len(), report.missing.len(), report.extra.len(), report.wrong_size.len(), report.wrong_hash.len());
+        Ok(report)
+    }
+}
+
+fn collect_extra(root: &Path, dir: &Path, listed: &HashSet<String>, extra: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() { collect_extra(root, &entry.path(), listed, extra)?; continue; }
+        let path = entry.path();
+        let rel = path.strip_prefix(root).unwrap_or(&path).to_string_lossy().replace('\\', "/");
+        if !listed.contains(&rel) { extra.push(rel); }
+    }
+    Ok(())
+}
+
+fn md5_of_file(path: &Path) -> io::Result<[u8; 16]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Md5::new();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 { break; }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// MD5s of the new files of a KrDiff patch, fed from the single stream all of them are written through.
+pub(crate) struct OutputMd5s {
+    /// `(path, size)` of every new file, in write order.
+    files: Vec<(String, u64)>,
+    current: usize,
+    remaining: u64,
+    hasher: Md5,
+    done: HashMap<String, [u8; 16]>,
+}
+
+impl OutputMd5s {
+    pub fn new(new_files: &[KrFileEntry]) -> Self {
+        let mut md5s = Self { files: new_files.iter().map(|fe| (fe.path.clone(), fe.size)).collect(), current: 0, remaining: 0, hasher: Md5::new(), done: HashMap::new() };
+        md5s.remaining = md5s.files.first().map_or(0, |f| f.1);
+        md5s.finish_empty();
+        md5s
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() && self.current < self.files.len() {
+            let take = (self.remaining as usize).min(data.len());
+            self.hasher.update(&data[..take]);
+            self.remaining -= take as u64;
+            data = &data[take..];
+            self.finish_empty();
+        }
+    }
+
+    // Closes the current file once all of it went by, along with any empty files after it.
+    fn finish_empty(&mut self) {
+        while self.current < self.files.len() && self.remaining == 0 {
+            let digest = std::mem::take(&mut self.hasher).finalize().into();
+            self.done.insert(self.files[self.current].0.clone(), digest);
+            self.current += 1;
+            self.remaining = self.files.get(self.current).map_or(0, |f| f.1);
+        }
+    }
+
+    /// The hashes of the files that were written completely.
+    pub fn finish(self) -> HashMap<String, [u8; 16]> {
+        self.done
+    }
+}
+
+/// Passes writes through to `inner`, hashing whatever was accepted.
+pub(crate) struct Md5Writer<'a> {
+    pub inner: &'a mut dyn SyncWrite,
+    pub md5s: Option<&'a mut OutputMd5s>,
+}
+
+impl Write for Md5Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(md5s) = self.md5s.as_mut() { md5s.update(&buf[..n]); }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl SyncWrite for Md5Writer<'_> {
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.inner.sync_data()
+    }
+}
@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::fs::{self, File, Permissions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::utils::structs::{OutputTimestamps, PatchOptions};
+
+/// What gets applied to one output file once the whole patch went through.
+pub(crate) struct OutputMetadata {
+    pub path: PathBuf,
+    /// Timestamp and permissions of the matched old file, read before patching since an in-place patch replaces it.
+    pub old: Option<(SystemTime, Permissions)>,
+    /// Marked executable by the patch itself (HDIFF19 `new_execute_list`).
+    pub executable: bool,
+}
+
+impl OutputMetadata {
+    pub fn new(path: PathBuf, old_path: Option<&Path>, executable: bool, options: &PatchOptions) -> io::Result<Self> {
+        let wants_old = options.preserve_permissions || options.output_timestamps == OutputTimestamps::FromOldFile;
+        let old = match old_path {
+            Some(old_path) if wants_old => read_old_metadata(old_path)?,
+            _ => None,
+        };
+        Ok(Self { path, old, executable })
+    }
+}
+
+fn read_old_metadata(path: &Path) -> io::Result<Option<(SystemTime, Permissions)>> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_file() => Ok(Some((meta.modified()?, meta.permissions()))),
+        Ok(_) => Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// The old file an output carries its metadata over from: the source of an identical pair, otherwise the old file
+/// at the same path. With `windows_paths` the path comparison ignores case, like the old file lookup does.
+pub(crate) fn match_old_paths<'a>(old_paths: &'a [String], new_paths: &[String], same_pairs: impl IntoIterator<Item = (usize, usize)>, ignore_case: bool) -> Vec<Option<&'a str>> {
+    let key = |p: &str| if ignore_case { p.to_lowercase() } else { p.to_string() };
+    let by_path: HashMap<String, &str> = old_paths.iter().map(|p| (key(p), p.as_str())).collect();
+    let mut matched: Vec<Option<&str>> = new_paths.iter().map(|p| by_path.get(&key(p)).copied()).collect();
+    for (new_index, old_index) in same_pairs { matched[new_index] = Some(old_paths[old_index].as_str()); }
+    matched
+}
+
+pub(crate) fn wants_output_metadata(options: &PatchOptions) -> bool {
+    options.output_timestamps != OutputTimestamps::Now || options.preserve_permissions
+}
+
+/// Runs last, so nothing written afterwards bumps the timestamps again. Outputs that were not written are skipped.
+pub(crate) fn apply_output_metadata(outputs: &[OutputMetadata], options: &PatchOptions) -> io::Result<()> {
+    for output in outputs {
+        let meta = match fs::symlink_metadata(&output.path) {
+            Ok(meta) if meta.is_file() => meta,
+            Ok(_) => continue,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+
+        let modified = match options.output_timestamps {
+            OutputTimestamps::Now => None,
+            OutputTimestamps::FromOldFile => output.old.as_ref().map(|old| old.0),
+            OutputTimestamps::Fixed(time) => Some(time),
+        };
+        // Before the permissions, which may take away the write access this needs.
+        if let Some(time) = modified { File::options().write(true).open(&output.path)?.set_modified(time).map_err(|e| io::Error::new(e.kind(), format!("[apply_output_metadata] Cannot set the timestamp of {}: {}", output.path.display(), e)))?; }
+
+        let mut permissions = match &output.old {
+            Some((_, old)) if options.preserve_permissions => old.clone(),
+            _ => meta.permissions(),
+        };
+        if output.executable { set_executable(&mut permissions); }
+        if permissions != meta.permissions() { fs::set_permissions(&output.path, permissions)?; }
+    }
+    Ok(())
+}
+
+// Execute permission for whoever may read the file, as hpatchz does.
+#[cfg(unix)]
+fn set_executable(permissions: &mut Permissions) {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = permissions.mode();
+    permissions.set_mode(mode | ((mode & 0o444) >> 2));
+}
+
+#[cfg(not(unix))]
+fn set_executable(_permissions: &mut Permissions) {}
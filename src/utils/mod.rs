@@ -6,4 +6,21 @@ pub(crate) mod patch_single;
 pub(crate) mod patch_core;
 pub(crate) mod patch_dir;
 pub(crate) mod patch_krdir;
-pub(crate) mod patch_sf;
\ No newline at end of file
+pub(crate) mod patch_sf;
+pub(crate) mod in_place;
+pub(crate) mod journal;
+pub(crate) mod checksum;
+pub(crate) mod checkpoint;
+pub(crate) mod paths;
+pub(crate) mod same_file;
+pub(crate) mod filter;
+pub(crate) mod symlinks;
+pub(crate) mod metadata;
+pub(crate) mod disk_space;
+pub(crate) mod atomic_file;
+pub(crate) mod encoder;
+pub(crate) mod suffix_array;
+pub(crate) mod hdiff_writer;
+pub(crate) mod kr_writer;
+#[cfg(feature = "kuro-index")]
+pub(crate) mod kuro_index;
@@ -1,19 +1,11 @@
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
+use crate::utils::checkpoint::SyncWrite;
+use crate::utils::disk_space::{size_output_file, OutputAllocation};
 use crate::utils::structs::{CombinedStream, NewFileCombinedStream, PairIndexReference};
 
 impl<T: Read> BinaryExtensions for T {}
 
-pub(crate) fn get_file_stream_buffer_size(file_size: u64) -> usize {
-    match file_size {
-        0..=131_072 => 4 * 1024,
-        131_073..=1_048_576 => 64 * 1024,
-        1_048_577..=33_554_432 => 128 * 1024,
-        33_554_433..=104_857_600 => 512 * 1024,
-        _ => 1 * 1024 * 1024,
-    }
-}
-
 pub(crate) trait BinaryExtensions: Read {
     fn read_boolean(&mut self) -> std::io::Result<bool> {
         let mut b = [0u8; 1];
@@ -92,9 +84,7 @@ pub(crate) trait BinaryExtensions: Read {
         for i in 0..count {
             let num = self.read_long_7bit()?;
             back_value += 1 + num;
-            if let Some(max_val) = check_count {
-                if back_value > max_val { return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("[get_longs_from_stream] Invalid back value at {}, expected max {}", i, max_val))); }
-            }
+            if let Some(max_val) = check_count && back_value > max_val { return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("[get_longs_from_stream] Invalid back value at {}, expected max {}", i, max_val))); }
             out.push(back_value);
         }
         Ok(out)
@@ -168,96 +158,38 @@ pub(crate) fn read_long_7bit_from_slice(buf: &[u8], offset: &mut usize, tag_bit:
     value
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct ChunkStream<T: Read + Seek> {
-    inner: T,
-    start: u64,
-    end: u64,
-    cur_pos: u64,
-}
-
-impl<T: Read + Seek> ChunkStream<T> {
-    pub fn new(mut stream: T, start: u64, end: u64) -> std::io::Result<Self> {
-        if end < start {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "end < start"));
-        }
-        stream.seek(SeekFrom::Start(start))?;
-        Ok(Self { inner: stream, start, end, cur_pos: 0 })
-    }
-
-    #[inline]
-    fn size(&self) -> u64 { self.end - self.start }
-
-    #[inline]
-    fn remain(&self) -> u64 { self.size().saturating_sub(self.cur_pos) }
-}
-
-impl<T: Read + Seek> Read for ChunkStream<T> {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        if self.remain() == 0 { return Ok(0); }
-        let to_read = std::cmp::min(buf.len() as u64, self.remain()) as usize;
-        self.inner.seek(SeekFrom::Start(self.start + self.cur_pos))?;
-        let n = self.inner.read(&mut buf[..to_read])?;
-        self.cur_pos += n as u64;
-        Ok(n)
-    }
-}
-
-impl<T: Read + Seek> Seek for ChunkStream<T> {
-    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
-        let new_pos = match pos {
-            SeekFrom::Start(offset) => offset,
-            SeekFrom::Current(offset) => {
-                let signed = self.cur_pos as i64 + offset;
-                if signed < 0 { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start")); }
-                signed as u64
-            }
-            SeekFrom::End(offset) => {
-                let signed = self.size() as i64 + offset;
-                if signed < 0 { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek before start")); }
-                signed as u64
-            }
-        };
-        if new_pos > self.size() { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "seek beyond end")); }
-        self.cur_pos = new_pos;
-        self.inner.seek(SeekFrom::Start(self.start + new_pos))?;
-        Ok(self.cur_pos)
-    }
-}
-
 impl CombinedStream {
+    /// No streams, or only empty ones, is a valid zero-length stream: a patch may have no old reference data
+    /// at all, or build nothing but empty files.
     pub fn new(streams: Vec<File>) -> std::io::Result<Self> {
-        if streams.is_empty() { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "streams cannot be empty")); }
-        let mut start_positions = vec![0u64; streams.len()];
-        for i in 1..streams.len() {
-            let prev_len = streams[i - 1].metadata()?.len();
-            start_positions[i] = start_positions[i - 1] + prev_len;
+        let mut start_positions = Vec::with_capacity(streams.len());
+        let mut total_len = 0u64;
+        for s in &streams {
+            start_positions.push(total_len);
+            total_len += s.metadata()?.len();
         }
-        let last_len = streams.last().unwrap().metadata()?.len();
-        let total_len = start_positions.last().copied().unwrap_or(0) + last_len;
-        Ok(Self { streams, start_positions, position: 0, index: 0, total_length: total_len })
+        Ok(Self { streams, allocation: None, start_positions, position: 0, index: 0, synced_index: 0, total_length: total_len })
     }
 
-    pub fn from_new_files(new_streams: Vec<NewFileCombinedStream>) -> std::io::Result<Self> {
-        if new_streams.is_empty() { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "streams cannot be empty")); }
+    pub fn from_new_files(new_streams: Vec<NewFileCombinedStream>, preallocate: bool) -> std::io::Result<Self> {
         let mut streams = Vec::with_capacity(new_streams.len());
-        let mut start_positions = vec![0u64; new_streams.len()];
-        for (i, s) in new_streams.iter().enumerate() {
-            s.file.set_len(s.size)?;
-            if i > 0 { start_positions[i] = start_positions[i - 1] + new_streams[i - 1].size; }
+        let mut start_positions = Vec::with_capacity(new_streams.len());
+        let mut total_len = 0u64;
+        let mut allocation = None;
+        for s in &new_streams {
+            allocation = OutputAllocation::combine(allocation, size_output_file(&s.file, s.size, preallocate)?);
+            start_positions.push(total_len);
+            total_len += s.size;
             streams.push(s.file.try_clone()?);
         }
-        let last_size = new_streams.last().unwrap().size;
-        let total_len = start_positions.last().copied().unwrap_or(0) + last_size;
-        Ok(Self { streams, start_positions, position: 0, index: 0, total_length: total_len })
+        Ok(Self { streams, allocation, start_positions, position: 0, index: 0, synced_index: 0, total_length: total_len })
     }
 
     pub fn length(&self) -> u64 { self.total_length }
-    pub fn get_position(&self) -> u64 { self.position }
 
     fn update_index(&mut self) -> std::io::Result<()> {
         if self.position == self.total_length {
-            self.index = self.streams.len() - 1;
+            self.index = self.streams.len().saturating_sub(1);
             return Ok(());
         }
         while self.index > 0 && self.position < self.start_positions[self.index] { self.index -= 1; }
@@ -271,6 +203,7 @@ impl CombinedStream {
 
 impl Read for CombinedStream {
     fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        if self.streams.is_empty() { return Ok(0); }
         let mut result = 0usize;
         let mut remaining = buffer.len();
         let mut offset = 0;
@@ -301,6 +234,7 @@ impl Read for CombinedStream {
 
 impl Write for CombinedStream {
     fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        if self.streams.is_empty() { return Ok(0); }
         let mut total = 0usize;
         let mut remaining = buffer.len();
         let mut offset = 0;
@@ -333,6 +267,18 @@ impl Write for CombinedStream {
     }
 }
 
+impl SyncWrite for CombinedStream {
+    fn sync_data(&mut self) -> std::io::Result<()> {
+        if self.streams.is_empty() { return Ok(()); }
+        for s in &mut self.streams[self.synced_index.min(self.index)..=self.index] {
+            s.flush()?;
+            s.sync_data()?;
+        }
+        self.synced_index = self.index;
+        Ok(())
+    }
+}
+
 impl Seek for CombinedStream {
     fn seek(&mut self, origin: SeekFrom) -> std::io::Result<u64> {
         let new_pos = match origin {
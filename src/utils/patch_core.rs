@@ -1,8 +1,18 @@
+use std::collections::{HashMap, HashSet};
 use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use crate::utils::checkpoint::{Checkpoint, Checkpointer, CountingReader, SyncWrite, TrackedWriter};
+use crate::utils::filter::FileFilter;
+use crate::utils::in_place::InPlacePlan;
+use crate::utils::journal::OutputFs;
+use crate::utils::paths::join_patch_path;
+use crate::utils::same_file::place_same_file;
+use crate::utils::symlinks::check_old_file;
 use crate::utils::parser::{read_long_7bit_from_slice, BinaryExtensions};
 use crate::utils::structs::{
-    CoverHeader, DirectoryReferencePair, HeaderInfo, PatchCore, PatchCoreImpl,
-    RleRefClip, SeekableRead,
+    check_cancel_flag, CoverHeader, DirectoryReferencePair, HeaderInfo, PatchCore, PatchCoreImpl,
+    RleRefClip, SameFileStrategy, SeekableRead,
 };
 
 const K_SIGN_TAG_BIT: u8  = 1;
@@ -17,29 +27,67 @@ impl PatchCore for PatchCoreImpl {
         self.dir_reference_pair = Some(pair);
     }
 
-    fn set_size_to_be_patched(&mut self, size_to_be_patched: i64, size_to_patch: i64) {
-        self.size_to_be_patched = size_to_be_patched;
-        self.size_patched = size_to_patch;
-    }
-
-    fn uncover_buffer_clips_stream(&mut self, clips: &mut [Box<dyn Read>], input_stream: &mut dyn SeekableRead, output_stream: &mut dyn Write, header_info: &HeaderInfo) {
-        self.write_cover_stream_to_output(clips, input_stream, output_stream, header_info.chunk_info.cover_count, header_info.chunk_info.cover_buf_size, header_info.new_data_size);
+    fn uncover_buffer_clips_stream(&mut self, clips: &mut [Box<dyn Read>], input_stream: &mut dyn SeekableRead, output_stream: &mut dyn SyncWrite, header_info: &HeaderInfo) -> std::io::Result<()> {
+        self.write_cover_stream_to_output(clips, input_stream, output_stream, header_info.chunk_info.cover_count, header_info.chunk_info.cover_buf_size, header_info.new_data_size)
     }
 }
 
 impl PatchCoreImpl {
-    pub fn new(size_to_be_patched: i64, input_path: std::path::PathBuf, output_path: std::path::PathBuf, write_bytes_callback: Option<Box<dyn FnMut(i64)>>) -> Self {
+    pub fn new(input_path: std::path::PathBuf, output_path: std::path::PathBuf, write_bytes_callback: Option<Box<dyn FnMut(i64)>>) -> Self {
         Self {
-            size_to_be_patched,
-            size_patched: 0,
             path_input: input_path,
             path_output: output_path,
             dir_reference_pair: None,
+            in_place: None,
+            output_fs: OutputFs::default(),
+            cancel_flag: None,
+            output_is_dir: true,
+            same_file_strategy: SameFileStrategy::Copy,
+            file_filter: None,
+            checkpointer: None,
+            resume_from: None,
             write_bytes_callback,
         }
     }
 
-    pub fn enumerate_cover_headers(mut cover_reader: &mut dyn Read, cover_size: i64, cover_count: i64) -> Vec<CoverHeader> {
+    pub fn set_in_place_plan(&mut self, plan: InPlacePlan) {
+        self.in_place = Some(plan);
+    }
+
+    pub fn set_output_is_dir(&mut self, output_is_dir: bool) {
+        self.output_is_dir = output_is_dir;
+    }
+
+    pub fn set_same_file_strategy(&mut self, strategy: SameFileStrategy) {
+        self.same_file_strategy = strategy;
+    }
+
+    pub fn set_file_filter(&mut self, filter: FileFilter) {
+        self.file_filter = Some(filter);
+    }
+
+    pub fn set_checkpointing(&mut self, checkpointer: Checkpointer, resume_from: Option<Checkpoint>) {
+        self.checkpointer = Some(checkpointer);
+        self.resume_from = resume_from;
+    }
+
+    pub fn set_output_fs(&mut self, output_fs: OutputFs, cancel_flag: Option<Arc<AtomicBool>>) {
+        self.output_fs = output_fs;
+        self.cancel_flag = cancel_flag;
+    }
+
+    fn is_wanted(&self, path: &str) -> bool {
+        self.file_filter.as_ref().is_none_or(|f| f.matches(path))
+    }
+
+    fn output_path(&self, rel: &str) -> std::path::PathBuf {
+        match &self.in_place {
+            Some(plan) => plan.target(rel),
+            None => join_patch_path(&self.path_output, rel),
+        }
+    }
+
+    pub fn enumerate_cover_headers(mut cover_reader: &mut dyn Read, cover_size: i64, cover_count: i64) -> std::io::Result<Vec<CoverHeader>> {
         let mut headers = Vec::with_capacity(cover_count as usize);
         let mut last_old_pos_back = 0i64;
         let mut last_new_pos_back = 0i64;
@@ -47,7 +95,7 @@ impl PatchCoreImpl {
 
         if cover_size < MAX_MEM_BUFFER_LEN {
             let mut buffer = vec![0u8; cover_size as usize];
-            cover_reader.read_exact(&mut buffer).expect("failed to read cover buffer");
+            cover_reader.read_exact(&mut buffer)?;
 
             let mut offset = 0usize;
             while remaining > 0 {
@@ -76,175 +124,221 @@ impl PatchCoreImpl {
                 let old_pos_back = last_old_pos_back;
                 let new_pos_back = last_new_pos_back;
                 let mut p_sign_buf = [0u8; 1];
-                cover_reader.read_exact(&mut p_sign_buf).expect("failed to read pSign from cover stream");
+                cover_reader.read_exact(&mut p_sign_buf)?;
                 let p_sign = p_sign_buf[0];
 
                 let inc_old_pos_sign = p_sign >> (8 - K_SIGN_TAG_BIT);
-                let inc_old_pos = cover_reader.read_long_7bit_tagged(K_SIGN_TAG_BIT, p_sign).expect("failed to read incOldPos");
+                let inc_old_pos = cover_reader.read_long_7bit_tagged(K_SIGN_TAG_BIT, p_sign)?;
                 let old_pos = if inc_old_pos_sign == 0 { old_pos_back + inc_old_pos } else { old_pos_back - inc_old_pos };
 
-                let copy_length  = cover_reader.read_long_7bit().expect("failed to read copyLength");
-                let cover_length = cover_reader.read_long_7bit().expect("failed to read coverLength");
+                let copy_length  = cover_reader.read_long_7bit()?;
+                let cover_length = cover_reader.read_long_7bit()?;
                 let new_pos_back = new_pos_back + copy_length;
                 last_old_pos_back = old_pos + cover_length;
                 last_new_pos_back = new_pos_back + cover_length;
                 headers.push(CoverHeader::new(old_pos, new_pos_back, cover_length, remaining));
             }
         }
-        headers
+        Ok(headers)
     }
 
-    fn write_cover_stream_to_output(&mut self, clips: &mut [Box<dyn Read>], input_stream: &mut dyn SeekableRead, output_stream: &mut dyn Write, cover_count: i64, cover_size: i64, new_data_size: i64) {
+    fn write_cover_stream_to_output(&mut self, clips: &mut [Box<dyn Read>], input_stream: &mut dyn SeekableRead, output_stream: &mut dyn SyncWrite, cover_count: i64, cover_size: i64, new_data_size: i64) -> std::io::Result<()> {
         let mut shared_buffer = vec![0u8; MAX_ARRAY_POOL_LEN];
         let mut cache = Cursor::new(Vec::<u8>::new());
 
-        self.run_copy_similar_files_routine();
+        self.run_copy_similar_files_routine()?;
         let mut new_pos_back = 0i64;
         let mut rle_struct = RleRefClip::default();
-        let (left, right) = clips.split_at_mut(2);
-        let headers = Self::enumerate_cover_headers(&mut *left[0], cover_size, cover_count);
+        let [cover_clip, ctrl_clip, code_clip, data_clip, ..] = clips else { return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "[PatchCore::write_cover_stream_to_output] Missing patch clips")); };
+        let headers = Self::enumerate_cover_headers(&mut **cover_clip, cover_size, cover_count)?;
+        let mut rle_ctrl = CountingReader::new(&mut **ctrl_clip);
+        let mut rle_code = CountingReader::new(&mut **code_clip);
+        let mut new_data = CountingReader::new(&mut **data_clip);
+
+        let mut first_cover = 0usize;
+        if let Some(cp) = self.resume_from.take() {
+            rle_ctrl.skip(cp.clip_offsets[0])?;
+            rle_code.skip(cp.clip_offsets[1])?;
+            new_data.skip(cp.clip_offsets[2])?;
+            new_pos_back = cp.new_pos as i64;
+            rle_struct = cp.rle;
+            first_cover = cp.cover_index as usize;
+        }
+        let mut out = TrackedWriter { inner: output_stream, checkpointer: self.checkpointer.as_mut(), callback: &mut self.write_bytes_callback };
 
-        for cover in &headers {
+        for (i, cover) in headers.iter().enumerate().skip(first_cover) {
+            check_cancel_flag(self.cancel_flag.as_deref())?;
             if new_pos_back < cover.new_pos {
                 let copy_length = cover.new_pos - new_pos_back;
-                Self::tbytes_copy_stream_from_old_clip(&mut cache, &mut *right[1], copy_length, &mut shared_buffer);
-                Self::tbytes_determine_rle_type(&mut rle_struct, &mut cache, copy_length, &mut shared_buffer, &mut *left[1], &mut *right[0]);
+                Self::tbytes_copy_stream_from_old_clip(&mut cache, &mut new_data, copy_length, &mut shared_buffer)?;
+                Self::tbytes_determine_rle_type(&mut rle_struct, &mut cache, copy_length, &mut shared_buffer, &mut rle_ctrl, &mut rle_code)?;
             }
 
-            Self::tbytes_copy_old_clip_patch(&mut cache, input_stream, &mut rle_struct, cover.old_pos, cover.cover_length, &mut shared_buffer, &mut *left[1], &mut *right[0]);
+            Self::tbytes_copy_old_clip_patch(&mut cache, input_stream, &mut rle_struct, cover.old_pos, cover.cover_length, &mut shared_buffer, &mut rle_ctrl, &mut rle_code)?;
             new_pos_back = cover.new_pos + cover.cover_length;
-            if cache.get_ref().len() > MAX_MEM_BUFFER_LIMIT || cover.next_cover_index == 0 { Self::write_cache_to_output(&mut cache, output_stream, &mut self.write_bytes_callback); }
+            let checkpoint_due = out.checkpointer.as_ref().is_some_and(|c| c.is_due(cache.get_ref().len() as u64));
+            if cache.get_ref().len() > MAX_MEM_BUFFER_LIMIT || cover.next_cover_index == 0 || checkpoint_due { Self::write_cache_to_output(&mut cache, &mut out)?; }
+            if checkpoint_due && let Some(checkpointer) = out.checkpointer.as_deref_mut() {
+                out.inner.sync_data()?;
+                checkpointer.save(Checkpoint { cover_index: i as u64 + 1, clip_offsets: [rle_ctrl.count, rle_code.count, new_data.count], rle: rle_struct, ..Default::default() })?;
+            }
         }
 
         if new_pos_back < new_data_size {
             let copy_length = new_data_size - new_pos_back;
-            Self::tbytes_copy_stream_from_old_clip(&mut cache, &mut *right[1], copy_length, &mut shared_buffer);
-            Self::tbytes_determine_rle_type(&mut rle_struct, &mut cache, copy_length, &mut shared_buffer, &mut *left[1], &mut *right[0]);
-            Self::write_cache_to_output(&mut cache, output_stream, &mut self.write_bytes_callback);
+            Self::tbytes_copy_stream_from_old_clip(&mut cache, &mut new_data, copy_length, &mut shared_buffer)?;
+            Self::tbytes_determine_rle_type(&mut rle_struct, &mut cache, copy_length, &mut shared_buffer, &mut rle_ctrl, &mut rle_code)?;
+            Self::write_cache_to_output(&mut cache, &mut out)?;
         }
+        Ok(())
     }
 
-    fn write_cache_to_output(cache: &mut Cursor<Vec<u8>>, output: &mut dyn Write, callback: &mut Option<Box<dyn FnMut(i64)>>) {
-        let data = cache.get_ref();
-        let written = data.len() as i64;
-        output.write_all(data).expect("failed to write cache to output");
+    fn write_cache_to_output(cache: &mut Cursor<Vec<u8>>, output: &mut dyn Write) -> std::io::Result<()> {
+        output.write_all(cache.get_ref())?;
         cache.get_mut().clear();
         cache.set_position(0);
-        if let Some(cb) = callback.as_mut() { cb(written); }
+        Ok(())
     }
 
-    fn tbytes_copy_old_clip_patch(out_cache: &mut Cursor<Vec<u8>>, input_stream: &mut dyn SeekableRead, rle_loader: &mut RleRefClip, old_pos: i64, add_length: i64, shared_buffer: &mut [u8], rle_ctrl_stream: &mut dyn Read, rle_code_stream: &mut dyn Read) {
+    #[allow(clippy::too_many_arguments)]
+    fn tbytes_copy_old_clip_patch(out_cache: &mut Cursor<Vec<u8>>, input_stream: &mut dyn SeekableRead, rle_loader: &mut RleRefClip, old_pos: i64, add_length: i64, shared_buffer: &mut [u8], rle_ctrl_stream: &mut dyn Read, rle_code_stream: &mut dyn Read) -> std::io::Result<()> {
         let last_pos = out_cache.position();
-        input_stream.seek(SeekFrom::Start(old_pos as u64)).expect("failed to seek input_stream");
-        Self::tbytes_copy_stream_inner(input_stream, out_cache, shared_buffer, add_length as usize);
-        out_cache.seek(SeekFrom::Start(last_pos)).expect("failed to restore cache position");
-        Self::tbytes_determine_rle_type(rle_loader, out_cache, add_length, shared_buffer, rle_ctrl_stream, rle_code_stream);
+        input_stream.seek(SeekFrom::Start(old_pos as u64))?;
+        Self::tbytes_copy_stream_inner(input_stream, out_cache, shared_buffer, add_length as usize)?;
+        out_cache.seek(SeekFrom::Start(last_pos))?;
+        Self::tbytes_determine_rle_type(rle_loader, out_cache, add_length, shared_buffer, rle_ctrl_stream, rle_code_stream)?;
+        Ok(())
     }
 
-    pub(crate) fn tbytes_copy_stream_from_old_clip(out_cache: &mut Cursor<Vec<u8>>, copy_reader: &mut dyn Read, copy_length: i64, shared_buffer: &mut [u8]) {
+    pub(crate) fn tbytes_copy_stream_from_old_clip(out_cache: &mut Cursor<Vec<u8>>, copy_reader: &mut dyn Read, copy_length: i64, shared_buffer: &mut [u8]) -> std::io::Result<()> {
         let last_pos = out_cache.position();
-        Self::tbytes_copy_stream_inner(copy_reader, out_cache, shared_buffer, copy_length as usize);
-        out_cache.seek(SeekFrom::Start(last_pos)).expect("failed to restore cache position");
+        Self::tbytes_copy_stream_inner(copy_reader, out_cache, shared_buffer, copy_length as usize)?;
+        out_cache.seek(SeekFrom::Start(last_pos))?;
+        Ok(())
     }
 
-    pub(crate) fn tbytes_copy_stream_inner(input: &mut dyn Read, output: &mut Cursor<Vec<u8>>, shared_buffer: &mut [u8], mut read_len: usize) {
+    pub(crate) fn tbytes_copy_stream_inner(input: &mut dyn Read, output: &mut Cursor<Vec<u8>>, shared_buffer: &mut [u8], mut read_len: usize) -> std::io::Result<()> {
         while read_len > 0 {
             let to_read = shared_buffer.len().min(read_len);
-            input.read_exact(&mut shared_buffer[..to_read]).expect("failed to read in tbytes_copy_stream_inner");
-            output.write_all(&shared_buffer[..to_read]).expect("failed to write in tbytes_copy_stream_inner");
+            input.read_exact(&mut shared_buffer[..to_read])?;
+            output.write_all(&shared_buffer[..to_read])?;
             read_len -= to_read;
         }
+        Ok(())
     }
 
-    fn tbytes_determine_rle_type(rle_loader: &mut RleRefClip, out_cache: &mut Cursor<Vec<u8>>, mut copy_length: i64, shared_buffer: &mut [u8], mut rle_ctrl_stream: &mut dyn Read, rle_code_stream: &mut dyn Read) {
-        Self::tbytes_set_rle(rle_loader, out_cache, &mut copy_length, shared_buffer, rle_code_stream);
+    fn tbytes_determine_rle_type(rle_loader: &mut RleRefClip, out_cache: &mut Cursor<Vec<u8>>, mut copy_length: i64, shared_buffer: &mut [u8], mut rle_ctrl_stream: &mut dyn Read, rle_code_stream: &mut dyn Read) -> std::io::Result<()> {
+        Self::tbytes_set_rle(rle_loader, out_cache, &mut copy_length, shared_buffer, rle_code_stream)?;
 
         while copy_length > 0 {
             let mut p_sign_buf = [0u8; 1];
-            rle_ctrl_stream.read_exact(&mut p_sign_buf).expect("failed to read pSign from rle_ctrl");
+            rle_ctrl_stream.read_exact(&mut p_sign_buf)?;
             let p_sign = p_sign_buf[0];
 
             let rle_type = p_sign >> (8 - K_BYTE_RLE_TYPE);
-            let mut length = rle_ctrl_stream.read_long_7bit_tagged(K_BYTE_RLE_TYPE, p_sign).expect("failed to read RLE length");
+            let mut length = rle_ctrl_stream.read_long_7bit_tagged(K_BYTE_RLE_TYPE, p_sign)?;
             length += 1;
 
             if rle_type == 3 {
                 rle_loader.mem_copy_length = length;
-                Self::tbytes_set_rle(rle_loader, out_cache, &mut copy_length, shared_buffer, rle_code_stream);
+                Self::tbytes_set_rle(rle_loader, out_cache, &mut copy_length, shared_buffer, rle_code_stream)?;
                 continue;
             }
 
             rle_loader.mem_set_length = length;
             if rle_type == 2 {
                 let mut val = [0u8; 1];
-                rle_code_stream.read_exact(&mut val).expect("failed to read RLE set value");
+                rle_code_stream.read_exact(&mut val)?;
                 rle_loader.mem_set_value = val[0];
-                Self::tbytes_set_rle(rle_loader, out_cache, &mut copy_length, shared_buffer, rle_code_stream);
+                Self::tbytes_set_rle(rle_loader, out_cache, &mut copy_length, shared_buffer, rle_code_stream)?;
                 continue;
             }
             rle_loader.mem_set_value = (0u8).wrapping_sub(rle_type);
-            Self::tbytes_set_rle(rle_loader, out_cache, &mut copy_length, shared_buffer, rle_code_stream);
+            Self::tbytes_set_rle(rle_loader, out_cache, &mut copy_length, shared_buffer, rle_code_stream)?;
         }
+        Ok(())
     }
 
-    fn tbytes_set_rle(rle_loader: &mut RleRefClip, out_cache: &mut Cursor<Vec<u8>>, copy_length: &mut i64, shared_buffer: &mut [u8], rle_code_stream: &mut dyn Read) {
-        Self::tbytes_set_rle_single(rle_loader, out_cache, copy_length, shared_buffer);
-        if rle_loader.mem_copy_length == 0 { return; }
+    fn tbytes_set_rle(rle_loader: &mut RleRefClip, out_cache: &mut Cursor<Vec<u8>>, copy_length: &mut i64, shared_buffer: &mut [u8], rle_code_stream: &mut dyn Read) -> std::io::Result<()> {
+        Self::tbytes_set_rle_single(rle_loader, out_cache, copy_length, shared_buffer)?;
+        if rle_loader.mem_copy_length == 0 { return Ok(()); }
 
         let decode_step = rle_loader.mem_copy_length.min(*copy_length) as usize;
         let last_pos = out_cache.position();
-        rle_code_stream.read_exact(&mut shared_buffer[..decode_step]).expect("failed to read from rle_code_stream");
-        out_cache.read_exact(&mut shared_buffer[MAX_ARRAY_POOL_SECOND_OFFSET..MAX_ARRAY_POOL_SECOND_OFFSET + decode_step]).expect("failed to read from out_cache");
-        out_cache.seek(SeekFrom::Start(last_pos)).expect("failed to restore cache pos");
-        Self::tbytes_set_rle_vector_software(rle_loader, out_cache, copy_length, decode_step, shared_buffer, 0, MAX_ARRAY_POOL_SECOND_OFFSET);
+        rle_code_stream.read_exact(&mut shared_buffer[..decode_step])?;
+        out_cache.read_exact(&mut shared_buffer[MAX_ARRAY_POOL_SECOND_OFFSET..MAX_ARRAY_POOL_SECOND_OFFSET + decode_step])?;
+        out_cache.seek(SeekFrom::Start(last_pos))?;
+        Self::tbytes_set_rle_vector_software(rle_loader, out_cache, copy_length, decode_step, shared_buffer, 0, MAX_ARRAY_POOL_SECOND_OFFSET)?;
+        Ok(())
     }
 
-    pub(crate) fn tbytes_set_rle_single(rle_loader: &mut RleRefClip, out_cache: &mut Cursor<Vec<u8>>, copy_length: &mut i64, shared_buffer: &mut [u8]) {
-        if rle_loader.mem_set_length == 0 { return; }
+    pub(crate) fn tbytes_set_rle_single(rle_loader: &mut RleRefClip, out_cache: &mut Cursor<Vec<u8>>, copy_length: &mut i64, shared_buffer: &mut [u8]) -> std::io::Result<()> {
+        if rle_loader.mem_set_length == 0 { return Ok(()); }
         let mem_set_step = rle_loader.mem_set_length.min(*copy_length);
 
         if rle_loader.mem_set_value != 0 {
             let last_pos = out_cache.position();
             let len = mem_set_step as usize;
-            out_cache.read_exact(&mut shared_buffer[..len]).expect("failed to read from cache for memset");
-            out_cache.seek(SeekFrom::Start(last_pos)).expect("failed to restore cache pos for memset");
+            out_cache.read_exact(&mut shared_buffer[..len])?;
+            out_cache.seek(SeekFrom::Start(last_pos))?;
             for i in (0..len).rev() { shared_buffer[i] = shared_buffer[i].wrapping_add(rle_loader.mem_set_value); }
-            out_cache.write_all(&shared_buffer[..len]).expect("failed to write memset result to cache");
+            out_cache.write_all(&shared_buffer[..len])?;
         } else {
             let cur = out_cache.position();
             out_cache.set_position(cur + mem_set_step as u64);
         }
         *copy_length -= mem_set_step;
         rle_loader.mem_set_length -= mem_set_step;
+        Ok(())
     }
 
-    fn tbytes_set_rle_vector_software(rle_loader: &mut RleRefClip, out_cache: &mut Cursor<Vec<u8>>, copy_length: &mut i64, decode_step: usize, buf: &mut [u8], rle_idx: usize, old_idx: usize) {
+    fn tbytes_set_rle_vector_software(rle_loader: &mut RleRefClip, out_cache: &mut Cursor<Vec<u8>>, copy_length: &mut i64, decode_step: usize, buf: &mut [u8], rle_idx: usize, old_idx: usize) -> std::io::Result<()> {
         for i in 0..decode_step { buf[rle_idx + i] = buf[rle_idx + i].wrapping_add(buf[old_idx + i]); }
-        out_cache.write_all(&buf[rle_idx..rle_idx + decode_step]).expect("failed to write RLE vector result");
+        out_cache.write_all(&buf[rle_idx..rle_idx + decode_step])?;
         rle_loader.mem_copy_length -= decode_step as i64;
         *copy_length -= decode_step as i64;
+        Ok(())
     }
 
     pub fn is_path_a_dir(input: &str) -> bool {
         input.is_empty() || input.ends_with('/')
     }
 
-    fn run_copy_similar_files_routine(&mut self) {
+    /// Like `is_path_a_dir`, except that `""` is the output file itself when the patch produces a single file.
+    fn is_dir_entry(&self, path: &str) -> bool {
+        if path.is_empty() { self.output_is_dir } else { path.ends_with('/') }
+    }
+
+    /// Creates directories and empty files and places identical files. The HDIFF13 path runs this itself
+    /// before decoding; a `PatchSF` inner diff has no core loop, so `PatchDir` calls it directly.
+    pub fn run_copy_similar_files_routine(&mut self) -> std::io::Result<()> {
         if let Some(pair) = self.dir_reference_pair.take() {
-            self.copy_old_similar_to_new_files(&pair);
+            self.copy_old_similar_to_new_files(&pair)?;
             self.dir_reference_pair = Some(pair);
         }
+        Ok(())
     }
 
-    fn copy_old_similar_to_new_files(&self, dir_data: &DirectoryReferencePair) {
+    fn copy_old_similar_to_new_files(&self, dir_data: &DirectoryReferencePair) -> std::io::Result<()> {
+        let movable = self.movable_old_files(dir_data);
+        let mut placed: Vec<(SameFileStrategy, usize)> = Vec::new();
         for pair in &dir_data.data_same_pair_list {
             let new_path = &dir_data.new_utf8_path_list[pair.new_index as usize];
-            if Self::is_path_a_dir(new_path) { continue; }
-            let old_full = self.path_input.join(&dir_data.old_utf8_path_list[pair.old_index as usize]);
-            let new_full = self.path_output.join(new_path);
-            if let Some(parent) = new_full.parent() { let _ = std::fs::create_dir_all(parent); }
-            let _ = std::fs::copy(&old_full, &new_full);
+            if self.is_dir_entry(new_path) || !self.is_wanted(new_path) { continue; }
+            let old_path = &dir_data.old_utf8_path_list[pair.old_index as usize];
+            // In-place: an identical file that keeps its path is already where it belongs.
+            if self.in_place.is_some() && old_path == new_path { continue; }
+            let old_full = join_patch_path(&self.path_input, old_path);
+            let new_full = self.output_path(new_path);
+            check_old_file(&self.path_input, &old_full, self.output_fs.symlink_policy())?;
+            let used = place_same_file(&self.output_fs, self.same_file_strategy, &old_full, &new_full, movable.contains(&pair.old_index))?;
+            match placed.iter_mut().find(|(s, _)| *s == used) {
+                Some((_, count)) => *count += 1,
+                None => placed.push((used, 1)),
+            }
         }
+        #[cfg(debug_assertions)]
+        for (strategy, count) in &placed { println!("[PatchCore::copy_old_similar_to_new_files] {} identical files placed with {:?}", count, strategy); }
 
         let new_ref_count  = dir_data.new_ref_list.len();
         let same_pair_count = dir_data.data_same_pair_list.len();
@@ -259,20 +353,32 @@ impl PatchCoreImpl {
             let is_same_pair = cur_same_pair_index < same_pair_count && cur_path_index == dir_data.data_same_pair_list[cur_same_pair_index].new_index as usize;
 
             if is_new_ref {
-                let path = &dir_data.new_utf8_path_list[dir_data.new_ref_list[cur_new_ref_index] as usize];
-                if Self::is_path_a_dir(path) { cur_path_index += 1; }
+                // Reference outputs are created and sized up front by the caller.
                 cur_new_ref_index += 1;
+                cur_path_index += 1;
             } else if is_same_pair {
                 cur_same_pair_index += 1;
                 cur_path_index += 1;
             } else {
                 let path = &dir_data.new_utf8_path_list[cur_path_index];
-                let combined = self.path_output.join(path);
-                if !path.is_empty() {
-                    if Self::is_path_a_dir(path) { let _ = std::fs::create_dir_all(&combined); } else if !combined.exists() { let _ = std::fs::File::create(&combined); }
+                let combined = self.output_path(path);
+                if (!path.is_empty() || !self.output_is_dir) && self.is_wanted(path) {
+                    // In-place: a leftover old file at this path must still end up empty.
+                    if self.is_dir_entry(path) { self.output_fs.create_dir_all(&combined)?; } else if self.in_place.is_some() || !combined.exists() { self.output_fs.create_file(&combined)?; }
                 }
                 cur_path_index += 1;
             }
         }
+        Ok(())
+    }
+
+    /// Old files that may be moved away: only in place, and only when one is neither patch input nor the source of
+    /// another identical pair.
+    fn movable_old_files(&self, dir_data: &DirectoryReferencePair) -> HashSet<i64> {
+        if self.in_place.is_none() { return HashSet::new(); }
+        let old_refs: HashSet<i64> = dir_data.old_ref_list.iter().copied().collect();
+        let mut uses: HashMap<i64, usize> = HashMap::new();
+        for pair in &dir_data.data_same_pair_list { *uses.entry(pair.old_index).or_default() += 1; }
+        uses.into_iter().filter(|&(old_index, count)| count == 1 && !old_refs.contains(&old_index)).map(|(old_index, _)| old_index).collect()
     }
 }
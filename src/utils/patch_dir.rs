@@ -1,30 +1,74 @@
-use std::fs::{self, File};
+use std::collections::HashSet;
+use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
+use crate::utils::checkpoint::{clear_checkpoint, start_checkpointing, verify_written_prefix, SyncWrite};
 use crate::utils::compression_utils::get_clip_stream;
+use crate::utils::disk_space::{ensure_free_space, existing_file_size, OutputAllocation};
+use crate::utils::filter::{needed_old_segments, FileFilter, SkippingWriter, SparseReader};
 use crate::utils::header::Header;
+use crate::utils::in_place::{is_same_dir, InPlacePlan};
+use crate::utils::journal::{Journal, OutputFs};
+use crate::utils::metadata::{apply_output_metadata, match_old_paths, wants_output_metadata, OutputMetadata};
 use crate::utils::parser::BinaryExtensions;
+use crate::utils::patch_sf::PatchSF;
+use crate::utils::paths::{from_windows_path, join_patch_path, normalize_patch_paths, PathIndex};
+use crate::utils::symlinks::check_old_file;
 use crate::utils::structs::PatchCoreImpl;
 use crate::utils::structs::{
     CombinedStream, CompressionMode, DataReferenceInfo, DirectoryReferencePair,
-    HeaderInfo, NewFileCombinedStream, PatchCore,
+    HeaderInfo, NewFileCombinedStream, PatchCore, PatchOptions, SeekableRead, SymlinkPolicy,
 };
 
 pub(crate) struct PatchDir {
     header_info: HeaderInfo,
     reference_info: DataReferenceInfo,
     patch_path: String,
+    options: PatchOptions,
+    resume: bool,
+    // From the outer HDIFF19 header; `header_info` is overwritten by the inner diff header later on.
+    input_is_dir: bool,
+    output_is_dir: bool,
+    allocation: Option<OutputAllocation>,
 }
 
 impl PatchDir {
-    pub fn new(header_info: HeaderInfo, reference_info: DataReferenceInfo, patch_path: String) -> Self {
-        Self { header_info, reference_info, patch_path }
+    pub fn new(header_info: HeaderInfo, reference_info: DataReferenceInfo, patch_path: String, options: PatchOptions) -> Self {
+        let (input_is_dir, output_is_dir) = (header_info.is_input_dir, header_info.is_output_dir);
+        Self { header_info, reference_info, patch_path, options, resume: false, input_is_dir, output_is_dir, allocation: None }
+    }
+
+    /// Continue from the checkpoint in `PatchOptions::checkpoint_path` instead of starting over.
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
+
+    /// How the new files were sized, once `patch` got that far. `None` if there were none.
+    pub fn output_allocation(&self) -> Option<OutputAllocation> {
+        self.allocation
     }
 
     pub fn patch(&mut self, input: &str, output: &str, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> std::io::Result<()> {
-        let base_input  = PathBuf::from(input);
         let base_output = PathBuf::from(output);
+        // A single output file keeps its journal in the folder it lives in.
+        let journal_root = match base_output.parent() {
+            Some(parent) if !self.output_is_dir => parent.to_path_buf(),
+            _ => base_output.clone(),
+        };
+        Journal::recover(&journal_root)?;
+        let output_fs = if self.options.transactional { OutputFs::transactional(&journal_root, self.options.symlink_policy)? } else { OutputFs::new(&journal_root, self.options.symlink_policy) };
+
+        match self.run(PathBuf::from(input), base_output, &output_fs, write_bytes_cb) {
+            Ok(()) => output_fs.commit(),
+            Err(e) => match output_fs.rollback() {
+                Ok(()) => Err(e),
+                Err(re) => Err(std::io::Error::new(e.kind(), format!("{} (rollback failed: {})", e, re))),
+            },
+        }
+    }
+
+    fn run(&mut self, base_input: PathBuf, base_output: PathBuf, output_fs: &OutputFs, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> std::io::Result<()> {
         let padding: u64 = match self.header_info.comp_mode { CompressionMode::Zlib => 1, _ => 0};
 
         let ri = &self.reference_info;
@@ -33,32 +77,231 @@ impl PatchDir {
 
         let head_file = File::open(&self.patch_path)?;
         let (mut head_stream, _) = get_clip_stream(head_file, self.header_info.comp_mode, ri.head_data_offset as u64 + header_padding, ri.head_data_size as u64, head_comp_size, true)?;
-        let dir_data = self.init_dir_patcher(&mut *head_stream)?;
+        let mut dir_data = self.init_dir_patcher(&mut *head_stream)?;
+        if self.options.windows_paths { Self::resolve_windows_paths(&mut dir_data, &base_input)?; }
+
+        let both_dirs = self.input_is_dir && self.output_is_dir;
+        let in_place = (both_dirs && is_same_dir(&base_input, &base_output)).then(|| Self::plan_in_place(&dir_data, &base_output));
+        if !self.options.skip_disk_space_check { ensure_free_space(&base_output, self.required_space(&dir_data, &base_input, &base_output, in_place.as_ref(), output_fs))?; }
+        let outputs = self.output_metadata(&dir_data, &base_input, &base_output)?;
+        if self.options.file_filter.is_some() { Self::check_missing_old_files(&dir_data, &base_input)?; }
+        let result = self.patch_streams(dir_data, base_input, base_output, output_fs, in_place.as_ref(), write_bytes_cb);
+        let dir_data = match result {
+            Ok(dir_data) => dir_data,
+            Err(e) => {
+                // Staged outputs are what a checkpoint points into, keep them around for `resume`.
+                if let Some(plan) = &in_place && self.options.checkpoint_path.is_none() { plan.discard(); }
+                return Err(e);
+            }
+        };
 
-        let old_files = Self::get_ref_old_streams(&dir_data, &base_input)?;
-        let new_files = Self::get_ref_new_streams(&dir_data, &base_output)?;
+        if let Some(plan) = &in_place {
+            plan.commit(output_fs)?;
+            // A selective patch leaves the rest of the tree alone.
+            if self.options.file_filter.is_none() { plan.remove_obsolete(output_fs, dir_data.old_utf8_path_list.iter().map(String::as_str), dir_data.new_utf8_path_list.iter().map(String::as_str))?; }
+        }
+        apply_output_metadata(&outputs, &self.options)
+    }
 
-        if self.header_info.is_single_compressed_diff { return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "[PatchDir::patch] Single-compressed dir patches are not supported")); }
+    /// Bytes the outputs need beyond what truncating existing files gives back. Staged in-place outputs and journaled
+    /// files keep their old copy until the very end, so those give nothing back.
+    fn required_space(&self, dir_data: &DirectoryReferencePair, base_input: &Path, base_output: &Path, in_place: Option<&InPlacePlan>, output_fs: &OutputFs) -> u64 {
+        let filter = self.options.file_filter.as_ref();
+        let wanted = |path: &str| filter.is_none_or(|f| f.matches(path));
+        let reused = |path: &str| match in_place {
+            _ if output_fs.is_transactional() => 0,
+            Some(plan) if plan.is_staged(path) => 0,
+            _ => existing_file_size(&join_patch_path(base_output, path)),
+        };
 
+        let mut needed = 0u64;
+        for (i, &ref_idx) in dir_data.new_ref_list.iter().enumerate() {
+            let path = &dir_data.new_utf8_path_list[ref_idx as usize];
+            if wanted(path) { needed += (dir_data.new_ref_size_list[i] as u64).saturating_sub(reused(path)); }
+        }
+        for pair in &dir_data.data_same_pair_list {
+            let new_path = &dir_data.new_utf8_path_list[pair.new_index as usize];
+            let old_path = &dir_data.old_utf8_path_list[pair.old_index as usize];
+            if !wanted(new_path) || (in_place.is_some() && old_path == new_path) { continue; }
+            needed += existing_file_size(&join_patch_path(base_input, old_path)).saturating_sub(reused(new_path));
+        }
+        needed
+    }
+
+    /// Collected before anything is written, an in-place patch replaces the old files the metadata comes from.
+    fn output_metadata(&self, dir_data: &DirectoryReferencePair, base_input: &Path, base_output: &Path) -> std::io::Result<Vec<OutputMetadata>> {
+        if !wants_output_metadata(&self.options) && dir_data.new_execute_list.is_empty() { return Ok(Vec::new()); }
+        let executables: HashSet<i64> = dir_data.new_execute_list.iter().copied().collect();
+        let same_pairs = dir_data.data_same_pair_list.iter().map(|p| (p.new_index as usize, p.old_index as usize));
+        let matched = match_old_paths(&dir_data.old_utf8_path_list, &dir_data.new_utf8_path_list, same_pairs, self.options.windows_paths);
+        let filter = self.options.file_filter.as_ref();
+        let mut outputs = Vec::new();
+        for (i, path) in dir_data.new_utf8_path_list.iter().enumerate() {
+            if (path.is_empty() && self.output_is_dir) || path.ends_with('/') || filter.is_some_and(|f| !f.matches(path)) { continue; }
+            let old_path = matched[i].filter(|old| !old.is_empty() || !self.input_is_dir).map(|old| join_patch_path(base_input, old));
+            let executable = executables.contains(&(i as i64));
+            outputs.push(OutputMetadata::new(join_patch_path(base_output, path), old_path.as_deref(), executable, &self.options)?);
+        }
+        Ok(outputs)
+    }
+
+    fn patch_streams(&mut self, dir_data: DirectoryReferencePair, base_input: PathBuf, base_output: PathBuf, output_fs: &OutputFs, in_place: Option<&InPlacePlan>, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> std::io::Result<DirectoryReferencePair> {
         let mut patch_for_inner = File::open(&self.patch_path)?;
         patch_for_inner.seek(SeekFrom::Start(self.reference_info.hdiff_data_offset as u64))?;
         let mut dummy_ref = DataReferenceInfo::default();
         Header::try_parse_header_info(&mut patch_for_inner, "", &mut self.header_info, &mut dummy_ref).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))?;
 
-        // Padding may have changed if inner patch uses a different comp mode.
-        let padding: u64 = match self.header_info.comp_mode { CompressionMode::Zlib => 1, _ => 0 };
+        if let Some(filter) = self.options.file_filter.clone() {
+            if self.header_info.is_single_compressed_diff { return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "[PatchDir::patch] File filters are not supported for HDIFFSF20 dir patches")); }
+            return self.patch_filtered_streams(dir_data, base_input, base_output, output_fs, in_place, write_bytes_cb, &filter, self.padding());
+        }
+
+        let old_files = Self::get_ref_old_streams(&dir_data, &base_input, self.options.symlink_policy)?;
+        let new_files = Self::get_ref_new_streams(&dir_data, &base_output, output_fs, in_place, self.resume, None)?;
         let mut old_combined = CombinedStream::new(old_files)?;
-        let mut new_combined = CombinedStream::from_new_files(new_files)?;
+        let mut new_combined = CombinedStream::from_new_files(new_files, self.options.preallocate)?;
+        self.allocation = new_combined.allocation;
 
         if old_combined.length() as i64 != self.header_info.old_data_size { return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("[PatchDir::patch] Old size mismatch: expected {} bytes, got {} bytes", self.header_info.old_data_size, old_combined.length()))); }
-        let mut core = PatchCoreImpl::new(self.header_info.new_data_size, base_input, base_output, write_bytes_cb);
-        core.set_directory_reference_pair(dir_data);
-        self.start_patch_routine(&mut old_combined, &mut new_combined, &mut core, padding)?;
+
+        let checkpointing = start_checkpointing(&self.options, &self.patch_path, new_combined.length(), self.resume)?;
+        if let Some(cp) = checkpointing.as_ref().and_then(|(_, cp)| cp.as_ref()) {
+            verify_written_prefix(&mut new_combined, cp)?;
+            new_combined.seek(SeekFrom::Start(cp.new_pos))?;
+        }
+
+        let mut core = if self.header_info.is_single_compressed_diff {
+            // HDIFFSF20 (`hdiffz -SD`): PatchSF's step loop runs over the combined streams, the core only lays out the tree.
+            let mut core = self.new_core(dir_data, base_input, base_output, output_fs, in_place, None);
+            core.run_copy_similar_files_routine()?;
+            PatchSF::new(self.header_info.clone()).with_checkpointing(checkpointing).with_cancel_flag(self.options.cancel_flag.clone()).patch(&mut old_combined, &mut new_combined, &self.patch_path, write_bytes_cb)?;
+            core
+        } else {
+            let mut core = self.new_core(dir_data, base_input, base_output, output_fs, in_place, write_bytes_cb);
+            if let Some((checkpointer, resume_from)) = checkpointing { core.set_checkpointing(checkpointer, resume_from); }
+            self.start_patch_routine(&mut old_combined, &mut new_combined, &mut core, self.padding())?;
+            core
+        };
         new_combined.flush()?;
-        Ok(())
+        if let Some(path) = &self.options.checkpoint_path { clear_checkpoint(path)?; }
+        Ok(core.dir_reference_pair.take().unwrap_or_default())
+    }
+
+    // Padding may have changed if inner patch uses a different comp mode.
+    fn padding(&self) -> u64 {
+        match self.header_info.comp_mode { CompressionMode::Zlib => 1, _ => 0 }
+    }
+
+    /// Same as `patch_streams`, but only new files accepted by `filter` are written. The cover and diff streams are
+    /// still decoded in full; old files only have to be present when a kept output actually copies from them.
+    #[allow(clippy::too_many_arguments)]
+    fn patch_filtered_streams(&mut self, dir_data: DirectoryReferencePair, base_input: PathBuf, base_output: PathBuf, output_fs: &OutputFs, in_place: Option<&InPlacePlan>, write_bytes_cb: Option<Box<dyn FnMut(i64)>>, filter: &FileFilter, padding: u64) -> std::io::Result<DirectoryReferencePair> {
+        if self.options.checkpoint_path.is_some() { return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "[PatchDir::patch] Checkpoints cannot be combined with a file filter")); }
+
+        let mut kept_new = Vec::new();
+        let mut start = 0u64;
+        for (i, &ref_idx) in dir_data.new_ref_list.iter().enumerate() {
+            let size = dir_data.new_ref_size_list[i] as u64;
+            if filter.matches(&dir_data.new_utf8_path_list[ref_idx as usize]) { kept_new.push((start, size)); }
+            start += size;
+        }
+
+        let mut clips = self.open_clips(padding)?;
+        let ci = &self.header_info.chunk_info;
+        let covers = PatchCoreImpl::enumerate_cover_headers(&mut *clips[0], ci.cover_buf_size, ci.cover_count)?;
+        let mut old_stream = self.open_sparse_old_streams(&dir_data, &base_input, &kept_new, covers.iter().map(|c| (c.old_pos as u64, c.new_pos as u64, c.cover_length as u64)))?;
+
+        let new_files = Self::get_ref_new_streams(&dir_data, &base_output, output_fs, in_place, false, Some(filter))?;
+        let mut new_combined = CombinedStream::from_new_files(new_files, self.options.preallocate)?;
+        self.allocation = new_combined.allocation;
+        let mut new_stream = SkippingWriter::new(&mut new_combined, kept_new);
+
+        let mut core = self.new_core(dir_data, base_input, base_output, output_fs, in_place, write_bytes_cb);
+        core.set_file_filter(filter.clone());
+        self.start_patch_routine(&mut old_stream, &mut new_stream, &mut core, padding)?;
+        new_stream.flush()?;
+        Ok(core.dir_reference_pair.take().unwrap_or_default())
+    }
+
+    /// HDIFF19 does not store old file sizes, so a filtered patch can only do without old reference files that sit
+    /// next to each other in the reference order: their combined size is whatever is left. Checked before anything is
+    /// written, as any other gap cannot be laid out.
+    fn check_missing_old_files(dir_data: &DirectoryReferencePair, base_input: &Path) -> std::io::Result<()> {
+        let missing: Vec<(usize, &str)> = dir_data.old_ref_list.iter().enumerate().map(|(i, &ref_idx)| (i, dir_data.old_utf8_path_list[ref_idx as usize].as_str())).filter(|(_, path)| !join_patch_path(base_input, path).exists()).collect();
+        let Some(w) = missing.windows(2).find(|w| w[1].0 != w[0].0 + 1) else { return Ok(()); };
+        let between = &dir_data.old_utf8_path_list[dir_data.old_ref_list[w[0].0 + 1] as usize];
+        let names = missing.iter().map(|m| m.1).collect::<Vec<_>>().join(", ");
+        Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("[PatchDir::patch] Missing old files must be next to each other in the patch's old file order, as HDIFF19 does not store their sizes, but {} sits between {} and {}. Missing: {}", between, w[0].1, w[1].1, names)))
+    }
+
+    /// Old reference files for a filtered patch, with the missing ones collapsed into one zero-filled gap.
+    fn open_sparse_old_streams(&self, dir_data: &DirectoryReferencePair, base_input: &Path, kept_new: &[(u64, u64)], covers: impl IntoIterator<Item = (u64, u64, u64)>) -> std::io::Result<SparseReader> {
+        let mut files = Vec::with_capacity(dir_data.old_ref_list.len());
+        let mut missing = Vec::new();
+        for &ref_idx in &dir_data.old_ref_list {
+            let path = &dir_data.old_utf8_path_list[ref_idx as usize];
+            let full_path = join_patch_path(base_input, path);
+            check_old_file(base_input, &full_path, self.options.symlink_policy)?;
+            match File::open(full_path) {
+                Ok(file) => files.push(Some((file.metadata()?.len(), file))),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => { missing.push(path.as_str()); files.push(None); }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Again, in case old files went missing since the check before patching.
+        Self::check_missing_old_files(dir_data, base_input)?;
+        let missing_names = || missing.join(", ");
+        let present: u64 = files.iter().flatten().map(|f| f.0).sum();
+        let gap = (self.header_info.old_data_size as u64).checked_sub(present).filter(|&gap| !missing.is_empty() || gap == 0);
+        let Some(gap) = gap else { return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("[PatchDir::patch] Old size mismatch: expected {} bytes, got {} bytes", self.header_info.old_data_size, present))); };
+
+        // The missing run collapses into a single zero-filled segment.
+        let mut segments: Vec<(u64, Option<File>)> = Vec::with_capacity(files.len());
+        let mut gap_index = None;
+        for file in files {
+            match file {
+                Some((len, file)) => segments.push((len, Some(file))),
+                None if gap_index.is_none() => { gap_index = Some(segments.len()); segments.push((gap, None)); }
+                None => {}
+            }
+        }
+
+        let mut layout = Vec::with_capacity(segments.len());
+        let mut start = 0u64;
+        for (len, _) in &segments { layout.push((start, *len)); start += len; }
+        if let Some(i) = gap_index && needed_old_segments(covers, kept_new, &layout)[i] { return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("[PatchDir::patch] Selected files need old data from missing files: {}", missing_names()))); }
+        Ok(SparseReader::new(segments))
+    }
+
+    fn new_core(&self, dir_data: DirectoryReferencePair, base_input: PathBuf, base_output: PathBuf, output_fs: &OutputFs, in_place: Option<&InPlacePlan>, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> PatchCoreImpl {
+        let mut core = PatchCoreImpl::new(base_input, base_output, write_bytes_cb);
+        core.set_directory_reference_pair(dir_data);
+        core.set_output_fs(output_fs.clone(), self.options.cancel_flag.clone());
+        core.set_output_is_dir(self.output_is_dir);
+        core.set_same_file_strategy(self.options.same_file_strategy);
+        if let Some(plan) = in_place { core.set_in_place_plan(plan.clone()); }
+        core
+    }
+
+    fn resolve_windows_paths(dir_data: &mut DirectoryReferencePair, base_input: &Path) -> std::io::Result<()> {
+        for path in dir_data.old_utf8_path_list.iter_mut().chain(dir_data.new_utf8_path_list.iter_mut()) { *path = from_windows_path(path)?; }
+        PathIndex::new(base_input).resolve_all(&mut dir_data.old_utf8_path_list)
+    }
+
+    /// Outputs that land on an old file still read by the patch (reference data or identical-file source) get staged.
+    fn plan_in_place(dir_data: &DirectoryReferencePair, base_output: &Path) -> InPlacePlan {
+        let referenced = dir_data.old_ref_list.iter().map(|&i| i as usize).chain(dir_data.data_same_pair_list.iter().map(|p| p.old_index as usize)).map(|i| dir_data.old_utf8_path_list[i].as_str());
+        let outputs = dir_data.new_utf8_path_list.iter().map(String::as_str).filter(|p| !PatchCoreImpl::is_path_a_dir(p));
+        InPlacePlan::new(base_output, referenced, outputs)
+    }
+
+    fn start_patch_routine(&self, old_stream: &mut dyn SeekableRead, new_stream: &mut dyn SyncWrite, core: &mut PatchCoreImpl, padding: u64) -> std::io::Result<()> {
+        let mut clips = self.open_clips(padding)?;
+        core.uncover_buffer_clips_stream(&mut clips, old_stream, new_stream, &self.header_info)
     }
 
-    fn start_patch_routine(&self, old_stream: &mut CombinedStream, new_stream: &mut CombinedStream, core: &mut PatchCoreImpl, padding: u64) -> std::io::Result<()> {
+    fn open_clips(&self, padding: u64) -> std::io::Result<[Box<dyn Read>; 4]> {
         let hi = &self.header_info;
         let ci = &hi.chunk_info;
 
@@ -89,16 +332,17 @@ impl PatchDir {
         let new_data_diff_padding = if ci.compress_new_data_diff_size > 0 { padding } else { 0 };
         let comp_diff_size = (ci.compress_new_data_diff_size as u64).saturating_sub(padding);
         let (clip3, _) = get_clip_stream(f3, hi.comp_mode, offset + new_data_diff_padding, ci.new_data_diff_size as u64, comp_diff_size, false)?;
-        let mut clips: [Box<dyn Read>; 4] = [clip0, clip1, clip2, clip3];
-        core.uncover_buffer_clips_stream(&mut clips, old_stream, new_stream, hi);
-        Ok(())
+        Ok([clip0, clip1, clip2, clip3])
     }
 
     fn init_dir_patcher(&self, mut reader: &mut dyn Read) -> std::io::Result<DirectoryReferencePair> {
         let ri = &self.reference_info;
         // Old and new path lists (null-separated strings packed into a fixed-size buffer).
-        let old_utf8_path_list = reader.get_paths_from_stream(ri.input_sum_size as usize, ri.input_dir_count as usize)?;
-        let new_utf8_path_list = reader.get_paths_from_stream(ri.output_sum_size as usize, ri.output_dir_count as usize)?;
+        // Normalised right here so nothing downstream ever joins an unchecked path onto a root.
+        let mut old_utf8_path_list = reader.get_paths_from_stream(ri.input_sum_size as usize, ri.input_dir_count as usize)?;
+        let mut new_utf8_path_list = reader.get_paths_from_stream(ri.output_sum_size as usize, ri.output_dir_count as usize)?;
+        normalize_patch_paths(&mut old_utf8_path_list)?;
+        normalize_patch_paths(&mut new_utf8_path_list)?;
         // Reference index lists (delta-encoded, validated against path count).
         let old_ref_list = reader.get_longs_from_stream(ri.input_ref_file_count as usize, Some(ri.input_dir_count))?;
         let new_ref_list = reader.get_longs_from_stream(ri.output_ref_file_count as usize, Some(ri.output_dir_count))?;
@@ -119,23 +363,24 @@ impl PatchDir {
         })
     }
 
-    fn get_ref_old_streams(dir_data: &DirectoryReferencePair, base_input: &Path) -> std::io::Result<Vec<File>> {
+    fn get_ref_old_streams(dir_data: &DirectoryReferencePair, base_input: &Path, symlink_policy: SymlinkPolicy) -> std::io::Result<Vec<File>> {
         let mut streams = Vec::with_capacity(dir_data.old_ref_list.len());
         for &ref_idx in &dir_data.old_ref_list {
             let path     = &dir_data.old_utf8_path_list[ref_idx as usize];
-            let full_path = base_input.join(path);
+            let full_path = join_patch_path(base_input, path);
+            check_old_file(base_input, &full_path, symlink_policy)?;
             streams.push(File::open(&full_path)?);
         }
         Ok(streams)
     }
 
-    fn get_ref_new_streams(dir_data: &DirectoryReferencePair, base_output: &Path) -> std::io::Result<Vec<NewFileCombinedStream>> {
+    fn get_ref_new_streams(dir_data: &DirectoryReferencePair, base_output: &Path, output_fs: &OutputFs, in_place: Option<&InPlacePlan>, resume: bool, filter: Option<&FileFilter>) -> std::io::Result<Vec<NewFileCombinedStream>> {
         let mut streams = Vec::with_capacity(dir_data.new_ref_list.len());
         for (i, &ref_idx) in dir_data.new_ref_list.iter().enumerate() {
             let path      = &dir_data.new_utf8_path_list[ref_idx as usize];
-            let full_path  = base_output.join(path);
-            if let Some(parent) = full_path.parent() { fs::create_dir_all(parent)?; }
-            let file = File::options().read(true).write(true).create(true).truncate(true).open(&full_path)?;
+            if filter.is_some_and(|f| !f.matches(path)) { continue; }
+            let full_path  = match in_place { Some(plan) => plan.target(path), None => join_patch_path(base_output, path) };
+            let file = if resume { output_fs.reopen_file(&full_path)? } else { output_fs.create_file(&full_path)? };
             streams.push(NewFileCombinedStream { file, size: dir_data.new_ref_size_list[i] as u64, });
         }
         Ok(streams)
@@ -1,11 +1,31 @@
-use std::fs::{self, File};
+use std::cell::Cell;
+#[cfg(feature = "kuro-index")]
+use std::cell::RefCell;
+#[cfg(feature = "kuro-index")]
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
 
+use crate::utils::atomic_file::file_identity;
+use crate::utils::checksum::{verify_crc32, ChecksumSection};
+use crate::utils::checkpoint::{clear_checkpoint, start_checkpointing, verify_written_prefix, Checkpoint, CountingReader, TrackedWriter};
 use crate::utils::compression_utils::get_clip_stream;
+use crate::utils::disk_space::{ensure_free_space, existing_file_size, size_output_file, OutputAllocation};
+use crate::utils::filter::{needed_old_segments, FileFilter, SkippingWriter, SparseReader};
+use crate::utils::in_place::{is_same_dir, InPlacePlan};
+use crate::utils::journal::{Journal, OutputFs};
+#[cfg(feature = "kuro-index")]
+use crate::utils::kuro_index::{Md5Writer, OutputMd5s};
+use crate::utils::metadata::{apply_output_metadata, match_old_paths, wants_output_metadata, OutputMetadata};
 use crate::utils::parser::BinaryExtensions;
-use crate::utils::structs::{CombinedStream, CompressionMode, NewFileCombinedStream};
+use crate::utils::paths::{from_windows_path, into_patch_error, normalize_patch_paths, PathIndex};
+use crate::utils::symlinks::check_old_file;
+use crate::utils::structs::{check_cancel_flag, ChecksumMode, CombinedStream, CompressionMode, NewFileCombinedStream, PatchOptions, SeekableRead};
 
 /*
 WARNING: This shit is extremely cursed and is modification of standard HDiff format, it is not something you should use it can break and go to fuckshit anytime...
@@ -16,61 +36,322 @@ HERE BE DRAGONS you are warned!!!
 
 pub struct KrPatchDir {
     patch_path: String,
+    options: PatchOptions,
+    resume: bool,
+    recover_journal: bool,
+    allocation: Cell<Option<OutputAllocation>>,
+    #[cfg(feature = "kuro-index")]
+    hash_outputs: bool,
+    #[cfg(feature = "kuro-index")]
+    output_md5s: RefCell<Option<HashMap<String, [u8; 16]>>>,
 }
 
 impl KrPatchDir {
-    pub fn new(patch_path: String) -> Self {
-        Self { patch_path }
+    pub fn new(patch_path: String, options: PatchOptions) -> Self {
+        Self {
+            patch_path,
+            options,
+            resume: false,
+            recover_journal: true,
+            allocation: Cell::new(None),
+            #[cfg(feature = "kuro-index")]
+            hash_outputs: false,
+            #[cfg(feature = "kuro-index")]
+            output_md5s: RefCell::new(None),
+        }
+    }
+
+    /// Hash every new file while it is written, for checking the output against a resource index afterwards.
+    #[cfg(feature = "kuro-index")]
+    pub fn set_hash_outputs(&mut self, hash_outputs: bool) {
+        self.hash_outputs = hash_outputs;
+    }
+
+    /// MD5s taken during the last `patch`. `None` when nothing was hashed: resumed and filtered patches are not.
+    #[cfg(feature = "kuro-index")]
+    pub fn take_output_md5s(&self) -> Option<HashMap<String, [u8; 16]>> {
+        self.output_md5s.borrow_mut().take()
+    }
+
+    /// Continue from the checkpoint in `PatchOptions::checkpoint_path` instead of starting over.
+    pub fn set_resume(&mut self, resume: bool) {
+        self.resume = resume;
+    }
+
+    /// How the new files were sized, once `patch` got that far. `None` if there were none.
+    pub fn output_allocation(&self) -> Option<OutputAllocation> {
+        self.allocation.get()
+    }
+
+    /// Whether `patch` rolls back a journal a crash left in the output first. `KrDiffBatch` does that once for all
+    /// groups instead.
+    pub fn set_recover_journal(&mut self, recover_journal: bool) {
+        self.recover_journal = recover_journal;
     }
 
     pub fn patch(&self, input: &str, output: &str, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> io::Result<()> {
-        let base_input  = PathBuf::from(input);
         let base_output = PathBuf::from(output);
+        if self.recover_journal { Journal::recover(&base_output)?; }
+        let output_fs = if self.options.transactional { OutputFs::transactional(&base_output, self.options.symlink_policy)? } else { OutputFs::new(&base_output, self.options.symlink_policy) };
+
+        match self.run(PathBuf::from(input), base_output, &output_fs, write_bytes_cb) {
+            Ok(()) => output_fs.commit(),
+            Err(e) => match output_fs.rollback() {
+                Ok(()) => Err(e),
+                Err(re) => Err(io::Error::new(e.kind(), format!("{} (rollback failed: {})", e, re))),
+            },
+        }
+    }
 
+    fn run(&self, base_input: PathBuf, base_output: PathBuf, output_fs: &OutputFs, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> io::Result<()> {
         let mut f = File::open(&self.patch_path)?;
-        let hd19 = parse_hd19(&mut f)?;
+        let mut hd19 = parse_hd19(&mut f)?;
         let hd13 = parse_hd13(&mut f)?;
+        if self.options.windows_paths { resolve_windows_paths(&mut hd19.head, &base_input)?; }
+
+        hd19.checksums.verify(ChecksumSection::Head, [File::open(&self.patch_path).and_then(|mut f| f.seek(SeekFrom::Start(hd19.checksums.head_offset)).map(|_| f))])?;
+
+        let filter = self.options.file_filter.as_ref();
+        if filter.is_some() && self.options.checkpoint_path.is_some() { return Err(io::Error::new(io::ErrorKind::InvalidInput, "[KrPatchDir] Checkpoints cannot be combined with a file filter")); }
+        let wanted = |path: &str| filter.is_none_or(|f| f.matches(path));
+        // Read before any output is created, in case the output tree is the source tree.
+        let outputs = self.output_metadata(&hd19.head, &base_input, &base_output)?;
+        let in_place = is_same_dir(&base_input, &base_output);
+        let plan = plan_aliased_outputs(&hd19.head, &base_input, &base_output, in_place)?;
+        let targets: Vec<PathBuf> = hd19.head.new_files.iter().map(|fe| match &plan { Some(plan) => plan.target(&fe.path), None => base_output.join(&fe.path) }).collect();
+        if !self.options.skip_disk_space_check {
+            // Staged outputs are written next to the old file they replace, which stays until the end.
+            let reused = |fe: &KrFileEntry| if output_fs.is_transactional() || plan.as_ref().is_some_and(|p| p.is_staged(&fe.path)) { 0 } else { existing_file_size(&base_output.join(&fe.path)) };
+            let needed = hd19.head.new_files.iter().filter(|fe| wanted(&fe.path)).map(|fe| fe.size.saturating_sub(reused(fe))).sum();
+            ensure_free_space(&base_output, needed)?;
+        }
 
         for dir in &hd19.head.new_directories {
-            if !dir.is_empty() { fs::create_dir_all(base_output.join(dir.trim_end_matches('/')))?; }
+            if !dir.is_empty() && wanted(dir) { output_fs.create_dir_all(&base_output.join(dir.trim_end_matches('/')))?; }
         }
 
         for fe in &hd19.head.old_files {
             let full = base_input.join(&fe.path);
+            check_old_file(&base_input, &full, self.options.symlink_policy)?;
+            // With a filter, whether a missing old file matters is only known once the covers are mapped.
+            if !full.exists() && filter.is_some() { continue; }
             if !full.exists() { return Err(io::Error::new(io::ErrorKind::NotFound, format!("[KrPatchDir] Old file not found: {}", full.display()))); }
             let actual = full.metadata()?.len();
             if actual != fe.size { return Err(io::Error::new(io::ErrorKind::InvalidData, format!("[KrPatchDir] Old file size mismatch for {}: expected {} bytes, got {}", full.display(), fe.size, actual))); }
         }
+        // A filtered patch may be missing old files it does not need, and leaves new files out, so neither sum can be checked.
+        if filter.is_none() { hd19.checksums.verify(ChecksumSection::OldReference, hd19.head.old_files.iter().map(|fe| File::open(base_input.join(&fe.path))))?; }
+
+        let result = self.write_new_files(&hd19, &hd13, &base_input, &targets, output_fs, write_bytes_cb);
+        if let Err(e) = result {
+            // Staged outputs are what a checkpoint points into, keep them around for `resume`.
+            if let Some(plan) = &plan && self.options.checkpoint_path.is_none() { plan.discard(); }
+            return Err(e);
+        }
 
-        for fe in &hd19.head.new_files {
-            let full = base_output.join(&fe.path);
-            if let Some(parent) = full.parent() { fs::create_dir_all(parent)?; }
-            let file = File::options().read(true).write(true).create(true).truncate(true).open(&full)?;
-            file.set_len(fe.size)?;
+        if let Some(plan) = &plan {
+            plan.commit(output_fs)?;
+            // A selective patch leaves the rest of the tree alone.
+            if in_place && self.options.remove_old_files && filter.is_none() {
+                let key = |path: &str| if self.options.windows_paths { path.to_lowercase() } else { path.to_string() };
+                let kept: HashSet<String> = hd19.head.new_files.iter().map(|fe| key(&fe.path)).collect();
+                let obsolete = hd19.head.old_files.iter().map(|fe| fe.path.as_str()).filter(|p| !kept.contains(&key(p)));
+                let new_paths = hd19.head.new_files.iter().map(|fe| fe.path.as_str()).chain(hd19.head.new_directories.iter().map(String::as_str));
+                plan.remove_obsolete(output_fs, obsolete, new_paths)?;
+            }
         }
+        apply_output_metadata(&outputs, &self.options)
+    }
 
-        if hd19.head.old_files.is_empty() || hd19.head.new_files.is_empty() { return Ok(()); }
+    /// Creates every wanted new file at its target and runs the diff into them.
+    fn write_new_files(&self, hd19: &KrHd19, hd13: &KrHd13, base_input: &Path, targets: &[PathBuf], output_fs: &OutputFs, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> io::Result<()> {
+        let filter = self.options.file_filter.as_ref();
+        for (fe, full) in hd19.head.new_files.iter().zip(targets) {
+            if filter.is_some_and(|f| !f.matches(&fe.path)) { continue; }
+            let file = if self.resume { output_fs.reopen_file(full)? } else { output_fs.create_file(full)? };
+            self.allocation.set(OutputAllocation::combine(self.allocation.get(), size_output_file(&file, fe.size, self.options.preallocate)?));
+        }
+
+        match filter {
+            Some(filter) => self.apply_filtered(hd19, hd13, base_input, targets, filter, write_bytes_cb),
+            None => {
+                self.apply_full(hd19, hd13, base_input, targets, write_bytes_cb)?;
+                hd19.checksums.verify(ChecksumSection::NewReference, targets.iter().map(File::open))
+            }
+        }
+    }
 
+    /// KrDiff patches carry no metadata of their own, so this only matters when the options ask for it.
+    fn output_metadata(&self, head: &KrHead, base_input: &Path, base_output: &Path) -> io::Result<Vec<OutputMetadata>> {
+        if !wants_output_metadata(&self.options) { return Ok(Vec::new()); }
+        let old_paths: Vec<String> = head.old_files.iter().map(|fe| fe.path.clone()).collect();
+        let new_paths: Vec<String> = head.new_files.iter().map(|fe| fe.path.clone()).collect();
+        let matched = match_old_paths(&old_paths, &new_paths, [], self.options.windows_paths);
+        let filter = self.options.file_filter.as_ref();
+        new_paths.iter().zip(matched).filter(|(path, _)| filter.is_none_or(|f| f.matches(path))).map(|(path, old)| {
+            OutputMetadata::new(base_output.join(path), old.map(|old| base_input.join(old)).as_deref(), false, &self.options)
+        }).collect()
+    }
+
+    fn apply_full(&self, hd19: &KrHd19, hd13: &KrHd13, base_input: &Path, targets: &[PathBuf], write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> io::Result<()> {
         let old_handles: Vec<File> = hd19.head.old_files.iter().map(|fe| File::open(base_input.join(&fe.path))).collect::<io::Result<_>>()?;
         let mut old_combined = CombinedStream::new(old_handles)?;
 
-        let new_handles: Vec<NewFileCombinedStream> = hd19.head.new_files.iter().map(|fe| {
-            let full = base_output.join(&fe.path);
-            let file = File::options().read(true).write(true).open(&full)?;
+        let new_handles: Vec<NewFileCombinedStream> = hd19.head.new_files.iter().zip(targets).map(|(fe, full)| {
+            let file = File::options().read(true).write(true).open(full)?;
             Ok(NewFileCombinedStream { file, size: fe.size })
         }).collect::<io::Result<_>>()?;
-        let mut new_combined = CombinedStream::from_new_files(new_handles)?;
+        let mut new_combined = CombinedStream::from_new_files(new_handles, false)?;
+
+        let (mut checkpointer, resume_from) = start_checkpointing(&self.options, &self.patch_path, new_combined.length(), self.resume)?.unzip();
+        let resume_from = resume_from.flatten();
+        if let Some(cp) = &resume_from {
+            verify_written_prefix(&mut new_combined, cp)?;
+            new_combined.seek(SeekFrom::Start(cp.new_pos))?;
+        }
+
+        // A resumed patch wrote part of its output in an earlier run, so there is nothing complete to hash.
+        #[cfg(feature = "kuro-index")]
+        let mut md5s = (self.hash_outputs && resume_from.is_none()).then(|| OutputMd5s::new(&hd19.head.new_files));
+        #[cfg(feature = "kuro-index")]
+        let mut new_combined = Md5Writer { inner: &mut new_combined, md5s: md5s.as_mut() };
 
         let mut cb = write_bytes_cb;
-        apply_patch(&hd13, hd19.old_ref_size, hd19.new_ref_size, &mut old_combined, &mut new_combined, &self.patch_path, &mut cb)?;
+        let mut out = TrackedWriter { inner: &mut new_combined, checkpointer: checkpointer.as_mut(), callback: &mut cb };
+        apply_patch(hd13, hd19.old_ref_size, hd19.new_ref_size, &mut old_combined, &mut out, &self.patch_path, resume_from, self.options.cancel_flag.as_deref())?;
         new_combined.flush()?;
+        #[cfg(feature = "kuro-index")]
+        { *self.output_md5s.borrow_mut() = md5s.map(OutputMd5s::finish); }
+        if let Some(path) = &self.options.checkpoint_path { clear_checkpoint(path)?; }
         Ok(())
     }
+
+    /// Writes only the new files accepted by `filter`. Old files that no kept output reads from may be missing.
+    fn apply_filtered(&self, hd19: &KrHd19, hd13: &KrHd13, base_input: &Path, targets: &[PathBuf], filter: &FileFilter, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> io::Result<()> {
+        let mut kept_new = Vec::new();
+        let mut kept_handles = Vec::new();
+        let mut start = 0u64;
+        for (fe, full) in hd19.head.new_files.iter().zip(targets) {
+            if filter.matches(&fe.path) {
+                kept_new.push((start, fe.size));
+                let file = File::options().read(true).write(true).open(full)?;
+                kept_handles.push(NewFileCombinedStream { file, size: fe.size });
+            }
+            start += fe.size;
+        }
+
+        let mut layout = Vec::with_capacity(hd19.head.old_files.len());
+        let mut start = 0u64;
+        for fe in &hd19.head.old_files { layout.push((start, fe.size)); start += fe.size; }
+        let needed = needed_old_segments(cover_positions(hd13, hd19.old_ref_size), &kept_new, &layout);
+
+        let mut old_files = Vec::with_capacity(hd19.head.old_files.len());
+        for (fe, needed) in hd19.head.old_files.iter().zip(needed) {
+            let full = base_input.join(&fe.path);
+            match File::open(&full) {
+                Ok(file) => old_files.push((fe.size, Some(file))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !needed => old_files.push((fe.size, None)),
+                Err(e) => return Err(io::Error::new(e.kind(), format!("[KrPatchDir] Old file needed by the selected files is not readable: {}: {}", full.display(), e))),
+            }
+        }
+        let mut old_stream = SparseReader::new(old_files);
+
+        let mut new_combined = CombinedStream::from_new_files(kept_handles, false)?;
+        let mut new_stream = SkippingWriter::new(&mut new_combined, kept_new);
+        let mut cb = write_bytes_cb;
+        let mut out = TrackedWriter { inner: &mut new_stream, checkpointer: None, callback: &mut cb };
+        apply_patch(hd13, hd19.old_ref_size, hd19.new_ref_size, &mut old_stream, &mut out, &self.patch_path, None, self.options.cancel_flag.as_deref())?;
+        new_stream.flush()
+    }
+}
+
+/// Outputs that are the very file of an old file the patch still reads get staged, or writing them would destroy
+/// that old data first. Old files count by what they are on disk, so hard links and case-insensitive lookups are
+/// caught as well as plain in-place patches. `None` when nothing needs staging and the patch is not in place.
+fn plan_aliased_outputs(head: &KrHead, base_input: &Path, base_output: &Path, in_place: bool) -> io::Result<Option<InPlacePlan>> {
+    let mut old_ids = HashSet::new();
+    for fe in &head.old_files {
+        if let Some(id) = file_identity(&base_input.join(&fe.path))? { old_ids.insert(id); }
+    }
+    let mut aliased = Vec::new();
+    for fe in &head.new_files {
+        if file_identity(&base_output.join(&fe.path))?.is_some_and(|id| old_ids.contains(&id)) { aliased.push(fe.path.as_str()); }
+    }
+
+    #[cfg(debug_assertions)]
+    if !aliased.is_empty() { println!("[KrPatchDir] Staging {} outputs that overwrite old files", aliased.len()); }
+    if aliased.is_empty() && !in_place { return Ok(None); }
+    Ok(Some(InPlacePlan::new(base_output, aliased.iter().copied(), aliased.iter().copied())))
 }
 
-struct KrFileEntry {
-    path: String,
-    size: u64,
+/// What a KrDiff patch contains, read from its head without applying it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrDiffInfo {
+    pub old_files: Vec<KrFileEntry>,
+    pub new_files: Vec<KrFileEntry>,
+    /// With their trailing `/`; `""` is the output root.
+    pub new_directories: Vec<String>,
+    /// Declared by the HDIFF19 part; the diff data carries its own.
+    pub compression: CompressionMode,
+    pub diff_compression: CompressionMode,
+    pub cover_count: u64,
+    /// Total size of all new files.
+    pub new_data_size: u64,
+    /// Where the new data diff starts in the patch file.
+    pub new_data_diff_offset: u64,
+    pub new_data_diff_size: u64,
+    /// 0 when the new data diff is stored uncompressed.
+    pub new_data_diff_compressed_size: u64,
+}
+
+impl KrDiffInfo {
+    /// Parses the heads and covers of the patch at `path`. Unsafe paths come back as an `UnsafePathError`.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let mut f = File::open(path.as_ref()).map_err(|e| io::Error::new(e.kind(), format!("[KrDiffInfo::read] Cannot open {}: {}", path.as_ref().display(), e)))?;
+        let hd19 = parse_hd19(&mut f).map_err(into_patch_error)?;
+        let hd13 = parse_hd13(&mut f)?;
+        Ok(Self {
+            old_files: hd19.head.old_files,
+            new_files: hd19.head.new_files,
+            new_directories: hd19.head.new_directories,
+            compression: hd19.comp_mode,
+            diff_compression: hd13.comp_mode,
+            cover_count: hd13.covers.len() as u64,
+            new_data_size: hd13.new_data_size,
+            new_data_diff_offset: hd13.new_data_diff_offset,
+            new_data_diff_size: hd13.new_data_diff_size,
+            new_data_diff_compressed_size: hd13.new_data_diff_comp_size,
+        })
+    }
+}
+
+/// The files a KrDiff patch reads and writes, as `KrDiffBatch` needs them to tell which groups may run side by side.
+pub(crate) struct KrPatchFiles {
+    pub old_files: Vec<String>,
+    pub new_files: Vec<String>,
+    /// Sizes of `new_files`, in the same order.
+    pub new_sizes: Vec<u64>,
+    pub new_size: u64,
+}
+
+/// Only parses the head; the covers and diff data are not looked at.
+pub(crate) fn read_patch_files(patch_path: &Path, windows_paths: bool) -> io::Result<KrPatchFiles> {
+    let mut f = File::open(patch_path)?;
+    let hd19 = parse_hd19(&mut f)?;
+    let path = |fe: &KrFileEntry| if windows_paths { from_windows_path(&fe.path).map(|p| p.to_lowercase()) } else { Ok(fe.path.clone()) };
+    Ok(KrPatchFiles {
+        old_files: hd19.head.old_files.iter().map(path).collect::<io::Result<_>>()?,
+        new_files: hd19.head.new_files.iter().map(path).collect::<io::Result<_>>()?,
+        new_sizes: hd19.head.new_files.iter().map(|fe| fe.size).collect(),
+        new_size: hd19.head.new_files.iter().map(|fe| fe.size).sum(),
+    })
+}
+
+/// A file listed in a KrDiff head.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KrFileEntry {
+    pub path: String,
+    pub size: u64,
 }
 
 struct KrHead {
@@ -84,6 +365,40 @@ struct KrHd19 {
     old_ref_size: u64,
     new_ref_size: u64,
     head: KrHead,
+    checksums: KrChecksums,
+}
+
+/// The checksums of an HDIFF19 head, `byte_size` bytes each, in the order HDiffPatch's dir diff writes them:
+/// new reference data, old reference data, same-file data, then everything from the head to the end of the patch.
+/// KrDiff patches have no same files, so that one is not checked.
+struct KrChecksums {
+    kind: String,
+    byte_size: usize,
+    data: Vec<u8>,
+    head_offset: u64,
+}
+
+impl KrChecksums {
+    /// The stored CRC-32 for `section`, or `None` when the patch declares no checksums. Any other checksum type is an
+    /// `Unsupported` error rather than a patch that silently goes unchecked.
+    fn crc32(&self, section: ChecksumSection) -> io::Result<Option<u32>> {
+        match ChecksumMode::from_str(&self.kind) {
+            Ok(ChecksumMode::Nochecksum) => return Ok(None),
+            Ok(ChecksumMode::Crc32) if self.byte_size == 4 => {}
+            Ok(ChecksumMode::Crc32) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("[KrPatchDir] crc32 checksums are 4 bytes, the patch declares {}", self.byte_size))),
+            _ => return Err(io::Error::new(io::ErrorKind::Unsupported, format!("[KrPatchDir] The patch declares {} checksums, only crc32 can be verified", self.kind))),
+        }
+        let slot = match section { ChecksumSection::NewReference => 0, ChecksumSection::OldReference => 1, ChecksumSection::Head => 3 };
+        let bytes = &self.data[slot * 4..slot * 4 + 4];
+        Ok(Some(u32::from_le_bytes(bytes.try_into().unwrap())))
+    }
+
+    fn verify(&self, section: ChecksumSection, files: impl IntoIterator<Item = io::Result<File>>) -> io::Result<()> {
+        match self.crc32(section)? {
+            Some(expected) => verify_crc32(section, expected, files),
+            None => Ok(()),
+        }
+    }
 }
 
 struct KrCover {
@@ -101,39 +416,85 @@ struct KrHd13 {
     comp_mode: CompressionMode,
 }
 
-fn apply_patch(hd13: &KrHd13, old_ref_size: u64, new_ref_size: u64, old_combined: &mut CombinedStream, new_combined: &mut CombinedStream, patch_path: &str, write_bytes_cb: &mut Option<Box<dyn FnMut(i64)>>) -> io::Result<()> {
+#[allow(clippy::too_many_arguments)]
+fn apply_patch(hd13: &KrHd13, old_ref_size: u64, new_ref_size: u64, old_combined: &mut dyn SeekableRead, out: &mut TrackedWriter, patch_path: &str, resume_from: Option<Checkpoint>, cancel_flag: Option<&AtomicBool>) -> io::Result<()> {
     let f_newdata = File::open(patch_path)?;
-    let (mut new_data, _) = get_clip_stream(f_newdata, hd13.comp_mode, hd13.new_data_diff_offset, hd13.new_data_diff_size, hd13.new_data_diff_comp_size, false)?;
+    let (mut new_data_clip, _) = get_clip_stream(f_newdata, hd13.comp_mode, hd13.new_data_diff_offset, hd13.new_data_diff_size, hd13.new_data_diff_comp_size, false)?;
+    let mut new_data = CountingReader::new(&mut *new_data_clip);
 
     let mut read_pos: i64 = 0;
     let mut write_pos: u64 = 0;
+    let mut first_cover = 0usize;
     let mut buf = vec![0u8; 64 * 1024];
 
-    for cover in &hd13.covers {
-        read_pos = read_pos.wrapping_add(cover.old_pos_delta);
+    if let Some(cp) = resume_from {
+        new_data.skip(cp.clip_offsets[2])?;
+        read_pos = cp.last_old_end;
+        write_pos = cp.new_pos;
+        first_cover = cp.cover_index as usize;
+    }
 
-        if old_ref_size > 0 {
-            let sz = old_ref_size as i64;
-            while read_pos > sz { read_pos -= sz; }
-            while read_pos < 0  { read_pos += sz; }
-        }
+    for (i, cover) in hd13.covers.iter().enumerate().skip(first_cover) {
+        check_cancel_flag(cancel_flag)?;
+        read_pos = wrap_read_pos(read_pos.wrapping_add(cover.old_pos_delta), old_ref_size);
 
         if cover.new_pos_gap > 0 {
-            copy_n(&mut *new_data, new_combined, cover.new_pos_gap as usize, &mut buf)?;
+            copy_n(&mut new_data, out, cover.new_pos_gap as usize, &mut buf)?;
             write_pos += cover.new_pos_gap;
         }
 
         if cover.length > 0 {
             old_combined.seek(SeekFrom::Start(read_pos as u64))?;
-            copy_n(old_combined, new_combined, cover.length as usize, &mut buf)?;
+            copy_n(old_combined, out, cover.length as usize, &mut buf)?;
         }
 
         read_pos  = read_pos.wrapping_add(cover.length as i64);
         write_pos = write_pos.saturating_add(cover.length);
-        if let Some(cb) = write_bytes_cb.as_mut() { cb(write_pos as i64); }
+        save_checkpoint_if_due(out, Checkpoint { cover_index: i as u64 + 1, clip_offsets: [0, 0, new_data.count], last_old_end: read_pos, ..Default::default() })?;
     }
 
-    if write_pos < new_ref_size { copy_n(&mut *new_data, new_combined, (new_ref_size - write_pos) as usize, &mut buf)?; }
+    if write_pos < new_ref_size { copy_n(&mut new_data, out, (new_ref_size - write_pos) as usize, &mut buf)?; }
+    Ok(())
+}
+
+fn wrap_read_pos(mut read_pos: i64, old_ref_size: u64) -> i64 {
+    if old_ref_size > 0 {
+        let sz = old_ref_size as i64;
+        while read_pos > sz { read_pos -= sz; }
+        while read_pos < 0  { read_pos += sz; }
+    }
+    read_pos
+}
+
+/// Absolute `(old_pos, new_pos, length)` of every cover, following the same position rules as `apply_patch`.
+fn cover_positions(hd13: &KrHd13, old_ref_size: u64) -> Vec<(u64, u64, u64)> {
+    let mut read_pos: i64 = 0;
+    let mut write_pos: u64 = 0;
+    let mut out = Vec::with_capacity(hd13.covers.len());
+    for cover in &hd13.covers {
+        read_pos = wrap_read_pos(read_pos.wrapping_add(cover.old_pos_delta), old_ref_size);
+        write_pos += cover.new_pos_gap;
+        out.push((read_pos as u64, write_pos, cover.length));
+        read_pos = read_pos.wrapping_add(cover.length as i64);
+        write_pos = write_pos.saturating_add(cover.length);
+    }
+    out
+}
+
+fn save_checkpoint_if_due(out: &mut TrackedWriter, cp: Checkpoint) -> io::Result<()> {
+    let Some(checkpointer) = out.checkpointer.as_deref_mut() else { return Ok(()); };
+    if !checkpointer.is_due(0) { return Ok(()); }
+    out.inner.sync_data()?;
+    checkpointer.save(cp)
+}
+
+fn resolve_windows_paths(head: &mut KrHead, base_input: &Path) -> io::Result<()> {
+    for dir in &mut head.new_directories { *dir = from_windows_path(dir)?; }
+    for fe in head.old_files.iter_mut().chain(head.new_files.iter_mut()) { fe.path = from_windows_path(&fe.path)?; }
+    let mut index = PathIndex::new(base_input);
+    for fe in &mut head.old_files {
+        if let Some(found) = index.resolve(&fe.path)? { fe.path = found; }
+    }
     Ok(())
 }
 
@@ -152,14 +513,14 @@ fn parse_hd19(reader: &mut (impl Read + Seek)) -> io::Result<KrHd19> {
     let chunk_type = read_delim(reader, b'&', 10)?;
     if chunk_type != "HDIFF19" { return Err(io::Error::new(io::ErrorKind::InvalidData, format!("[KrPatchDir] Expected HDIFF19 chunk, got {:?}", chunk_type))); }
     let comp_str = read_delim(reader, b'&', 10)?;
-    let _checksum_type = read_delim(reader, b'\0', 15)?;
+    let checksum_type = read_delim(reader, b'\0', 15)?;
     let _old_is_dir = reader.read_boolean()?;
     let _new_is_dir = reader.read_boolean()?;
 
     let old_path_count = reader.read_long_7bit()? as u64;
-    let _old_path_sum_size = reader.read_long_7bit()? as u64;
+    let old_path_sum_size = reader.read_long_7bit()? as u64;
     let new_path_count = reader.read_long_7bit()? as u64;
-    let _new_path_sum_size = reader.read_long_7bit()? as u64;
+    let new_path_sum_size = reader.read_long_7bit()? as u64;
     let old_ref_file_count = reader.read_long_7bit()? as u64;
     let old_ref_size = reader.read_long_7bit()? as u64;
     let new_ref_file_count = reader.read_long_7bit()? as u64;
@@ -174,19 +535,37 @@ fn parse_hd19(reader: &mut (impl Read + Seek)) -> io::Result<KrHd19> {
     let head_data_comp_size = reader.read_long_7bit()? as u64;
     let checksum_byte_size = reader.read_long_7bit()? as u64;
 
-    skip_bytes(reader, checksum_byte_size * 4)?;
+    if checksum_byte_size > 64 { return Err(io::Error::new(io::ErrorKind::InvalidData, format!("[KrPatchDir] Implausible checksum size {}", checksum_byte_size))); }
+    let mut checksum_data = vec![0u8; (checksum_byte_size * 4) as usize];
+    reader.read_exact(&mut checksum_data)?;
+    let checksums = KrChecksums { kind: checksum_type, byte_size: checksum_byte_size as usize, data: checksum_data, head_offset: reader.stream_position()? };
 
-    let head = parse_hd19_head(reader, old_path_count, new_path_count, old_ref_file_count, new_ref_file_count, head_data_size, head_data_comp_size)?;
+    let counts = KrHeadCounts { old_path_count, old_path_sum_size, new_path_count, new_path_sum_size, old_ref_file_count, new_ref_file_count };
+    let head = parse_hd19_head(reader, &counts, head_data_size, head_data_comp_size)?;
     skip_bytes(reader, private_extern_size)?;
     skip_bytes(reader, extern_size)?;
 
     let comp_mode = CompressionMode::from_str(&comp_str).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-    Ok(KrHd19 { comp_mode, old_ref_size, new_ref_size, head })
+    Ok(KrHd19 { comp_mode, old_ref_size, new_ref_size, head, checksums })
 }
 
-fn parse_hd19_head(reader: &mut (impl Read + Seek), old_path_count:u64, new_path_count: u64, old_ref_file_count: u64, new_ref_file_count: u64, head_data_size: u64, head_data_comp_size: u64) -> io::Result<KrHead> {
+/// Entry counts from the HDIFF19 header that size the head data.
+#[derive(Clone, Copy)]
+struct KrHeadCounts {
+    old_path_count: u64,
+    /// Bytes of all old paths including their terminating NULs.
+    old_path_sum_size: u64,
+    new_path_count: u64,
+    new_path_sum_size: u64,
+    old_ref_file_count: u64,
+    new_ref_file_count: u64,
+}
+
+fn parse_hd19_head(reader: &mut (impl Read + Seek), counts: &KrHeadCounts, head_data_size: u64, head_data_comp_size: u64) -> io::Result<KrHead> {
+    // The paths are part of the head data, so their declared sizes cannot exceed it; checked before anything is allocated for them.
+    if counts.old_path_sum_size.saturating_add(counts.new_path_sum_size) > head_data_size { return Err(io::Error::new(io::ErrorKind::InvalidData, format!("[KrPatchDir] Path sizes {} + {} exceed the head data size {}", counts.old_path_sum_size, counts.new_path_sum_size, head_data_size))); }
     // Record start so we can seek to the exact end even if the decoder stops early.
-    let section_start = reader.seek(SeekFrom::Current(0))?;
+    let section_start = reader.stream_position()?;
     let file_bytes = if head_data_comp_size > 0 { head_data_comp_size } else { head_data_size };
 
     let head = if head_data_comp_size > 0 {
@@ -194,10 +573,10 @@ fn parse_hd19_head(reader: &mut (impl Read + Seek), old_path_count:u64, new_path
         // Stream directly into the decoder — no intermediate compressed or decompressed Vec.
         let mut dec = zstd::stream::read::Decoder::new(reader.by_ref().take(head_data_comp_size))?;
         dec.set_parameter(zstd::zstd_safe::DParameter::WindowLogMax(window_log))?;
-        parse_head_data_seq(&mut dec, old_path_count, new_path_count, old_ref_file_count, new_ref_file_count)?
+        parse_head_data_seq(&mut dec, counts)?
     } else {
         let mut limited = reader.by_ref().take(head_data_size);
-        parse_head_data_seq(&mut limited, old_path_count, new_path_count, old_ref_file_count, new_ref_file_count)?
+        parse_head_data_seq(&mut limited, counts)?
     };
 
     // Guarantee reader sits exactly at the end of the section regardless of decoder internals.
@@ -205,12 +584,12 @@ fn parse_hd19_head(reader: &mut (impl Read + Seek), old_path_count:u64, new_path
     Ok(head)
 }
 
-fn parse_head_data_seq(reader: &mut impl Read, old_path_count: u64, new_path_count: u64, old_ref_file_count: u64, new_ref_file_count: u64) -> io::Result<KrHead> {
-    let mut old_paths = Vec::with_capacity(old_path_count as usize);
-    for _ in 0..old_path_count { old_paths.push(read_null_str(reader)?); }
-
-    let mut new_paths = Vec::with_capacity(new_path_count as usize);
-    for _ in 0..new_path_count { new_paths.push(read_null_str(reader)?); }
+fn parse_head_data_seq(reader: &mut impl Read, counts: &KrHeadCounts) -> io::Result<KrHead> {
+    let KrHeadCounts { old_ref_file_count, new_ref_file_count, .. } = *counts;
+    let mut old_paths = read_paths(reader, counts.old_path_count, counts.old_path_sum_size, "old")?;
+    let mut new_paths = read_paths(reader, counts.new_path_count, counts.new_path_sum_size, "new")?;
+    normalize_patch_paths(&mut old_paths)?;
+    normalize_patch_paths(&mut new_paths)?;
 
     let mut old_offsets = Vec::with_capacity(old_ref_file_count as usize);
     for _ in 0..old_ref_file_count { old_offsets.push(reader.read_long_7bit()? as u64); }
@@ -223,8 +602,9 @@ fn parse_head_data_seq(reader: &mut impl Read, old_path_count: u64, new_path_cou
     let mut new_sizes = Vec::with_capacity(new_ref_file_count as usize);
     for _ in 0..new_ref_file_count { new_sizes.push(reader.read_long_7bit()? as u64); }
 
-    // Unknown field present in KrDiff: one VarInt per new reference file.
-    for _ in 0..new_ref_file_count { let _ = reader.read_long_7bit()?; }
+    // Unknown field present in KrDiff: one VarInt per new reference file. Nothing is known about it beyond that,
+    // so it is read past and not exposed.
+    for _ in 0..new_ref_file_count { reader.read_long_7bit()?; }
 
     let (old_files, _old_dirs) = split_paths_with_offsets(&old_paths, &old_offsets, &old_sizes);
     let (new_files, new_directories) = split_paths_with_offsets(&new_paths, &new_offsets, &new_sizes);
@@ -276,7 +656,7 @@ fn parse_hd13(reader: &mut (impl Read + Seek)) -> io::Result<KrHd13> {
     let new_data_diff_size = reader.read_long_7bit()? as u64;
     let new_data_diff_comp_size = reader.read_long_7bit()? as u64;
 
-    let cover_buf_start = reader.seek(SeekFrom::Current(0))?;
+    let cover_buf_start = reader.stream_position()?;
     let covers = read_covers(reader, cover_count, cover_buf_size, comp_cover_buf_size)?;
 
     let cover_file_bytes    = if comp_cover_buf_size    > 0 { comp_cover_buf_size    } else { cover_buf_size    };
@@ -339,8 +719,22 @@ fn read_delim(reader: &mut impl Read, delim: u8, limit: usize) -> io::Result<Str
     Ok(String::from_utf8_lossy(&buf).into_owned())
 }
 
-fn read_null_str(reader: &mut impl Read) -> io::Result<String> {
-    read_delim(reader, b'\0', 255)
+/// Reads `count` NUL-terminated paths that take up exactly `sum_size` bytes. Paths are not length-limited,
+/// but must be valid UTF-8.
+fn read_paths(reader: &mut impl Read, count: u64, sum_size: u64, which: &str) -> io::Result<Vec<String>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, format!("[KrPatchDir] {} paths: {}", which, msg));
+    // Every path has at least its terminator.
+    if count > sum_size { return Err(invalid(format!("{} paths cannot fit in {} bytes", count, sum_size))); }
+    let mut raw = Vec::with_capacity(sum_size as usize);
+    reader.take(sum_size).read_to_end(&mut raw)?;
+    if (raw.len() as u64) < sum_size { return Err(invalid(format!("head data ends after {} of {} bytes", raw.len(), sum_size))); }
+
+    if count == 0 { return if raw.is_empty() { Ok(Vec::new()) } else { Err(invalid(format!("no paths, but {} bytes of them", sum_size))) }; }
+
+    let Some(body) = raw.strip_suffix(b"\0") else { return Err(invalid("last path is not NUL-terminated".to_string())); };
+    let parts: Vec<&[u8]> = body.split(|&b| b == 0).collect();
+    if parts.len() as u64 != count { return Err(invalid(format!("expected {} paths in {} bytes, found {}", count, sum_size, parts.len()))); }
+    parts.into_iter().map(|part| String::from_utf8(part.to_vec()).map_err(|_| invalid(format!("{:?} is not valid UTF-8", String::from_utf8_lossy(part))))).collect()
 }
 
 fn skip_bytes(reader: &mut (impl Read + Seek), n: u64) -> io::Result<()> {
@@ -1,38 +1,76 @@
 use std::fs::File;
 use std::io::{Cursor, Read, SeekFrom, Write};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use crate::utils::checkpoint::{Checkpoint, Checkpointer, CountingReader, SyncWrite, TrackedWriter};
 use crate::utils::compression_utils::get_clip_stream;
 use crate::utils::parser::BinaryExtensions;
-use crate::utils::structs::{HeaderInfo, SeekableRead};
+use crate::utils::structs::{check_cancel_flag, HeaderInfo, SeekableRead};
 
 pub struct PatchSF {
     header_info: HeaderInfo,
+    checkpointing: Option<(Checkpointer, Option<Checkpoint>)>,
+    cancel_flag: Option<Arc<AtomicBool>>,
 }
 
 impl PatchSF {
     pub fn new(header_info: HeaderInfo) -> Self {
-        Self { header_info }
+        Self { header_info, checkpointing: None, cancel_flag: None }
     }
 
-    pub fn patch(&self, input_stream: &mut dyn SeekableRead, output_stream: &mut dyn Write, patch_path: &str, _write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> std::io::Result<()> {
+    pub(crate) fn with_checkpointing(mut self, checkpointing: Option<(Checkpointer, Option<Checkpoint>)>) -> Self {
+        self.checkpointing = checkpointing;
+        self
+    }
+
+    /// Checked once per step, so a cancelled patch stops within `step_mem_size` bytes of output.
+    pub(crate) fn with_cancel_flag(mut self, cancel_flag: Option<Arc<AtomicBool>>) -> Self {
+        self.cancel_flag = cancel_flag;
+        self
+    }
+
+    pub fn patch(&mut self, input_stream: &mut dyn SeekableRead, output_stream: &mut dyn SyncWrite, patch_path: &str, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> std::io::Result<()> {
         let sci = &self.header_info.single_chunk_info;
         let (mut diff, _) = get_clip_stream(File::open(patch_path)?, self.header_info.comp_mode, sci.diff_data_pos as u64, sci.uncompressed_size as u64, sci.compressed_size as u64, false)?;
-        self.start_patch_routine(&mut diff, input_stream, output_stream)
+        let (mut checkpointer, resume_from) = self.checkpointing.take().unzip();
+        let mut callback = write_bytes_cb;
+        let mut out = TrackedWriter { inner: output_stream, checkpointer: checkpointer.as_mut(), callback: &mut callback };
+        self.start_patch_routine(&mut diff, input_stream, &mut out, resume_from.flatten())
     }
 
-    fn start_patch_routine(&self, diff: &mut dyn Read, old: &mut dyn SeekableRead, out: &mut dyn Write) -> std::io::Result<()> {
+    fn start_patch_routine(&self, diff: &mut dyn Read, old: &mut dyn SeekableRead, out: &mut TrackedWriter, resume_from: Option<Checkpoint>) -> std::io::Result<()> {
         let cover_count = self.header_info.chunk_info.cover_count as u64;
         let step_mem_size = self.header_info.step_mem_size as usize;
         let mut step_buf = vec![0u8; step_mem_size];
         let mut io_buf = vec![0u8; step_mem_size];
-        patch_loop(diff, old, out, cover_count, &mut step_buf, &mut io_buf)
+        patch_loop(diff, old, out, cover_count, &mut step_buf, &mut io_buf, resume_from, self.cancel_flag.as_deref())
     }
 }
 
-fn patch_loop(mut diff: &mut dyn Read, old: &mut dyn SeekableRead, out: &mut dyn Write, mut cover_count: u64, step_buf: &mut Vec<u8>, io_buf: &mut Vec<u8>) -> std::io::Result<()> {
+// Checkpoints are only taken between steps: the RLE decoder never spans a step, and everything up to the
+// last cover's end has been written by then.
+#[allow(clippy::too_many_arguments)]
+fn patch_loop(diff: &mut dyn Read, old: &mut dyn SeekableRead, out: &mut TrackedWriter, mut cover_count: u64, step_buf: &mut Vec<u8>, io_buf: &mut [u8], resume_from: Option<Checkpoint>, cancel_flag: Option<&AtomicBool>) -> std::io::Result<()> {
+    let mut diff = CountingReader::new(diff);
     let mut last_old_end = 0u64;
     let mut last_new_end = 0u64;
+    let mut covers_done = 0u64;
+
+    if let Some(cp) = resume_from {
+        diff.skip(cp.clip_offsets[2])?;
+        covers_done = cp.cover_index;
+        cover_count = cover_count.saturating_sub(cp.cover_index);
+        last_old_end = cp.last_old_end as u64;
+        last_new_end = cp.last_new_end;
+    }
 
     while cover_count > 0 {
+        check_cancel_flag(cancel_flag)?;
+        if let Some(checkpointer) = out.checkpointer.as_deref_mut() && checkpointer.is_due(0) {
+            out.inner.sync_data()?;
+            checkpointer.save(Checkpoint { cover_index: covers_done, clip_offsets: [0, 0, diff.count], last_old_end: last_old_end as i64, last_new_end, ..Default::default() })?;
+        }
+
         let buf_cover_size = diff.read_long_7bit()? as usize;
         let buf_rle_size = diff.read_long_7bit()? as usize;
         let step_end = buf_cover_size + buf_rle_size;
@@ -48,8 +86,9 @@ fn patch_loop(mut diff: &mut dyn Read, old: &mut dyn SeekableRead, out: &mut dyn
         while covers.position() < covers_len && cover_count > 0 {
             let prev_new_end = last_new_end;
             let (old_pos, new_pos, length) = decode_cover(&mut covers, &mut last_old_end, &mut last_new_end)?;
-            if new_pos > prev_new_end { copy_n(&mut *diff, out, new_pos - prev_new_end, io_buf)?; }
+            if new_pos > prev_new_end { copy_n(&mut diff, out, new_pos - prev_new_end, io_buf)?; }
             cover_count -= 1;
+            covers_done += 1;
 
             if length > 0 {
                 old.seek(SeekFrom::Start(old_pos))?;
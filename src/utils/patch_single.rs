@@ -1,26 +1,34 @@
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Read;
+use crate::utils::checkpoint::{Checkpoint, Checkpointer, SyncWrite};
 use crate::utils::compression_utils::get_clip_stream;
 use crate::utils::structs::PatchCoreImpl;
 use crate::utils::structs::{CompressionMode, HeaderInfo, PatchCore, SeekableRead};
 
 pub struct PatchSingle {
     header_info: HeaderInfo,
+    checkpointing: Option<(Checkpointer, Option<Checkpoint>)>,
 }
 
 impl PatchSingle {
     pub fn new(header_info: HeaderInfo) -> Self {
-        Self { header_info }
+        Self { header_info, checkpointing: None }
     }
 
-    pub fn patch(&self, input_stream: &mut dyn SeekableRead, output_stream: &mut dyn Write, patch_path: &str, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> std::io::Result<()> {
+    pub(crate) fn with_checkpointing(mut self, checkpointing: Option<(Checkpointer, Option<Checkpoint>)>) -> Self {
+        self.checkpointing = checkpointing;
+        self
+    }
+
+    pub fn patch(&mut self, input_stream: &mut dyn SeekableRead, output_stream: &mut dyn SyncWrite, patch_path: &str, write_bytes_cb: Option<Box<dyn FnMut(i64)>>) -> std::io::Result<()> {
         // Zlib has a 1-byte padding per compressed chunk; zstd has none.
         let padding: u64 = match self.header_info.comp_mode { CompressionMode::Zlib => 1, _ => 0 };
-        let mut core = PatchCoreImpl::new(self.header_info.new_data_size, std::path::PathBuf::new(), std::path::PathBuf::new(), write_bytes_cb);
+        let mut core = PatchCoreImpl::new(std::path::PathBuf::new(), std::path::PathBuf::new(), write_bytes_cb);
+        if let Some((checkpointer, resume_from)) = self.checkpointing.take() { core.set_checkpointing(checkpointer, resume_from); }
         self.start_patch_routine(input_stream, output_stream, &mut core, patch_path, padding)
     }
 
-    fn start_patch_routine(&self, input_stream: &mut dyn SeekableRead, output_stream: &mut dyn Write, core: &mut PatchCoreImpl, patch_path: &str, padding: u64) -> std::io::Result<()> {
+    fn start_patch_routine(&self, input_stream: &mut dyn SeekableRead, output_stream: &mut dyn SyncWrite, core: &mut PatchCoreImpl, patch_path: &str, padding: u64) -> std::io::Result<()> {
         let hi = &self.header_info;
         let ci = &hi.chunk_info;
 
@@ -46,7 +54,6 @@ impl PatchSingle {
         let comp_diff_size = (ci.compress_new_data_diff_size as u64).saturating_sub(padding);
         let (clip3, _) = get_clip_stream(f3, hi.comp_mode, offset + new_data_diff_padding, ci.new_data_diff_size as u64, comp_diff_size, false)?;
         let mut clips: [Box<dyn Read>; 4] = [clip0, clip1, clip2, clip3];
-        core.uncover_buffer_clips_stream(&mut clips, input_stream, output_stream, hi);
-        Ok(())
+        core.uncover_buffer_clips_stream(&mut clips, input_stream, output_stream, hi)
     }
 }
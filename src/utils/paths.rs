@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::utils::checksum::ChecksumMismatchError;
+use crate::utils::disk_space::InsufficientSpaceError;
+
+/// A path stored in a patch that would resolve outside the source or destination root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsafePathError {
+    pub path: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for UnsafePathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unsafe path in patch {:?}: {}", self.path, self.reason)
+    }
+}
+
+impl Error for UnsafePathError {}
+
+impl UnsafePathError {
+    fn reject(path: &str, reason: &'static str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, Self { path: path.to_string(), reason })
+    }
+}
+
+/// Unwraps an `UnsafePathError`, `InsufficientSpaceError` or `ChecksumMismatchError` carried by an io error so callers can downcast the boxed error directly.
+pub(crate) fn into_patch_error(e: io::Error) -> Box<dyn Error> {
+    into_send_patch_error(e)
+}
+
+/// `into_patch_error` for errors that cross threads.
+pub(crate) fn into_send_patch_error(e: io::Error) -> Box<dyn Error + Send + Sync> {
+    if !e.get_ref().is_some_and(|inner| inner.is::<UnsafePathError>() || inner.is::<InsufficientSpaceError>() || inner.is::<ChecksumMismatchError>()) { return Box::new(e); }
+    match e.into_inner() {
+        Some(inner) => inner,
+        None => unreachable!("checked above"),
+    }
+}
+
+/// Validates a path from a patch and returns it in normal form: `/`-separated, no `.` or empty components,
+/// with the trailing `/` of directory entries kept. `""` is the root itself.
+/// Both `/` and `\` count as separators while checking, so a patch made on Windows cannot sneak `..\` past us.
+pub(crate) fn normalize_patch_path(raw: &str) -> io::Result<String> {
+    if raw.contains('\0') { return Err(UnsafePathError::reject(raw, "contains a NUL byte")); }
+    if raw.starts_with('/') { return Err(UnsafePathError::reject(raw, "absolute path")); }
+
+    let mut parts = Vec::new();
+    for part in raw.split('/') {
+        if part.split('\\').any(|piece| piece == "..") { return Err(UnsafePathError::reject(raw, "parent directory component")); }
+        if part.is_empty() || part == "." { continue; }
+        // Checked on the first component that survives normalisation, so "./C:" cannot turn into "C:".
+        if parts.is_empty() && (part.starts_with('\\') || is_drive_prefix(part)) { return Err(UnsafePathError::reject(raw, "absolute path")); }
+        parts.push(part);
+    }
+
+    let mut normalized = parts.join("/");
+    if raw.ends_with('/') && !normalized.is_empty() { normalized.push('/'); }
+    Ok(normalized)
+}
+
+pub(crate) fn normalize_patch_paths(paths: &mut [String]) -> io::Result<()> {
+    for path in paths.iter_mut() { *path = normalize_patch_path(path)?; }
+    Ok(())
+}
+
+// "C:", "C:foo" - drive-absolute or drive-relative on Windows either way.
+fn is_drive_prefix(piece: &str) -> bool {
+    let b = piece.as_bytes();
+    b.len() >= 2 && b[0].is_ascii_alphabetic() && b[1] == b':'
+}
+
+/// Joins a normalised patch path onto a root. `""` is the root itself, which for a file-to-dir or dir-to-file
+/// patch is the single file on that side rather than a directory.
+pub(crate) fn join_patch_path(root: &Path, rel: &str) -> PathBuf {
+    if rel.is_empty() { root.to_path_buf() } else { root.join(rel) }
+}
+
+/// Rewrites `\` separators of an already normalised path to `/` and normalises it again.
+pub(crate) fn from_windows_path(path: &str) -> io::Result<String> {
+    normalize_patch_path(&path.replace('\\', "/"))
+}
+
+/// Resolves patch paths against what is really on disk, ignoring case. Every directory is listed once and
+/// kept around, so resolving thousands of paths below the same folders stays cheap.
+pub(crate) struct PathIndex {
+    root: PathBuf,
+    listings: HashMap<PathBuf, HashMap<String, Vec<String>>>,
+}
+
+impl PathIndex {
+    pub fn new(root: &Path) -> Self {
+        Self { root: root.to_path_buf(), listings: HashMap::new() }
+    }
+
+    /// Returns the on-disk spelling of a normalised relative path, `None` if nothing matches.
+    /// An exact match always wins; two entries that only differ in case are reported as ambiguous.
+    pub fn resolve(&mut self, rel: &str) -> io::Result<Option<String>> {
+        let is_dir = rel.ends_with('/');
+        let mut resolved = Vec::new();
+        let mut dir = self.root.clone();
+
+        for part in rel.split('/').filter(|p| !p.is_empty()) {
+            let listing = self.listing(&dir)?;
+            let Some(candidates) = listing.get(&part.to_lowercase()) else { return Ok(None); };
+            let name = match candidates.iter().find(|c| c.as_str() == part) {
+                Some(exact) => exact.clone(),
+                None if candidates.len() == 1 => candidates[0].clone(),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("[PathIndex::resolve] {:?} is ambiguous, it matches {} in {}", rel, candidates.join(", "), dir.display()))),
+            };
+            dir.push(&name);
+            resolved.push(name);
+        }
+
+        let mut out = resolved.join("/");
+        if is_dir && !out.is_empty() { out.push('/'); }
+        Ok(Some(out))
+    }
+
+    fn listing(&mut self, dir: &Path) -> io::Result<&HashMap<String, Vec<String>>> {
+        if !self.listings.contains_key(dir) {
+            let mut listing: HashMap<String, Vec<String>> = HashMap::new();
+            match fs::read_dir(dir) {
+                Ok(entries) => for entry in entries {
+                    let name = entry?.file_name().to_string_lossy().into_owned();
+                    listing.entry(name.to_lowercase()).or_default().push(name);
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound || e.kind() == io::ErrorKind::NotADirectory => {}
+                Err(e) => return Err(e),
+            }
+            for names in listing.values_mut() { names.sort(); }
+            self.listings.insert(dir.to_path_buf(), listing);
+        }
+        Ok(&self.listings[dir])
+    }
+
+    /// Rewrites every path that exists on disk to its real spelling; paths with no match are left untouched.
+    pub fn resolve_all(&mut self, paths: &mut [String]) -> io::Result<()> {
+        for path in paths.iter_mut() {
+            if let Some(found) = self.resolve(path)? { *path = found; }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use std::io;
+use std::path::Path;
+use crate::utils::journal::OutputFs;
+use crate::utils::structs::SameFileStrategy;
+
+/// Puts `from` at `to` with the requested strategy and returns the one that was actually used.
+/// `can_move` says whether `from` may disappear, i.e. an in-place patch where nothing else reads that old file.
+/// Unsupported strategies fall back to a copy; any other failure is returned as is.
+pub(crate) fn place_same_file(output_fs: &OutputFs, strategy: SameFileStrategy, from: &Path, to: &Path, can_move: bool) -> io::Result<SameFileStrategy> {
+    let used = match strategy {
+        SameFileStrategy::Rename if can_move && !output_fs.is_transactional() => {
+            // A resumed patch runs this again after the move already happened.
+            if from.exists() || !to.exists() { output_fs.rename(from, to)?; }
+            SameFileStrategy::Rename
+        }
+        SameFileStrategy::Hardlink => match output_fs.hard_link(from, to) {
+            Ok(()) => SameFileStrategy::Hardlink,
+            Err(e) if is_unsupported(&e) => copy(output_fs, from, to)?,
+            Err(e) => return Err(e),
+        },
+        SameFileStrategy::Reflink => match reflink(output_fs, from, to) {
+            Ok(()) => SameFileStrategy::Reflink,
+            Err(e) if is_unsupported(&e) => copy(output_fs, from, to)?,
+            Err(e) => return Err(e),
+        },
+        _ => copy(output_fs, from, to)?,
+    };
+    Ok(used)
+}
+
+fn copy(output_fs: &OutputFs, from: &Path, to: &Path) -> io::Result<SameFileStrategy> {
+    output_fs.copy(from, to).map_err(|e| io::Error::new(e.kind(), format!("[place_same_file] Cannot copy {} to {}: {}", from.display(), to.display(), e)))?;
+    Ok(SameFileStrategy::Copy)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(output_fs: &OutputFs, from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let src = std::fs::File::open(from)?;
+    let dst = output_fs.create_file(to)?;
+    // SAFETY: both descriptors are open for the duration of the call and FICLONE takes the source fd by value.
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_output_fs: &OutputFs, _from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+// Errors meaning "this filesystem or pair of paths cannot do that", as opposed to real I/O failures.
+fn is_unsupported(e: &io::Error) -> bool {
+    if matches!(e.kind(), io::ErrorKind::Unsupported | io::ErrorKind::CrossesDevices) { return true; }
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() { return [libc::EOPNOTSUPP, libc::ENOTTY, libc::EINVAL, libc::EXDEV, libc::EPERM, libc::EMLINK].contains(&code); }
+    false
+}
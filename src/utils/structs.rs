@@ -1,6 +1,15 @@
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Read;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use crate::utils::checkpoint::{Checkpoint, Checkpointer, SyncWrite};
+use crate::utils::disk_space::OutputAllocation;
+use crate::utils::filter::FileFilter;
+use crate::utils::in_place::InPlacePlan;
+use crate::utils::journal::OutputFs;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum CompressionMode {
@@ -48,6 +57,93 @@ impl FromStr for ChecksumMode {
     }
 }
 
+/// How a new file that is byte-identical to an old one gets put into the output.
+/// Anything the filesystem or situation does not allow falls back to `Copy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SameFileStrategy {
+    /// Plain byte-for-byte copy.
+    #[default]
+    Copy,
+    /// Copy-on-write clone (`FICLONE`), instant on btrfs and xfs. Linux only.
+    Reflink,
+    /// Hard link to the old file. Both names then share one inode, so editing one edits the other.
+    Hardlink,
+    /// Move the old file over when patching in place and nothing else still reads it.
+    Rename,
+}
+
+/// Modification time given to the files a directory patch writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputTimestamps {
+    /// Whatever the filesystem sets while writing.
+    #[default]
+    Now,
+    /// The mtime of the matched old file: the source of an identical pair, or the old file at the same path.
+    /// Brand new files keep the current time.
+    FromOldFile,
+    /// The same timestamp on every output.
+    Fixed(SystemTime),
+}
+
+/// What a directory patch does with symlinks below the source and output roots. The roots themselves may be links.
+/// Output files are always opened without following a link in their last component.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Fail on any symlink met while reading old files or writing outputs. The hardened choice for installs that
+    /// are not expected to contain links.
+    Refuse,
+    /// Read old files and enter output directories through symlinks, as patching always did. A link sitting where
+    /// an output file goes is replaced by the file, its target is left alone.
+    #[default]
+    Follow,
+    /// Never look through a symlink. Links in the way of an output are replaced by real files and directories,
+    /// and an old file that is or sits below a link is an error.
+    Opaque,
+}
+
+/// Behaviour switches for directory patches, passed to `HDiff::with_options` / `KrDiff::with_options`.
+#[derive(Debug, Clone, Default)]
+pub struct PatchOptions {
+    /// Journal every file created, replaced or deleted in the destination and undo all of it when the patch fails or is cancelled.
+    /// A journal left behind by a crash is rolled back on the next patch into the same destination.
+    pub transactional: bool,
+    /// Setting this flag from another thread aborts a running patch at the next cover.
+    pub cancel_flag: Option<Arc<AtomicBool>>,
+    /// Periodically persist patch progress to this file so an interrupted patch can be continued with `resume`.
+    /// Cannot be combined with `transactional`.
+    pub checkpoint_path: Option<PathBuf>,
+    /// Output bytes between two checkpoints, 64 MiB when left at 0.
+    pub checkpoint_interval: u64,
+    /// Treat `\` in patch paths as a separator and look old files up case-insensitively, for patches made on Windows.
+    /// New files keep the spelling stored in the patch. Old paths matching several files that only differ in case are an error.
+    pub windows_paths: bool,
+    /// How identical old/new file pairs are materialised.
+    pub same_file_strategy: SameFileStrategy,
+    /// Only write the new files this filter accepts. Old files are then only required if a selected file is built from them.
+    pub file_filter: Option<FileFilter>,
+    /// How symlinks inside the source and output trees are handled.
+    pub symlink_policy: SymlinkPolicy,
+    /// Modification time of the written files.
+    pub output_timestamps: OutputTimestamps,
+    /// Copy the permission bits of the matched old file, see `OutputTimestamps::FromOldFile` for which one that is.
+    /// Files the patch marks executable get execute permission either way.
+    pub preserve_permissions: bool,
+    /// Skip comparing the space the output needs with what is free on the destination before writing.
+    pub skip_disk_space_check: bool,
+    /// Reserve the blocks of every new file with `fallocate` before writing, so a full disk shows up right away and
+    /// large files are not fragmented. Falls back to sparse files where that is not supported; `output_allocation`
+    /// on the patcher tells which one was used.
+    pub preallocate: bool,
+    /// `KrDiff` only: when patching in place, delete the old files the patch reads but does not write again, and
+    /// directories left empty by that. In-place `HDIFF19` patches always do this. Not supported by `KrDiffBatch`.
+    pub remove_old_files: bool,
+}
+
+pub(crate) fn check_cancel_flag(flag: Option<&AtomicBool>) -> std::io::Result<()> {
+    if flag.is_some_and(|f| f.load(Ordering::Relaxed)) { return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "Patch was cancelled")); }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct HeaderInfo {
     pub comp_mode: CompressionMode,
@@ -158,27 +254,36 @@ impl CoverHeader {
 
 pub(crate) trait PatchCore {
     fn set_directory_reference_pair(&mut self, pair: DirectoryReferencePair);
-    fn set_size_to_be_patched(&mut self, size_to_be_patched: i64, size_to_patch: i64);
-    fn uncover_buffer_clips_stream(&mut self, clips: &mut [Box<dyn Read>], input_stream: &mut dyn SeekableRead, output_stream: &mut dyn Write, header_info: &HeaderInfo);
+    fn uncover_buffer_clips_stream(&mut self, clips: &mut [Box<dyn Read>], input_stream: &mut dyn SeekableRead, output_stream: &mut dyn SyncWrite, header_info: &HeaderInfo) -> std::io::Result<()>;
 }
 
 pub(crate) trait SeekableRead: Read + std::io::Seek {}
 impl<T: Read + std::io::Seek> SeekableRead for T {}
 
 pub(crate) struct PatchCoreImpl {
-    pub size_to_be_patched: i64,
-    pub size_patched: i64,
     pub path_input: std::path::PathBuf,
     pub path_output: std::path::PathBuf,
     pub dir_reference_pair: Option<DirectoryReferencePair>,
+    pub in_place: Option<InPlacePlan>,
+    pub output_fs: OutputFs,
+    pub cancel_flag: Option<Arc<AtomicBool>>,
+    pub output_is_dir: bool,
+    pub same_file_strategy: SameFileStrategy,
+    pub file_filter: Option<FileFilter>,
+    pub checkpointer: Option<Checkpointer>,
+    pub resume_from: Option<Checkpoint>,
     pub write_bytes_callback: Option<Box<dyn FnMut(i64)>>,
 }
 
 pub(crate) struct CombinedStream {
     pub(crate) streams: Vec<File>,
+    /// How the files of a new-file stream were sized, `None` for old data or when there are no files.
+    pub(crate) allocation: Option<OutputAllocation>,
     pub(crate) start_positions: Vec<u64>,
     pub(crate) position: u64,
     pub(crate) index: usize,
+    /// First stream `sync_data` still has to sync; everything before it has not been written to since.
+    pub(crate) synced_index: usize,
     pub(crate) total_length: u64,
 }
 
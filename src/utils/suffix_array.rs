@@ -0,0 +1,126 @@
+use std::cmp::Ordering;
+
+const NONE: u32 = u32::MAX;
+
+/// Longest matches of arbitrary patterns in `data`, found through its suffix array.
+pub(crate) struct SuffixArray<'a> {
+    data: &'a [u8],
+    sa: Vec<u32>,
+}
+
+impl<'a> SuffixArray<'a> {
+    /// Positions are stored as `u32`, so `data` has to be shorter than 4 GiB.
+    pub fn new(data: &'a [u8]) -> Self {
+        assert!(data.len() < NONE as usize, "[SuffixArray::new] input of {} bytes is too large", data.len());
+        Self { data, sa: sa_is(data, u8::MAX as usize) }
+    }
+
+    /// `(position, length)` of the longest prefix of `pattern` found in the data, `(0, 0)` if not even the first byte is.
+    pub fn longest_match(&self, pattern: &[u8]) -> (usize, usize) {
+        // Suffixes are only compared on a bounded prefix; the winner is extended afterwards.
+        const PROBE_LEN: usize = 4096;
+        let probe = &pattern[..pattern.len().min(PROBE_LEN)];
+        let at = self.sa.partition_point(|&pos| self.suffix(pos).iter().take(probe.len()).cmp(probe.iter()) == Ordering::Less);
+        let mut best = (0, 0);
+        for &pos in self.sa[at.saturating_sub(1)..(at + 1).min(self.sa.len())].iter() {
+            let len = common_prefix(self.suffix(pos), pattern);
+            if len > best.1 { best = (pos as usize, len); }
+        }
+        best
+    }
+
+    fn suffix(&self, pos: u32) -> &[u8] {
+        &self.data[pos as usize..]
+    }
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+/// SA-IS (Nong, Zhang and Chan): suffix array of `s`, whose symbols are all at most `upper`.
+fn sa_is<T: Copy + Into<u32>>(s: &[T], upper: usize) -> Vec<u32> {
+    let n = s.len();
+    let at = |i: usize| s[i].into() as usize;
+    match n {
+        0 => return Vec::new(),
+        1 => return vec![0],
+        2 => return if at(0) < at(1) { vec![0, 1] } else { vec![1, 0] },
+        _ => {}
+    }
+
+    // `ls[i]`: the suffix at `i` is smaller than the one after it (S-type).
+    let mut ls = vec![false; n];
+    for i in (0..n - 1).rev() { ls[i] = if at(i) == at(i + 1) { ls[i + 1] } else { at(i) < at(i + 1) }; }
+    // Bucket starts: `sum_l` for L-type suffixes, `sum_s` for S-type ones.
+    let (mut sum_l, mut sum_s) = (vec![0u32; upper + 1], vec![0u32; upper + 1]);
+    for i in 0..n {
+        if ls[i] { sum_l[at(i) + 1] += 1; } else { sum_s[at(i)] += 1; }
+    }
+    for c in 0..=upper {
+        sum_s[c] += sum_l[c];
+        if c < upper { sum_l[c + 1] += sum_s[c]; }
+    }
+
+    let induce = |sa: &mut [u32], lms: &[u32]| {
+        sa.fill(NONE);
+        let mut buf = sum_s.clone();
+        for &d in lms {
+            let d = d as usize;
+            if d == n { continue; }
+            sa[buf[at(d)] as usize] = d as u32;
+            buf[at(d)] += 1;
+        }
+        buf.copy_from_slice(&sum_l);
+        sa[buf[at(n - 1)] as usize] = (n - 1) as u32;
+        buf[at(n - 1)] += 1;
+        for i in 0..n {
+            let v = sa[i];
+            if v != NONE && v >= 1 && !ls[v as usize - 1] {
+                let c = at(v as usize - 1);
+                sa[buf[c] as usize] = v - 1;
+                buf[c] += 1;
+            }
+        }
+        buf.copy_from_slice(&sum_l);
+        for i in (0..n).rev() {
+            let v = sa[i];
+            if v != NONE && v >= 1 && ls[v as usize - 1] {
+                let c = at(v as usize - 1);
+                buf[c + 1] -= 1;
+                sa[buf[c + 1] as usize] = v - 1;
+            }
+        }
+    };
+
+    // Leftmost S-type positions, and the index of each among them.
+    let mut lms_map = vec![NONE; n + 1];
+    let mut lms = Vec::new();
+    for i in 1..n {
+        if !ls[i - 1] && ls[i] { lms_map[i] = lms.len() as u32; lms.push(i as u32); }
+    }
+    let m = lms.len();
+    let mut sa = vec![NONE; n];
+    induce(&mut sa, &lms);
+    if m == 0 { return sa; }
+
+    // Name the LMS substrings in sorted order, then sort them for real by recursing on the names.
+    let mut sorted_lms: Vec<u32> = sa.iter().copied().filter(|&v| lms_map[v as usize] != NONE).collect();
+    let mut rec_s = vec![0u32; m];
+    let mut rec_upper = 0u32;
+    for i in 1..m {
+        let (mut l, mut r) = (sorted_lms[i - 1] as usize, sorted_lms[i] as usize);
+        let end = |p: usize| { let k = lms_map[p] as usize + 1; if k < m { lms[k] as usize } else { n } };
+        let (end_l, end_r) = (end(l), end(r));
+        let same = end_l - l == end_r - r && {
+            while l < end_l && at(l) == at(r) { l += 1; r += 1; }
+            l != n && r != n && at(l) == at(r)
+        };
+        if !same { rec_upper += 1; }
+        rec_s[lms_map[sorted_lms[i] as usize] as usize] = rec_upper;
+    }
+    let rec_sa = sa_is(&rec_s, rec_upper as usize);
+    for (i, &r) in rec_sa.iter().enumerate() { sorted_lms[i] = lms[r as usize]; }
+    induce(&mut sa, &sorted_lms);
+    sa
+}
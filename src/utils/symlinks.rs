@@ -0,0 +1,39 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::utils::structs::SymlinkPolicy;
+
+/// Calls `on_link` for every symlink on the way from `root` down to `full`, with whether it is the last component.
+/// `root` itself is not looked at. The walk stops at the first component that does not exist, so a link that
+/// `on_link` removes ends it.
+pub(crate) fn for_each_link_below(root: &Path, full: &Path, mut on_link: impl FnMut(&Path, bool) -> io::Result<()>) -> io::Result<()> {
+    let (mut cur, rel) = match full.strip_prefix(root) {
+        Ok(rel) => (root.to_path_buf(), rel.to_path_buf()),
+        Err(_) => (full.parent().map(Path::to_path_buf).unwrap_or_default(), full.file_name().map(PathBuf::from).unwrap_or_default()),
+    };
+    let count = rel.components().count();
+    for (i, part) in rel.components().enumerate() {
+        cur.push(part);
+        match fs::symlink_metadata(&cur) {
+            Ok(meta) if meta.file_type().is_symlink() => on_link(&cur, i + 1 == count)?,
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Checks an old file before its data is read. Missing files are left for the caller to report.
+pub(crate) fn check_old_file(root: &Path, full: &Path, policy: SymlinkPolicy) -> io::Result<()> {
+    if policy == SymlinkPolicy::Follow { return Ok(()); }
+    for_each_link_below(root, full, |link, _| Err(refused(link, policy)))
+}
+
+pub(crate) fn refused(link: &Path, policy: SymlinkPolicy) -> io::Error {
+    let why = match policy {
+        SymlinkPolicy::Opaque => "symlinks are opaque, nothing is read through them",
+        _ => "symlinks are refused",
+    };
+    io::Error::new(io::ErrorKind::InvalidInput, format!("[SymlinkPolicy] {} is a symlink and {}", link.display(), why))
+}